@@ -4,7 +4,6 @@ use flodgatt::request::{Content::*, Reach::*, Stream::*, Timeline};
 use flodgatt::response::{Event, Manager, RedisMsg, RedisParseOutput};
 use flodgatt::Id;
 use futures::{Async, Stream};
-use lru::LruCache;
 use std::convert::TryFrom;
 use std::fs;
 
@@ -19,7 +18,7 @@ fn parse_long_redis_input<'a>(input: &'a str) -> RedisMsg<'a> {
 
 fn parse_to_timeline(msg: RedisMsg) -> Timeline {
     let trimmed_tl_txt = &msg.timeline_txt["timeline:".len()..];
-    let tl = Timeline::from_redis_text(trimmed_tl_txt, &mut LruCache::new(1000)).unwrap();
+    let tl = Timeline::from_redis_text(trimmed_tl_txt, |_| None).unwrap();
     assert_eq!(tl, Timeline(User(Id(1)), Federated, All));
     tl
 }
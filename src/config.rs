@@ -1,5 +1,9 @@
 pub use self::deployment_cfg::Deployment;
+pub use self::deployment_cfg_types::Cors;
+pub use self::deployment_cfg_types::EventQueueOverflowInner as OverflowPolicy;
+pub use self::deployment_cfg_types::LogFormatInner as LogFormat;
 pub use self::postgres_cfg::Postgres;
+pub(crate) use self::postgres_cfg_types::PgSslInner as PgSslMode;
 pub use self::redis_cfg::Redis;
 
 use self::environmental_variables::EnvVar;
@@ -10,6 +14,7 @@ use std::fmt;
 mod deployment_cfg;
 mod deployment_cfg_types;
 mod environmental_variables;
+mod file;
 mod postgres_cfg;
 mod postgres_cfg_types;
 mod redis_cfg;
@@ -17,12 +22,27 @@ mod redis_cfg_types;
 
 type Result<T> = std::result::Result<T, Error>;
 
-pub fn merge_dotenv() -> Result<()> {
-    let env_file = match env::var("ENV").ok().as_deref() {
+/// Env vars whose settings can be reloaded while running; changing any other needs a restart
+const RELOADABLE: [&str; 7] = [
+    "RUST_LOG",
+    "LOG_FORMAT",
+    "WHITELIST_MODE",
+    "REDIS_FREQ",
+    "CORS_ALLOWED_ORIGINS",
+    "CORS_ALLOWED_METHODS",
+    "CORS_ALLOWED_HEADERS",
+];
+
+fn env_file() -> Result<&'static str> {
+    Ok(match env::var("ENV").ok().as_deref() {
         Some("production") => ".env.production",
         Some("development") | None => ".env",
         Some(v) => Err(Error::config("ENV", v, "`production` or `development`"))?,
-    };
+    })
+}
+
+pub fn merge_dotenv() -> Result<()> {
+    let env_file = env_file()?;
     let res = dotenv::from_filename(env_file);
 
     if let Ok(log_level) = env::var("RUST_LOG") {
@@ -50,10 +70,60 @@ pub fn merge_dotenv() -> Result<()> {
     Ok(())
 }
 
+/// The config file to read: the `--config` flag's `path`, if given, or else `FLODGATT_CONFIG`
+pub fn config_file(flag: Option<String>) -> Option<String> {
+    flag.or_else(|| env::var("FLODGATT_CONFIG").ok())
+        .filter(|path| !path.is_empty())
+}
+
+/// Add the settings from the config file at `path` (if any) to `vars`.  Those already in
+/// `vars` (from the env file or the environment) take precedence.
+#[allow(clippy::implicit_hasher)]
+pub fn merge_config_file(
+    path: Option<&str>,
+    vars: HashMap<String, String>,
+) -> Result<HashMap<String, String>> {
+    let mut merged = match path {
+        Some(path) => file::read(path)?,
+        None => return Ok(vars),
+    };
+    // (An empty env var means the default, so it shouldn't hide the file's setting)
+    merged.extend(vars.into_iter().filter(|(_, value)| !value.is_empty()));
+    Ok(merged)
+}
+
+/// Read the env file again, for reloading the configuration.  As at startup, variables from
+/// `process_env` (the environment Flodgatt started with) take precedence over the file.
+#[allow(clippy::implicit_hasher)]
+pub fn reread_env_file(process_env: &HashMap<String, String>) -> Result<HashMap<String, String>> {
+    let env_file = env_file()?;
+    let mut vars: HashMap<String, String> = match dotenv::from_filename_iter(env_file) {
+        Ok(iter) => iter.filter_map(std::result::Result::ok).collect(),
+        Err(e) => {
+            log::warn!("Could not read {}: {}", env_file, e);
+            HashMap::new()
+        }
+    };
+    vars.extend(process_env.clone());
+    Ok(vars)
+}
+
+/// The env vars for Flodgatt's settings that differ between `old` and `new`, split into those
+/// that can be reloaded while running and those that need a restart
 #[allow(clippy::implicit_hasher)]
-pub fn from_env<'a>(
-    env_vars: HashMap<String, String>,
-) -> Result<(Postgres, Redis, Deployment<'a>)> {
+pub fn changed_vars(
+    old: &HashMap<String, String>,
+    new: &HashMap<String, String>,
+) -> (Vec<&'static str>, Vec<&'static str>) {
+    EnvVar::NAMES
+        .iter()
+        .copied()
+        .filter(|&var| old.get(var) != new.get(var))
+        .partition(|var| RELOADABLE.contains(var))
+}
+
+#[allow(clippy::implicit_hasher)]
+pub fn from_env(env_vars: HashMap<String, String>) -> Result<(Postgres, Redis, Deployment)> {
     let env_vars = EnvVar::new(env_vars);
     log::info!(
         "Flodgatt received the following environmental variables:{}",
@@ -70,6 +140,26 @@ pub fn from_env<'a>(
     Ok((pg_cfg, redis_cfg, deployment_cfg))
 }
 
+/// A setting (such as a password) that's kept out of logs and printed configuration
+#[derive(Clone, PartialEq)]
+pub struct Secret(String);
+
+impl Secret {
+    pub(crate) fn new(secret: &str) -> Self {
+        Self(secret.to_string())
+    }
+
+    pub(crate) fn expose(&self) -> &str {
+        &self.0
+    }
+}
+
+impl fmt::Debug for Secret {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str("[redacted]")
+    }
+}
+
 #[derive(Debug)]
 pub enum Error {
     Config(String),
@@ -100,6 +190,14 @@ impl Error {
             var, value, allowed_vals, ""
         ))
     }
+
+    /// For a section or key in the config file at `path` that isn't one of the `known` ones
+    fn unknown_key(path: &str, key: &str, what: &str, known: &str) -> Self {
+        Self::Config(format!(
+            "{0} contains `{1}`, which is invalid.\n{4:7}The {2} must be among: {3}.",
+            path, key, what, known, ""
+        ))
+    }
 }
 
 impl From<urlencoding::FromUrlEncodingError> for Error {
@@ -113,3 +211,6 @@ impl From<url::ParseError> for Error {
         Self::UrlParse(e)
     }
 }
+
+#[cfg(test)]
+mod test;
@@ -2,26 +2,68 @@ use super::deployment_cfg_types::*;
 use super::{EnvVar, Error};
 
 #[derive(Debug, Default)]
-pub struct Deployment<'a> {
+pub struct Deployment {
     pub(crate) env: Env,
-    pub(crate) log_level: LogLevel,
+    pub log_level: LogLevel,
+    pub log_format: LogFormat,
     pub address: FlodgattAddr,
     pub port: Port,
     pub unix_socket: Socket,
-    pub cors: Cors<'a>,
+    pub cors: Cors,
     pub whitelist_mode: WhitelistMode,
+    pub metrics: Metrics,
+    pub metrics_addr: MetricsAddr,
+    pub slow_event_threshold: SlowEventThreshold,
+    pub event_queue_size: EventQueueSize,
+    pub event_queue_overflow: EventQueueOverflow,
+    pub replay_buffer_size: ReplayBufferSize,
+    pub replay_buffer_max_age: ReplayBufferMaxAge,
+    pub shutdown_timeout: ShutdownTimeout,
+    pub max_connections_per_user: MaxConnectionsPerUser,
+    pub max_connections_per_ip: MaxConnectionsPerIp,
+    pub max_connection_rate_per_ip: MaxConnectionRatePerIp,
+    pub trusted_proxies: TrustedProxies,
 }
 
-impl Deployment<'_> {
+impl Deployment {
     pub(crate) fn from_env(env: &EnvVar) -> Result<Self, Error> {
         let mut cfg = Self {
             env: Env::default().maybe_update(env.get("NODE_ENV"))?,
             log_level: LogLevel::default().maybe_update(env.get("RUST_LOG"))?,
+            log_format: LogFormat::default().maybe_update(env.get("LOG_FORMAT"))?,
             address: FlodgattAddr::default().maybe_update(env.get("BIND"))?,
             port: Port::default().maybe_update(env.get("PORT"))?,
             unix_socket: Socket::default().maybe_update(env.get("SOCKET"))?,
             whitelist_mode: WhitelistMode::default().maybe_update(env.get("WHITELIST_MODE"))?,
-            cors: Cors::default(),
+            metrics: Metrics::default().maybe_update(env.get("METRICS"))?,
+            metrics_addr: MetricsAddr::default().maybe_update(env.get("METRICS_ADDR"))?,
+            slow_event_threshold: SlowEventThreshold::default()
+                .maybe_update(env.get("SLOW_EVENT_THRESHOLD"))?,
+            event_queue_size: EventQueueSize::default()
+                .maybe_update(env.get("EVENT_QUEUE_SIZE"))?,
+            event_queue_overflow: EventQueueOverflow::default()
+                .maybe_update(env.get("EVENT_QUEUE_OVERFLOW"))?,
+            replay_buffer_size: ReplayBufferSize::default()
+                .maybe_update(env.get("REPLAY_BUFFER_SIZE"))?,
+            replay_buffer_max_age: ReplayBufferMaxAge::default()
+                .maybe_update(env.get("REPLAY_BUFFER_MAX_AGE"))?,
+            shutdown_timeout: ShutdownTimeout::default()
+                .maybe_update(env.get("SHUTDOWN_TIMEOUT"))?,
+            max_connections_per_user: MaxConnectionsPerUser::default()
+                .maybe_update(env.get("MAX_CONNECTIONS_PER_USER"))?,
+            max_connections_per_ip: MaxConnectionsPerIp::default()
+                .maybe_update(env.get("MAX_CONNECTIONS_PER_IP"))?,
+            max_connection_rate_per_ip: MaxConnectionRatePerIp::default()
+                .maybe_update(env.get("MAX_CONNECTION_RATE_PER_IP"))?,
+            trusted_proxies: TrustedProxies::default().maybe_update(env.get("TRUSTED_PROXIES"))?,
+            cors: Cors {
+                allowed_origins: CorsAllowedOrigins::default()
+                    .maybe_update(env.get("CORS_ALLOWED_ORIGINS"))?,
+                allowed_methods: CorsAllowedMethods::default()
+                    .maybe_update(env.get("CORS_ALLOWED_METHODS"))?,
+                allowed_headers: CorsAllowedHeaders::default()
+                    .maybe_update(env.get("CORS_ALLOWED_HEADERS"))?,
+            },
         };
         cfg.env = cfg.env.maybe_update(env.get("RUST_ENV"))?;
         Ok(cfg)
@@ -1,7 +1,8 @@
 use crate::from_env_var;
 use std::fmt;
-use std::net::{IpAddr, Ipv4Addr};
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
 use std::str::FromStr;
+use std::time::Duration;
 use strum_macros::{EnumString, EnumVariantNames};
 
 from_env_var!(
@@ -28,6 +29,13 @@ from_env_var!(
     let (env_var, allowed_values) = ("RUST_LOG",  &format!("one of: {:?}", LogLevelInner::variants())); 
     let from_str = |s| LogLevelInner::from_str(s).ok();
 );
+from_env_var!(
+    /// Whether to log as text or as JSON lines
+    let name = LogFormat;
+    let default: LogFormatInner = LogFormatInner::Text;
+    let (env_var, allowed_values) = ("LOG_FORMAT", &format!("one of: {:?}", LogFormatInner::variants()));
+    let from_str = |s| LogFormatInner::from_str(s).ok();
+);
 from_env_var!(
     /// A Unix Socket to use in place of a local address
     let name = Socket;
@@ -52,29 +60,213 @@ from_env_var!(
     let (env_var, allowed_values) = ("WHITELIST_MODE", "true or false");
     let from_str = |s| s.parse().ok();
 );
+from_env_var!(
+    /// Serves Prometheus metrics at `/metrics`
+    let name = Metrics;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("METRICS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// Serves `/metrics` on its own listener at this address, rather than alongside the
+    /// streaming API
+    let name = MetricsAddr;
+    let default: Option<SocketAddr> = None;
+    let (env_var, allowed_values) = ("METRICS_ADDR", "a valid address and port (e.g., 127.0.0.1:9090)");
+    let from_str = |s| s.parse().ok().map(Some);
+);
+from_env_var!(
+    /// Log events that are older than this (since Mastodon queued them) when flodgatt reads
+    /// them from Redis
+    let name = SlowEventThreshold;
+    let default: Option<Duration> = Some(Duration::from_secs(10));
+    let (env_var, allowed_values) = ("SLOW_EVENT_THRESHOLD", "a number of milliseconds (0 to never log)");
+    let from_str = |s| match s.parse() {
+        Ok(0) => Some(None),
+        Ok(ms) => Some(Some(Duration::from_millis(ms))),
+        Err(_) => None,
+    };
+);
+from_env_var!(
+    /// How many events to queue for a client that hasn't yet received them
+    let name = EventQueueSize;
+    let default: usize = 100;
+    let (env_var, allowed_values) = ("EVENT_QUEUE_SIZE", "a positive number");
+    let from_str = |s| match s.parse() {
+        Ok(0) | Err(_) => None,
+        Ok(size) => Some(size),
+    };
+);
+from_env_var!(
+    /// What to do when a client falls so far behind that its event queue is full
+    let name = EventQueueOverflow;
+    let default: EventQueueOverflowInner = EventQueueOverflowInner::DropOldest;
+    let (env_var, allowed_values) = ("EVENT_QUEUE_OVERFLOW", &format!("one of: {:?}", EventQueueOverflowInner::variants()));
+    let from_str = |s| EventQueueOverflowInner::from_str(s).ok();
+);
+from_env_var!(
+    /// How many recent events to keep for each timeline, to replay for clients that resume
+    /// with `Last-Event-ID` (SSE) or `since` (WebSocket)
+    let name = ReplayBufferSize;
+    let default: usize = 100;
+    let (env_var, allowed_values) = ("REPLAY_BUFFER_SIZE", "a number of events (0 to disable replay)");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// How long to keep events for replay
+    let name = ReplayBufferMaxAge;
+    let default: Duration = Duration::from_secs(60);
+    let (env_var, allowed_values) = ("REPLAY_BUFFER_MAX_AGE", "a number of seconds");
+    let from_str = |s| s.parse().map(Duration::from_secs).ok();
+);
+from_env_var!(
+    /// How long to wait for clients to disconnect when shutting down
+    let name = ShutdownTimeout;
+    let default: Duration = Duration::from_secs(10);
+    let (env_var, allowed_values) = ("SHUTDOWN_TIMEOUT", "a number of seconds");
+    let from_str = |s| s.parse().map(Duration::from_secs).ok();
+);
+from_env_var!(
+    /// The most connections one user may have open at once
+    let name = MaxConnectionsPerUser;
+    let default: usize = 0;
+    let (env_var, allowed_values) = ("MAX_CONNECTIONS_PER_USER", "a number of connections (0 for no limit)");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// The most connections one client IP may have open at once
+    let name = MaxConnectionsPerIp;
+    let default: usize = 0;
+    let (env_var, allowed_values) = ("MAX_CONNECTIONS_PER_IP", "a number of connections (0 for no limit)");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// The most new connections one client IP may open per minute
+    let name = MaxConnectionRatePerIp;
+    let default: usize = 0;
+    let (env_var, allowed_values) = ("MAX_CONNECTION_RATE_PER_IP", "a number of connections per minute (0 for no limit)");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// The proxies whose `X-Forwarded-For` headers give the client's IP address
+    let name = TrustedProxies;
+    let default: Vec<IpAddr> = vec![IpAddr::V4(Ipv4Addr::LOCALHOST), IpAddr::V6(Ipv6Addr::LOCALHOST)];
+    let (env_var, allowed_values) = ("TRUSTED_PROXIES", "a comma-separated list of IP addresses (e.g., 127.0.0.1,10.0.0.2)");
+    let from_str = |s| s.split(',').map(|addr| addr.trim().parse().ok()).collect();
+);
+from_env_var!(
+    /// The origins allowed to make cross-origin requests
+    let name = CorsAllowedOrigins;
+    let default: Vec<CorsOrigin> = vec![CorsOrigin::Any];
+    let (env_var, allowed_values) = ("CORS_ALLOWED_ORIGINS", "a comma-separated list of origins (e.g., https://example.com or https://*.example.com), or * for any origin");
+    let from_str = |s| s.split(',').map(CorsOrigin::parse).collect();
+);
+from_env_var!(
+    /// The methods allowed in cross-origin requests
+    let name = CorsAllowedMethods;
+    let default: Vec<String> = vec!["GET".to_string(), "OPTIONS".to_string()];
+    let (env_var, allowed_values) = ("CORS_ALLOWED_METHODS", "a comma-separated list of HTTP methods (e.g., GET,OPTIONS)");
+    let from_str = |s| s.split(',').map(|method| {
+        let method = method.trim();
+        match !method.is_empty() && method.chars().all(|c| c.is_ascii_alphabetic()) {
+            true => Some(method.to_ascii_uppercase()),
+            false => None,
+        }
+    }).collect();
+);
+from_env_var!(
+    /// The headers allowed in cross-origin requests
+    let name = CorsAllowedHeaders;
+    let default: Vec<String> = vec!["Authorization".to_string(), "Accept".to_string(), "Cache-Control".to_string()];
+    let (env_var, allowed_values) = ("CORS_ALLOWED_HEADERS", "a comma-separated list of header names (e.g., Authorization,Accept)");
+    let from_str = |s| s.split(',').map(|header| {
+        let header = header.trim();
+        let token_char = |c: char| c.is_ascii_alphanumeric() || "!#$%&'*+-.^_`|~".contains(c);
+        match !header.is_empty() && header.chars().all(token_char) {
+            true => Some(header.to_string()),
+            false => None,
+        }
+    }).collect();
+);
 /// Permissions for Cross Origin Resource Sharing (CORS)
-pub struct Cors<'a> {
-    pub allowed_headers: Vec<&'a str>,
-    pub allowed_methods: Vec<&'a str>,
+#[derive(Clone, Default)]
+pub struct Cors {
+    pub allowed_origins: CorsAllowedOrigins,
+    pub allowed_methods: CorsAllowedMethods,
+    pub allowed_headers: CorsAllowedHeaders,
 }
-impl std::default::Default for Cors<'_> {
-    fn default() -> Self {
-        Self {
-            allowed_methods: vec!["GET", "OPTIONS"],
-            allowed_headers: vec!["Authorization", "Accept", "Cache-Control"],
-        }
+impl Cors {
+    /// Whether requests from `origin` (the value of an `Origin` header) are allowed
+    pub fn allows_origin(&self, origin: &str) -> bool {
+        self.allowed_origins
+            .iter()
+            .any(|allowed| allowed.matches(origin))
     }
 }
-impl fmt::Debug for Cors<'_> {
+impl fmt::Debug for Cors {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         write!(
             f,
-            "allowed headers: {:?}\n      allowed methods: {:?}",
-            self.allowed_headers, self.allowed_methods
+            "allowed origins: {:?}\n      allowed headers: {:?}\n      allowed methods: {:?}",
+            self.allowed_origins, self.allowed_headers, self.allowed_methods
         )
     }
 }
 
+/// An origin that may make cross-origin requests
+#[derive(Debug, Clone, PartialEq)]
+pub enum CorsOrigin {
+    /// `*`
+    Any,
+    /// An origin such as `https://example.com`
+    Exact(String),
+    /// Every subdomain of a domain, as with `https://*.example.com` (which is held as the
+    /// scheme, `https://`, and the rest, `.example.com`)
+    Subdomains { scheme: String, domain: String },
+}
+impl CorsOrigin {
+    fn parse(s: &str) -> Option<Self> {
+        let s = s.trim().to_ascii_lowercase();
+        if s == "*" {
+            return Some(Self::Any);
+        }
+        let scheme = ["https://", "http://"]
+            .iter()
+            .find(|scheme| s.starts_with(*scheme))?;
+        let host = &s[scheme.len()..];
+        let host_char = |c: char| c.is_ascii_alphanumeric() || "-.:[]".contains(c);
+        if host.starts_with("*.") && host.len() > 2 && host[2..].chars().all(host_char) {
+            Some(Self::Subdomains {
+                scheme: scheme.to_string(),
+                domain: host[1..].to_string(),
+            })
+        } else if !host.is_empty() && host.chars().all(host_char) {
+            Some(Self::Exact(s))
+        } else {
+            None
+        }
+    }
+
+    fn matches(&self, origin: &str) -> bool {
+        let origin = origin.to_ascii_lowercase();
+        match self {
+            Self::Any => true,
+            Self::Exact(allowed) => *allowed == origin,
+            Self::Subdomains { scheme, domain } => {
+                origin.starts_with(scheme.as_str())
+                    && origin.ends_with(domain.as_str())
+                    && origin.len() > scheme.len() + domain.len()
+                    && origin[scheme.len()..origin.len() - domain.len()]
+                        .split('.')
+                        .all(|label| {
+                            !label.is_empty()
+                                && label.chars().all(|c| c.is_ascii_alphanumeric() || c == '-')
+                        })
+            }
+        }
+    }
+}
+
 #[derive(EnumString, EnumVariantNames, Debug, Clone)]
 #[strum(serialize_all = "snake_case")]
 pub enum LogLevelInner {
@@ -84,6 +276,24 @@ pub enum LogLevelInner {
     Warn,
     Error,
 }
+impl LogLevelInner {
+    pub fn level_filter(&self) -> log::LevelFilter {
+        match self {
+            Self::Trace => log::LevelFilter::Trace,
+            Self::Debug => log::LevelFilter::Debug,
+            Self::Info => log::LevelFilter::Info,
+            Self::Warn => log::LevelFilter::Warn,
+            Self::Error => log::LevelFilter::Error,
+        }
+    }
+}
+
+#[derive(EnumString, EnumVariantNames, Debug, Clone, Copy, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum LogFormatInner {
+    Text,
+    Json,
+}
 
 #[derive(EnumString, EnumVariantNames, Debug, Clone)]
 #[strum(serialize_all = "snake_case")]
@@ -91,3 +301,14 @@ pub enum EnvInner {
     Production,
     Development,
 }
+
+#[derive(EnumString, EnumVariantNames, Debug, Clone, Copy, PartialEq)]
+#[strum(serialize_all = "snake_case")]
+pub enum EventQueueOverflowInner {
+    /// Drop the oldest queued event to make room for the new one
+    DropOldest,
+    /// Drop the new event
+    DropNewest,
+    /// Disconnect the client
+    Disconnect,
+}
@@ -1,5 +1,6 @@
 use hashbrown::HashMap;
 use std::fmt;
+use url::Url;
 
 #[derive(Debug)]
 pub(crate) struct EnvVar(pub HashMap<String, String>);
@@ -16,6 +17,67 @@ impl Clone for EnvVar {
     }
 }
 impl EnvVar {
+    /// Every env var that configures Flodgatt
+    pub(crate) const NAMES: &'static [&'static str] = &[
+        "FLODGATT_CONFIG",
+        "NODE_ENV",
+        "RUST_LOG",
+        "LOG_FORMAT",
+        "BIND",
+        "PORT",
+        "SOCKET",
+        "WHITELIST_MODE",
+        "CORS_ALLOWED_ORIGINS",
+        "CORS_ALLOWED_METHODS",
+        "CORS_ALLOWED_HEADERS",
+        "METRICS",
+        "METRICS_ADDR",
+        "SLOW_EVENT_THRESHOLD",
+        "EVENT_QUEUE_SIZE",
+        "EVENT_QUEUE_OVERFLOW",
+        "REPLAY_BUFFER_SIZE",
+        "REPLAY_BUFFER_MAX_AGE",
+        "SHUTDOWN_TIMEOUT",
+        "MAX_CONNECTIONS_PER_USER",
+        "MAX_CONNECTIONS_PER_IP",
+        "MAX_CONNECTION_RATE_PER_IP",
+        "TRUSTED_PROXIES",
+        "SSE_FREQ",
+        "WS_FREQ",
+        "DATABASE_URL",
+        "DB_USER",
+        "USER",
+        "DB_PORT",
+        "DB_HOST",
+        "DB_PASS",
+        "DB_NAME",
+        "DB_SSLMODE",
+        "DB_SSLROOTCERT",
+        "DB_SSLCERT",
+        "DB_SSLKEY",
+        "DB_APPLICATION_NAME",
+        "DB_CONNECT_TIMEOUT",
+        "DB_BLOCKS_REFRESH",
+        "DB_CACHE_SIZE",
+        "DB_CACHE_TTL",
+        "REDIS_URL",
+        "REDIS_HOST",
+        "REDIS_USER",
+        "REDIS_PORT",
+        "REDIS_PASSWORD",
+        "REDIS_DB",
+        "REDIS_NAMESPACE",
+        "REDIS_FREQ",
+        "REDIS_SENTINELS",
+        "REDIS_SENTINEL_MASTER",
+        "REDIS_TLS",
+        "REDIS_TLS_CA_FILE",
+        "REDIS_TLS_CERT_FILE",
+        "REDIS_TLS_KEY_FILE",
+        "REDIS_TLS_SERVER_NAME",
+        "REDIS_INPUT_BUFFER_MAX",
+    ];
+
     pub(crate) fn new(vars: HashMap<String, String>) -> Self {
         Self(vars)
     }
@@ -29,37 +91,41 @@ impl EnvVar {
 impl fmt::Display for EnvVar {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         let mut result = String::new();
-        for env_var in &[
-            "NODE_ENV",
-            "RUST_LOG",
-            "BIND",
-            "PORT",
-            "SOCKET",
-            "SSE_FREQ",
-            "WS_FREQ",
-            "DATABASE_URL",
-            "DB_USER",
-            "USER",
-            "DB_PORT",
-            "DB_HOST",
-            "DB_PASS",
-            "DB_NAME",
-            "DB_SSLMODE",
-            "REDIS_HOST",
-            "REDIS_USER",
-            "REDIS_PORT",
-            "REDIS_PASSWORD",
-            "REDIS_USER",
-            "REDIS_DB",
-            "REDIS_FREQ",
-        ] {
+        for env_var in Self::NAMES {
             if let Some(value) = self.get(&(*env_var).to_string()) {
-                result = format!("{}\n    {}: {}", result, env_var, value)
+                result = format!("{}\n    {}: {}", result, env_var, redacted(env_var, value))
             }
         }
         write!(f, "{}", result)
     }
 }
+/// The value of an env var, with any password hidden
+fn redacted(env_var: &str, value: &str) -> String {
+    match env_var {
+        "DB_PASS" | "REDIS_PASSWORD" => "[redacted]".to_string(),
+        "DATABASE_URL" | "REDIS_URL" => match Url::parse(value) {
+            Ok(mut url) => {
+                if url.password().is_some() {
+                    url.set_password(Some("redacted")).unwrap_or_default();
+                }
+                let query: Vec<(String, String)> = url
+                    .query_pairs()
+                    .into_owned()
+                    .map(|(k, v)| match k.as_str() {
+                        "password" => (k, "redacted".to_string()),
+                        _ => (k, v),
+                    })
+                    .collect();
+                if !query.is_empty() {
+                    url.query_pairs_mut().clear().extend_pairs(query);
+                }
+                url.to_string()
+            }
+            Err(_) => "[redacted]".to_string(),
+        },
+        _ => value.to_string(),
+    }
+}
 #[macro_export]
 #[doc(hidden)]
 macro_rules! maybe_update {
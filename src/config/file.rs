@@ -0,0 +1,89 @@
+//! An optional TOML config file, with `[postgres]`, `[redis]` and `[deployment]` sections.
+//!
+//! Each key stands for one of the env vars: `[postgres]` has the `DB_` vars without their
+//! prefix (`host` for `DB_HOST`, plus `url` for `DATABASE_URL`), `[redis]` has the `REDIS_`
+//! vars without theirs, and `[deployment]` has the rest (`port` for `PORT`,
+//! `cors_allowed_origins` for `CORS_ALLOWED_ORIGINS`, and so on).  Values are checked just
+//! as the env vars are; lists are joined with commas.
+use super::{EnvVar, Error};
+
+use hashbrown::HashMap;
+use std::fs;
+use toml::value::{Table, Value};
+
+const SECTIONS: [&str; 3] = ["postgres", "redis", "deployment"];
+
+/// The section and key for an env var in the config file (`None` for the env vars that can't
+/// be set there)
+fn key_for(var: &str) -> Option<(&'static str, String)> {
+    match var {
+        "USER" | "FLODGATT_CONFIG" => None,
+        "DATABASE_URL" => Some(("postgres", "url".to_string())),
+        _ if var.starts_with("DB_") => Some(("postgres", var["DB_".len()..].to_lowercase())),
+        _ if var.starts_with("REDIS_") => Some(("redis", var["REDIS_".len()..].to_lowercase())),
+        _ => Some(("deployment", var.to_lowercase())),
+    }
+}
+
+/// Read the config file at `path`, as the env vars its settings stand for
+pub(super) fn read(path: &str) -> Result<HashMap<String, String>, Error> {
+    let text = fs::read_to_string(path)
+        .map_err(|e| Error::Config(format!("could not read the config file {}: {}", path, e)))?;
+    let file: Table = toml::from_str(&text)
+        .map_err(|e| Error::Config(format!("could not parse the config file {}: {}", path, e)))?;
+
+    let mut vars = HashMap::new();
+    for (section, table) in file {
+        let table = match table {
+            Value::Table(table) if SECTIONS.contains(&section.as_str()) => table,
+            _ => {
+                let sections = format!("[{}]", SECTIONS.join("], ["));
+                Err(Error::unknown_key(path, &section, "sections", &sections))?
+            }
+        };
+        for (key, value) in table {
+            let is_key = |var: &&str| key_for(var).map_or(false, |(s, k)| s == section && k == key);
+            let var = match EnvVar::NAMES.iter().find(is_key) {
+                Some(var) => var,
+                None => Err(Error::unknown_key(
+                    path,
+                    &format!("[{}] {}", section, key),
+                    &format!("keys in [{}]", section),
+                    &keys_in(&section).join(", "),
+                ))?,
+            };
+            vars.insert(var.to_string(), env_value(var, &value)?);
+        }
+    }
+    Ok(vars)
+}
+
+fn keys_in(section: &str) -> Vec<String> {
+    EnvVar::NAMES
+        .iter()
+        .filter_map(|var| key_for(var))
+        .filter(|(s, _)| *s == section)
+        .map(|(_, key)| key)
+        .collect()
+}
+
+/// A setting from the file, as the value of its env var
+fn env_value(var: &str, value: &Value) -> Result<String, Error> {
+    match value {
+        Value::String(s) => Ok(s.clone()),
+        Value::Integer(_) | Value::Float(_) | Value::Boolean(_) => Ok(value.to_string()),
+        Value::Array(values) => Ok(values
+            .iter()
+            .map(|value| match value {
+                Value::Array(_) => Err(Error::config(var, &value.to_string(), "a flat list")),
+                value => env_value(var, value),
+            })
+            .collect::<Result<Vec<_>, _>>()?
+            .join(",")),
+        Value::Table(_) | Value::Datetime(_) => Err(Error::config(
+            var,
+            &value.to_string(),
+            "a string, number, boolean, or list",
+        )),
+    }
+}
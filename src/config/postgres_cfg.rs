@@ -16,42 +16,115 @@ pub struct Postgres {
     pub database: PgDatabase,
     pub(crate) port: PgPort,
     pub(crate) ssl_mode: PgSslMode,
+    pub(crate) ssl_root_cert: PgSslRootCert,
+    pub(crate) ssl_cert: PgSslCert,
+    pub(crate) ssl_key: PgSslKey,
+    pub(crate) application_name: PgAppName,
+    pub(crate) connect_timeout: PgConnectTimeout,
+    pub(crate) blocks_refresh: PgBlocksRefresh,
+    pub(crate) cache_size: PgCacheSize,
+    pub(crate) cache_ttl: PgCacheTtl,
 }
 
 impl EnvVar {
+    /// Add the settings from a libpq connection URI (`DATABASE_URL`).  Settings in the URL take
+    /// precedence over the individual `DB_*` variables and, within the URL, query parameters
+    /// take precedence over the rest of the URL.
     fn update_with_postgres_url(mut self, url_str: &str) -> Result<Self> {
         let url = Url::parse(url_str)?;
         let none_if_empty = |s: String| if s.is_empty() { None } else { Some(s) };
 
+        self.maybe_add_env_var("DB_PORT", url.port());
+        self.maybe_add_env_var(
+            "DB_PASS",
+            url.password().map(urlencoding::decode).transpose()?,
+        );
+        let host = url.host_str().map(urlencoding::decode).transpose()?; // may be a socket dir
+        self.maybe_add_env_var("DB_HOST", host.and_then(none_if_empty));
+        let user = urlencoding::decode(url.username())?;
+        self.maybe_add_env_var("DB_USER", none_if_empty(user));
+        let db_name = urlencoding::decode(url.path().trim_start_matches('/'))?;
+        self.maybe_add_env_var("DB_NAME", none_if_empty(db_name));
+
+        let app_name_set = url.query_pairs().any(|(k, _)| k == "application_name");
         for (k, v) in url.query_pairs().into_owned() {
-            match k.to_string().as_str() {
-                "user" => self.maybe_add_env_var("DB_USER", Some(v.to_string())),
-                "password" => self.maybe_add_env_var("DB_PASS", Some(v.to_string())),
-                "host" => self.maybe_add_env_var("DB_HOST", Some(v.to_string())),
-                "sslmode" => self.maybe_add_env_var("DB_SSLMODE", Some(v.to_string())),
+            match k.as_str() {
+                "user" => self.maybe_add_env_var("DB_USER", Some(v)),
+                "password" => self.maybe_add_env_var("DB_PASS", Some(v)),
+                "host" => self.maybe_add_env_var("DB_HOST", Some(v)),
+                "port" => self.maybe_add_env_var("DB_PORT", Some(v)),
+                "dbname" => self.maybe_add_env_var("DB_NAME", Some(v)),
+                "sslmode" => self.maybe_add_env_var("DB_SSLMODE", Some(v)),
+                "sslrootcert" => self.maybe_add_env_var("DB_SSLROOTCERT", Some(v)),
+                "sslcert" => self.maybe_add_env_var("DB_SSLCERT", Some(v)),
+                "sslkey" => self.maybe_add_env_var("DB_SSLKEY", Some(v)),
+                "application_name" => self.maybe_add_env_var("DB_APPLICATION_NAME", Some(v)),
+                "fallback_application_name" if !app_name_set => {
+                    self.maybe_add_env_var("DB_APPLICATION_NAME", Some(v))
+                }
+                "fallback_application_name" => (),
+                "connect_timeout" => self.maybe_add_env_var("DB_CONNECT_TIMEOUT", Some(v)),
+                k if Postgres::UNSUPPORTED_LIBPQ_PARAMS.contains(&k) => {
+                    Err(Error::config("DATABASE_URL", &k, Postgres::UNSUPPORTED))?
+                }
+                k if Postgres::IGNORED_LIBPQ_PARAMS.contains(&k) => {
+                    log::warn!(
+                        "Ignoring the DATABASE_URL parameter `{}`: {}",
+                        k,
+                        Postgres::IGNORED
+                    )
+                }
                 _ => Err(Error::config(
-                    "POSTGRES_URL",
+                    "DATABASE_URL",
                     &k,
-                    "a URL with parameters `password`, `user`, `host`, and `sslmode` only",
+                    "a URL with libpq connection parameters only",
                 ))?,
             }
         }
-
-        self.maybe_add_env_var("DB_PORT", url.port());
-        self.maybe_add_env_var("DB_PASS", url.password());
-        self.maybe_add_env_var(
-            "DB_HOST",
-            url.host()
-                .map(|host| urlencoding::decode(&host.to_string()))
-                .transpose()?,
-        );
-        self.maybe_add_env_var("DB_USER", none_if_empty(url.username().to_string()));
-        self.maybe_add_env_var("DB_NAME", none_if_empty(url.path()[1..].to_string()));
         Ok(self)
     }
 }
 
 impl Postgres {
+    /// libpq parameters that change which server flodgatt would connect to, or what kind of
+    /// session it would get, so that ignoring them could silently connect to the wrong one
+    const UNSUPPORTED_LIBPQ_PARAMS: [&'static str; 5] = [
+        "hostaddr",
+        "options",
+        "replication",
+        "service",
+        "target_session_attrs",
+    ];
+    const UNSUPPORTED: &'static str = "a URL without the libpq parameters flodgatt does not \
+        support (hostaddr, options, replication, service and target_session_attrs)";
+
+    /// libpq parameters that flodgatt accepts in a `DATABASE_URL` but does not act on
+    const IGNORED_LIBPQ_PARAMS: [&'static str; 22] = [
+        "passfile",
+        "require_auth",
+        "channel_binding",
+        "client_encoding",
+        "keepalives",
+        "keepalives_idle",
+        "keepalives_interval",
+        "keepalives_count",
+        "tcp_user_timeout",
+        "gssencmode",
+        "sslnegotiation",
+        "sslcompression",
+        "sslpassword",
+        "sslcertmode",
+        "sslcrl",
+        "sslcrldir",
+        "sslsni",
+        "requirepeer",
+        "ssl_min_protocol_version",
+        "ssl_max_protocol_version",
+        "krbsrvname",
+        "gsslib",
+    ];
+    const IGNORED: &'static str = "flodgatt does not support this setting";
+
     /// Configure Postgres and return a connection
     pub(crate) fn from_env(env: EnvVar) -> Result<Self> {
         let env = match env.get("DATABASE_URL").cloned() {
@@ -66,14 +139,36 @@ impl Postgres {
             database: PgDatabase::default().maybe_update(env.get("DB_NAME"))?,
             port: PgPort::default().maybe_update(env.get("DB_PORT"))?,
             ssl_mode: PgSslMode::default().maybe_update(env.get("DB_SSLMODE"))?,
+            ssl_root_cert: PgSslRootCert::default().maybe_update(env.get("DB_SSLROOTCERT"))?,
+            ssl_cert: PgSslCert::default().maybe_update(env.get("DB_SSLCERT"))?,
+            ssl_key: PgSslKey::default().maybe_update(env.get("DB_SSLKEY"))?,
+            application_name: PgAppName::default().maybe_update(env.get("DB_APPLICATION_NAME"))?,
+            connect_timeout: PgConnectTimeout::default()
+                .maybe_update(env.get("DB_CONNECT_TIMEOUT"))?,
+            blocks_refresh: PgBlocksRefresh::default()
+                .maybe_update(env.get("DB_BLOCKS_REFRESH"))?,
+            cache_size: PgCacheSize::default().maybe_update(env.get("DB_CACHE_SIZE"))?,
+            cache_ttl: PgCacheTtl::default().maybe_update(env.get("DB_CACHE_TTL"))?,
         };
         Ok(cfg)
     }
 
-    //     // use openssl::ssl::{SslConnector, SslMethod};
-    //     // use postgres_openssl::MakeTlsConnector;
-    //     // let mut builder = SslConnector::builder(SslMethod::tls())?;
-    //     // builder.set_ca_file("/etc/ssl/cert.pem")?;
-    //     // let connector = MakeTlsConnector::new(builder.build());
-    //     // TODO: add TLS support, remove `NoTls`
+    /// Whether to verify the server's certificate: libpq does with `verify-ca` and
+    /// `verify-full`, and treats `require` as `verify-ca` when given a root CA
+    pub(crate) fn verify_cert(&self) -> bool {
+        use PgSslInner::*;
+        match *self.ssl_mode {
+            VerifyCa | VerifyFull => true,
+            Require => self.ssl_root_cert.is_some(),
+            Disable | Allow | Prefer => false,
+        }
+    }
+
+    /// Whether to check that the server's certificate is for the host connected to
+    pub(crate) fn verify_hostname(&self) -> bool {
+        *self.ssl_mode == PgSslInner::VerifyFull
+    }
 }
+
+#[cfg(test)]
+mod test;
@@ -1,5 +1,7 @@
+use super::Secret;
 use crate::from_env_var;
 use std::str::FromStr;
+use std::time::Duration;
 use strum_macros::{EnumString, EnumVariantNames};
 
 from_env_var!(
@@ -11,7 +13,7 @@ from_env_var!(
 );
 
 from_env_var!(
-    /// The host address where Postgres is running)
+    /// The host address where Postgres is running (or a directory with its Unix socket)
     let name = PgHost;
     let default: String = "localhost".to_string();
     let (env_var, allowed_values) = ("DB_HOST", "any string");
@@ -21,9 +23,9 @@ from_env_var!(
 from_env_var!(
     /// The password to use with Postgress
     let name = PgPass;
-    let default: Option<String> = None;
+    let default: Option<Secret> = None;
     let (env_var, allowed_values) = ("DB_PASS", "any string");
-    let from_str = |s| Some(Some(s.to_string()));
+    let from_str = |s| Some(Some(Secret::new(s)));
 );
 
 from_env_var!(
@@ -43,14 +45,93 @@ from_env_var!(
 );
 
 from_env_var!(
+    /// The `application_name` flodgatt reports to Postgres
+    let name = PgAppName;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("DB_APPLICATION_NAME", "any string");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+
+from_env_var!(
+    /// How long to wait for a connection to Postgres (by default, forever)
+    let name = PgConnectTimeout;
+    let default: Option<Duration> = None;
+    let (env_var, allowed_values) = ("DB_CONNECT_TIMEOUT", "a number of seconds");
+    let from_str = |s| match s.parse() {
+        Ok(0) => Some(None), // as with libpq, zero means to wait forever
+        Ok(secs) => Some(Some(Duration::from_secs(secs))),
+        Err(_) => None,
+    };
+);
+
+from_env_var!(
+    /// How often to re-query the blocks, mutes and filters of users with open connections
+    let name = PgBlocksRefresh;
+    let default: Duration = Duration::from_secs(60);
+    let (env_var, allowed_values) = ("DB_BLOCKS_REFRESH", "a number of seconds from 1 up");
+    let from_str = |s| match s.parse() {
+        Ok(0) | Err(_) => None, // (0 would re-query continuously)
+        Ok(secs) => Some(Duration::from_secs(secs)),
+    };
+);
+
+from_env_var!(
+    /// How many access tokens (and users' blocks) to keep cached
+    let name = PgCacheSize;
+    let default: usize = 10_000;
+    let (env_var, allowed_values) = ("DB_CACHE_SIZE", "a number of entries");
+    let from_str = |s| s.parse().ok();
+);
+
+from_env_var!(
+    /// How long to trust a cached access token (or user's blocks) before re-querying Postgres
+    let name = PgCacheTtl;
+    let default: Duration = Duration::from_secs(60);
+    let (env_var, allowed_values) = ("DB_CACHE_TTL", "a number of seconds");
+    let from_str = |s| s.parse().map(Duration::from_secs).ok();
+);
+
+from_env_var!(
+    /// Whether and how to secure the Postgres connection with TLS (as with libpq's `sslmode`)
     let name = PgSslMode;
     let default: PgSslInner = PgSslInner::Prefer;
     let (env_var, allowed_values) = ("DB_SSLMODE", &format!("one of: {:?}", PgSslInner::variants()));
     let from_str = |s| PgSslInner::from_str(s).ok();
 );
 
-#[derive(EnumString, EnumVariantNames, Debug, Clone)]
+from_env_var!(
+    /// A file of CA certificates for verifying the Postgres server's certificate
+    let name = PgSslRootCert;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("DB_SSLROOTCERT", "a path to a PEM file");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+
+from_env_var!(
+    /// The client certificate to present to Postgres
+    let name = PgSslCert;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("DB_SSLCERT", "a path to a PEM file");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+
+from_env_var!(
+    /// The private key for the client certificate
+    let name = PgSslKey;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("DB_SSLKEY", "a path to a PEM file");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+
+#[derive(EnumString, EnumVariantNames, Debug, Clone, Copy, PartialEq)]
 #[strum(serialize_all = "snake_case")]
 pub enum PgSslInner {
+    Disable,
+    Allow,
     Prefer,
+    Require,
+    #[strum(serialize = "verify-ca")]
+    VerifyCa,
+    #[strum(serialize = "verify-full")]
+    VerifyFull,
 }
@@ -13,6 +13,14 @@ pub struct Redis {
     pub(crate) host: RedisHost,
     pub(crate) db: RedisDb,
     pub(crate) namespace: RedisNamespace,
+    pub(crate) sentinels: RedisSentinels,
+    pub(crate) sentinel_master: RedisSentinelMaster,
+    pub(crate) tls: RedisTls,
+    pub(crate) tls_ca_file: RedisTlsCaFile,
+    pub(crate) tls_cert_file: RedisTlsCertFile,
+    pub(crate) tls_key_file: RedisTlsKeyFile,
+    pub(crate) tls_server_name: RedisTlsServerName,
+    pub(crate) input_buffer_max: RedisInputBufferMax,
     // **NOTE**:  Polling Redis is much more time consuming than polling the `Receiver` (~1ms
     // compared to ~50μs).  Thus, changing this setting with REDIS_POLL_INTERVAL may be a good
     // place to start for performance improvements at the cost of delaying all updates.
@@ -20,22 +28,46 @@ pub struct Redis {
 }
 
 impl EnvVar {
+    /// Add the settings from a `redis://`, `rediss://`, or `unix://` URL (`REDIS_URL`).
+    /// Settings in the URL take precedence over the individual `REDIS_*` variables and,
+    /// within the URL, query parameters take precedence over the rest of the URL.
     fn update_with_redis_url(mut self, url_str: &str) -> Result<Self> {
         let url = Url::parse(url_str)?;
         let none_if_empty = |s: String| if s.is_empty() { None } else { Some(s) };
 
+        match url.scheme() {
+            "redis" => self.maybe_add_env_var("REDIS_HOST", url.host_str()),
+            "rediss" => {
+                self.maybe_add_env_var("REDIS_TLS", Some("true"));
+                self.maybe_add_env_var("REDIS_HOST", url.host_str());
+            }
+            "unix" => self.maybe_add_env_var("REDIS_HOST", Some(url.path())),
+            scheme => Err(Error::config(
+                "REDIS_URL",
+                scheme,
+                "a URL starting with `redis://`, `rediss://`, or `unix://`",
+            ))?,
+        }
         self.maybe_add_env_var("REDIS_PORT", url.port());
-        self.maybe_add_env_var("REDIS_PASSWORD", url.password());
-        self.maybe_add_env_var("REDIS_USERNAME", none_if_empty(url.username().to_string()));
-        self.maybe_add_env_var("REDIS_DB", none_if_empty(url.path()[1..].to_string()));
+        let user = urlencoding::decode(url.username())?;
+        self.maybe_add_env_var("REDIS_USER", none_if_empty(user));
+        let password = url.password().map(urlencoding::decode).transpose()?;
+        self.maybe_add_env_var("REDIS_PASSWORD", password);
+        if url.scheme() != "unix" {
+            let db = url.path().trim_start_matches('/').to_string();
+            self.maybe_add_env_var("REDIS_DB", none_if_empty(db));
+        }
+
         for (k, v) in url.query_pairs().into_owned() {
-            match k.to_string().as_str() {
-                "password" => self.maybe_add_env_var("REDIS_PASSWORD", Some(v.to_string())),
-                "db" => self.maybe_add_env_var("REDIS_DB", Some(v.to_string())),
+            match k.as_str() {
+                "user" => self.maybe_add_env_var("REDIS_USER", Some(v)),
+                "password" => self.maybe_add_env_var("REDIS_PASSWORD", Some(v)),
+                "db" => self.maybe_add_env_var("REDIS_DB", Some(v)),
+                "namespace" => self.maybe_add_env_var("REDIS_NAMESPACE", Some(v)),
                 _ => Err(Error::config(
                     "REDIS_URL",
                     &k,
-                    "a URL with parameters `password`, `db`,  only",
+                    "a URL with parameters `user`, `password`, `db`, and `namespace` only",
                 ))?,
             }
         }
@@ -45,9 +77,9 @@ impl EnvVar {
 
 impl Redis {
     const USER_SET_WARNING: &'static str =
-        "Redis user specified, but Redis did not ask for a username.  Ignoring it.";
-    const DB_SET_WARNING: &'static str = r"Redis database specified, but PubSub connections do not use databases.
-For similar functionality, you may wish to set a REDIS_NAMESPACE";
+        "Redis user specified without a password (REDIS_PASSWORD).  Ignoring it.";
+    const TLS_UNSET_WARNING: &'static str =
+        "Redis TLS settings specified, but REDIS_TLS is not enabled.  Ignoring them.";
 
     pub(crate) fn from_env(env: EnvVar) -> Result<Self> {
         let env = match env.get("REDIS_URL").cloned() {
@@ -63,14 +95,35 @@ For similar functionality, you may wish to set a REDIS_NAMESPACE";
             db: RedisDb::default().maybe_update(env.get("REDIS_DB"))?,
             namespace: RedisNamespace::default().maybe_update(env.get("REDIS_NAMESPACE"))?,
             polling_interval: RedisInterval::default().maybe_update(env.get("REDIS_FREQ"))?,
+            sentinels: RedisSentinels::default().maybe_update(env.get("REDIS_SENTINELS"))?,
+            sentinel_master: RedisSentinelMaster::default()
+                .maybe_update(env.get("REDIS_SENTINEL_MASTER"))?,
+            tls: RedisTls::default().maybe_update(env.get("REDIS_TLS"))?,
+            tls_ca_file: RedisTlsCaFile::default().maybe_update(env.get("REDIS_TLS_CA_FILE"))?,
+            tls_cert_file: RedisTlsCertFile::default()
+                .maybe_update(env.get("REDIS_TLS_CERT_FILE"))?,
+            tls_key_file: RedisTlsKeyFile::default().maybe_update(env.get("REDIS_TLS_KEY_FILE"))?,
+            tls_server_name: RedisTlsServerName::default()
+                .maybe_update(env.get("REDIS_TLS_SERVER_NAME"))?,
+            input_buffer_max: RedisInputBufferMax::default()
+                .maybe_update(env.get("REDIS_INPUT_BUFFER_MAX"))?,
         };
 
-        if cfg.db.is_some() {
-            log::warn!("{}", Self::DB_SET_WARNING);
-        }
-        if cfg.user.is_some() {
+        if cfg.user.is_some() && cfg.password.is_none() {
             log::warn!("{}", Self::USER_SET_WARNING);
         }
+        let tls_settings = [
+            &cfg.tls_ca_file.0,
+            &cfg.tls_cert_file.0,
+            &cfg.tls_key_file.0,
+            &cfg.tls_server_name.0,
+        ];
+        if !*cfg.tls && tls_settings.iter().any(|setting| setting.is_some()) {
+            log::warn!("{}", Self::TLS_UNSET_WARNING);
+        }
         Ok(cfg)
     }
 }
+
+#[cfg(test)]
+mod test;
@@ -1,10 +1,11 @@
+use super::Secret;
 use crate::from_env_var; //macro
 use std::time::Duration;
 //use std::{fmt, net::IpAddr, os::unix::net::UnixListener, str::FromStr, time::Duration};
 //use strum_macros::{EnumString, EnumVariantNames};
 
 from_env_var!(
-    /// The host address where Redis is running
+    /// The host address where Redis is running (or the path to its Unix socket)
     let name = RedisHost;
     let default: String = "127.0.0.1".to_string(); 
     let (env_var, allowed_values) = ("REDIS_HOST", "any string");
@@ -24,12 +25,22 @@ from_env_var!(
     let (env_var, allowed_values) = ("REDIS_FREQ", "a number of milliseconds");
     let from_str = |s| s.parse().map(Duration::from_millis).ok();
 );
+from_env_var!(
+    /// The most input to buffer from Redis (in bytes); past this, input is discarded
+    let name = RedisInputBufferMax;
+    let default: usize = 16 * 1024 * 1024;
+    let (env_var, allowed_values) = ("REDIS_INPUT_BUFFER_MAX", "a number of KiB (at least 16)");
+    let from_str = |s| match s.parse::<usize>() {
+        Ok(kib) if kib >= 16 => Some(kib * 1024),
+        _ => None,
+    };
+);
 from_env_var!(
     /// The password to use for Redis
     let name = RedisPass;
-    let default: Option<String> = None;
+    let default: Option<Secret> = None;
     let (env_var, allowed_values) = ("REDIS_PASSWORD", "any string");
-    let from_str = |s| Some(Some(s.to_string()));
+    let from_str = |s| Some(Some(Secret::new(s)));
 );
 from_env_var!(
     /// An optional Redis Namespace
@@ -39,16 +50,65 @@ from_env_var!(
     let from_str = |s| Some(Some(s.to_string()));
 );
 from_env_var!(
-    /// A user for Redis (not supported)
+    /// A user for Redis (for Redis 6 ACLs; requires a `RedisPass`)
     let name = RedisUser;
     let default: Option<String> = None;
     let (env_var, allowed_values) = ("REDIS_USER", "any string");
     let from_str = |s| Some(Some(s.to_string()));
 );
 from_env_var!(
-    /// The database to use with Redis (no current effect for PubSub connections)
+    /// The database for the `subscribed:` keys (PubSub connections don't use databases)
     let name = RedisDb;
+    let default: Option<u32> = None;
+    let (env_var, allowed_values) = ("REDIS_DB", "a database number");
+    let from_str = |s| s.parse().map(Some).ok();
+);
+from_env_var!(
+    /// Whether to connect to Redis over TLS
+    let name = RedisTls;
+    let default: bool = false;
+    let (env_var, allowed_values) = ("REDIS_TLS", "true or false");
+    let from_str = |s| s.parse().ok();
+);
+from_env_var!(
+    /// A file of CA certificates for verifying the Redis server's certificate
+    let name = RedisTlsCaFile;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("REDIS_TLS_CA_FILE", "a path to a PEM file");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+from_env_var!(
+    /// The client certificate to present to Redis
+    let name = RedisTlsCertFile;
     let default: Option<String> = None;
-    let (env_var, allowed_values) = ("REDIS_DB", "any string");
+    let (env_var, allowed_values) = ("REDIS_TLS_CERT_FILE", "a path to a PEM file");
     let from_str = |s| Some(Some(s.to_string()));
 );
+from_env_var!(
+    /// The private key for the client certificate
+    let name = RedisTlsKeyFile;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("REDIS_TLS_KEY_FILE", "a path to a PEM file");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+from_env_var!(
+    /// The name to expect on the Redis server's certificate, if it isn't the host connected to
+    let name = RedisTlsServerName;
+    let default: Option<String> = None;
+    let (env_var, allowed_values) = ("REDIS_TLS_SERVER_NAME", "any string");
+    let from_str = |s| Some(Some(s.to_string()));
+);
+from_env_var!(
+    /// The addresses of the Redis Sentinels to ask for the current master (if using Sentinel)
+    let name = RedisSentinels;
+    let default: Vec<String> = Vec::new();
+    let (env_var, allowed_values) = ("REDIS_SENTINELS", "a comma-separated list of host:port");
+    let from_str = |s| Some(s.split(',').map(|addr| addr.trim().to_string()).collect());
+);
+from_env_var!(
+    /// The name of the master to ask the Redis Sentinels about
+    let name = RedisSentinelMaster;
+    let default: String = "mymaster".to_string();
+    let (env_var, allowed_values) = ("REDIS_SENTINEL_MASTER", "any string");
+    let from_str = |s| Some(s.to_string());
+);
@@ -0,0 +1,122 @@
+use super::*;
+
+fn vars(vars: &[(&str, &str)]) -> HashMap<String, String> {
+    vars.iter()
+        .map(|(k, v)| (k.to_string(), v.to_string()))
+        .collect()
+}
+
+#[test]
+fn changed_vars_splits_reloadable_settings_from_the_rest() {
+    let old = vars(&[
+        ("RUST_LOG", "warn"),
+        ("PORT", "4000"),
+        ("WHITELIST_MODE", "false"),
+    ]);
+    let new = vars(&[
+        ("RUST_LOG", "info"),
+        ("PORT", "4001"),
+        ("UNRELATED", "value"),
+    ]);
+
+    let (reloaded, need_restart) = changed_vars(&old, &new);
+    assert_eq!(reloaded, vec!["RUST_LOG", "WHITELIST_MODE"]);
+    assert_eq!(need_restart, vec!["PORT"]);
+}
+
+#[test]
+fn cors_origins_match_exactly_or_by_subdomain() -> std::result::Result<(), Error> {
+    let cfg = Deployment::from_env(&EnvVar::new(vars(&[(
+        "CORS_ALLOWED_ORIGINS",
+        "https://example.com, https://*.example.org",
+    )])))?
+    .cors;
+
+    assert!(cfg.allows_origin("https://example.com"));
+    assert!(cfg.allows_origin("https://EXAMPLE.com"));
+    assert!(!cfg.allows_origin("http://example.com"));
+    assert!(!cfg.allows_origin("https://www.example.com"));
+    assert!(cfg.allows_origin("https://www.example.org"));
+    assert!(cfg.allows_origin("https://a.b.example.org"));
+    assert!(!cfg.allows_origin("https://example.org"));
+    assert!(!cfg.allows_origin("https://evilexample.org"));
+    Ok(())
+}
+
+#[test]
+fn invalid_cors_settings_are_config_errors() {
+    for (var, value) in &[
+        ("CORS_ALLOWED_ORIGINS", "example.com"),
+        ("CORS_ALLOWED_ORIGINS", "https://example.com/path"),
+        ("CORS_ALLOWED_METHODS", "GET,,OPTIONS"),
+        ("CORS_ALLOWED_HEADERS", "Authorization,Bad Header"),
+    ] {
+        let cfg = Deployment::from_env(&EnvVar::new(vars(&[(*var, *value)])));
+        assert!(cfg.is_err(), "{}={} should be rejected", var, value);
+    }
+}
+
+#[test]
+fn metrics_addr_needs_a_port() -> std::result::Result<(), Error> {
+    let cfg = Deployment::from_env(&EnvVar::new(vars(&[("METRICS_ADDR", "127.0.0.1:9090")])))?;
+    assert_eq!(*cfg.metrics_addr, Some(([127, 0, 0, 1], 9090).into()));
+
+    let cfg = Deployment::from_env(&EnvVar::new(vars(&[("METRICS_ADDR", "127.0.0.1")])));
+    assert!(cfg.is_err());
+    Ok(())
+}
+
+/// A config file for one test, removed when the test is done
+struct ConfigFile(std::path::PathBuf);
+
+impl ConfigFile {
+    /// Write `contents` to a file that's unique to this test (`name`) and this test run
+    fn new(name: &str, contents: &str) -> Self {
+        let file_name = format!("flodgatt_test_{}_{}.toml", name, std::process::id());
+        let path = std::env::temp_dir().join(file_name);
+        std::fs::write(&path, contents).expect("could not write the test config file");
+        Self(path)
+    }
+
+    fn path(&self) -> &str {
+        self.0.to_str().expect("temp dir is UTF-8")
+    }
+}
+
+impl Drop for ConfigFile {
+    fn drop(&mut self) {
+        std::fs::remove_file(&self.0).unwrap_or_default();
+    }
+}
+
+#[test]
+fn config_file_settings_give_way_to_env_vars() -> std::result::Result<(), Error> {
+    let file = ConfigFile::new(
+        "give_way",
+        "[postgres]\nhost = \"db.example\"\nport = 5433\n\n\
+         [deployment]\nport = 4001\ncors_allowed_origins = [\"https://a.example\", \"https://b.example\"]\n",
+    );
+
+    let merged = merge_config_file(
+        Some(file.path()),
+        vars(&[("PORT", "4002"), ("DB_PORT", "")]),
+    )?;
+    assert_eq!(
+        merged.get("DB_HOST").map(String::as_str),
+        Some("db.example")
+    );
+    assert_eq!(merged.get("DB_PORT").map(String::as_str), Some("5433"));
+    assert_eq!(merged.get("PORT").map(String::as_str), Some("4002"));
+    assert_eq!(
+        merged.get("CORS_ALLOWED_ORIGINS").map(String::as_str),
+        Some("https://a.example,https://b.example")
+    );
+    Ok(())
+}
+
+#[test]
+fn config_file_with_an_unknown_key_is_a_config_error() {
+    let file = ConfigFile::new("unknown_key", "[postgres]\nhots = \"db.example\"\n");
+    let unknown_key = merge_config_file(Some(file.path()), HashMap::new());
+    assert!(unknown_key.is_err());
+}
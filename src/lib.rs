@@ -43,6 +43,8 @@ pub use err::Error;
 
 pub mod config;
 mod err;
+pub mod logging;
+pub mod metrics;
 pub mod request;
 pub mod response;
 
@@ -0,0 +1,161 @@
+//! Log output, as text or as JSON lines, and structured records about client connections
+//!
+//! Records about a connection (its `accept`, each `subscribe`, `filtered` events, an
+//! `auth_failure` and its `disconnect`) carry the connection's ID, so that every line about a
+//! connection can be found.  They never include the access token, or text from the client.
+//! `filtered` records (one per event per client) are logged at the `debug` level.
+use crate::config::LogFormat;
+use crate::Id;
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+use serde_json::{json, Map, Value};
+use std::convert::TryFrom;
+use std::env;
+use std::io::{self, Write as _};
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::time::{Instant, SystemTime};
+
+/// The target of structured records
+const TARGET: &str = "flodgatt::connection";
+
+static JSON: AtomicBool = AtomicBool::new(false);
+static NEXT_CONN_ID: AtomicU64 = AtomicU64::new(1);
+
+struct Logger {
+    text: Box<dyn Log>,
+}
+
+/// Start logging (as text, until `set_format` says otherwise).  The logger passes every
+/// level, and `log::set_max_level` does the filtering, so that the level can be changed
+/// while running.
+pub fn init() -> Result<(), log::SetLoggerError> {
+    let text = pretty_env_logger::formatted_timed_builder()
+        .filter_level(LevelFilter::Trace)
+        .build();
+    log::set_boxed_logger(Box::new(Logger {
+        text: Box::new(text),
+    }))?;
+    // Until the configuration has been read
+    log::set_max_level(
+        env::var("RUST_LOG")
+            .ok()
+            .and_then(|level| level.parse().ok())
+            .unwrap_or(LevelFilter::Warn),
+    );
+    Ok(())
+}
+
+pub fn set_format(format: LogFormat) {
+    JSON.store(format == LogFormat::Json, Ordering::Release);
+}
+
+/// Log a structured record of an `event`, with `fields` (a JSON object)
+pub(crate) fn record(level: Level, event: &str, fields: Value) {
+    if level > log::max_level() {
+        return;
+    }
+    let fields = match fields {
+        Value::Object(fields) => fields,
+        _ => Map::new(),
+    };
+    if JSON.load(Ordering::Acquire) {
+        write_json(level, TARGET, event, fields);
+    } else {
+        let fields: Vec<_> = fields.iter().map(|(k, v)| format!("{}={}", k, v)).collect();
+        log::log!(target: TARGET, level, "{} {}", event, fields.join(" "));
+    }
+}
+
+fn write_json(level: Level, target: &str, event: &str, fields: Map<String, Value>) {
+    let mut line = Map::new();
+    line.insert(
+        "time".to_string(),
+        json!(humantime::format_rfc3339_millis(SystemTime::now()).to_string()),
+    );
+    line.insert("level".to_string(), json!(level.to_string()));
+    line.insert("target".to_string(), json!(target));
+    line.insert("event".to_string(), json!(event));
+    line.extend(fields);
+    // Like the text logger, ignore errors writing to stderr (there's nowhere to report them)
+    let _ = writeln!(io::stderr().lock(), "{}", Value::Object(line));
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= log::max_level()
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+        if JSON.load(Ordering::Acquire) {
+            let mut fields = Map::new();
+            fields.insert("msg".to_string(), json!(record.args().to_string()));
+            write_json(record.level(), record.target(), "log", fields);
+        } else {
+            self.text.log(record);
+        }
+    }
+
+    fn flush(&self) {
+        self.text.flush();
+    }
+}
+
+/// The context for records about one client connection; it logs the `disconnect` (with how
+/// long the connection lasted, and how much it was sent) when dropped
+pub(crate) struct ConnLog {
+    id: u64,
+    transport: &'static str,
+    user_id: Option<Id>,
+    opened: Instant,
+    bytes_sent: u64,
+}
+
+impl ConnLog {
+    /// Log a newly accepted connection, for a user (if authenticated) and a timeline kind
+    pub(crate) fn accept(transport: &'static str, user_id: Option<Id>, timeline: &str) -> Self {
+        let log = Self {
+            id: NEXT_CONN_ID.fetch_add(1, Ordering::Relaxed),
+            transport,
+            user_id,
+            opened: Instant::now(),
+            bytes_sent: 0,
+        };
+        log.record(Level::Info, "accept", json!({ "timeline": timeline }));
+        log
+    }
+
+    /// Log a structured record about this connection
+    pub(crate) fn record(&self, level: Level, event: &str, fields: Value) {
+        let mut fields = match fields {
+            Value::Object(fields) => fields,
+            _ => Map::new(),
+        };
+        fields.insert("conn".to_string(), json!(self.id));
+        fields.insert("transport".to_string(), json!(self.transport));
+        if let Some(Id(user_id)) = self.user_id {
+            fields.insert("user_id".to_string(), json!(user_id));
+        }
+        record(level, event, Value::Object(fields));
+    }
+
+    pub(crate) fn sent(&mut self, bytes: usize) {
+        self.bytes_sent += u64::try_from(bytes).unwrap_or(u64::max_value());
+    }
+}
+
+impl Drop for ConnLog {
+    fn drop(&mut self) {
+        let duration_ms = u64::try_from(self.opened.elapsed().as_millis());
+        self.record(
+            Level::Info,
+            "disconnect",
+            json!({
+                "duration_ms": duration_ms.unwrap_or(u64::max_value()),
+                "bytes_sent": self.bytes_sent,
+            }),
+        );
+    }
+}
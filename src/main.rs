@@ -1,71 +1,185 @@
 use flodgatt::config;
+use flodgatt::logging;
+use flodgatt::metrics;
 use flodgatt::request::{Handler, Subscription};
-use flodgatt::response::{RedisManager, SseStream, WsStream};
+use flodgatt::response::{
+    event_queue, open_connections, Permit, RedisManager, SseStream, WsStream,
+};
 use flodgatt::Error;
 
-use futures::future::lazy;
-use futures::stream::Stream as _;
+use futures::future::{lazy, loop_fn, Either, Future, Loop};
+use futures::stream::Stream;
+use futures::sync::oneshot;
+use hashbrown::HashMap;
+use std::env;
 use std::fs;
+use std::io;
 use std::net::SocketAddr;
 use std::os::unix::fs::PermissionsExt;
-use std::time::Instant;
-use tokio::net::UnixListener;
-use tokio::sync::mpsc;
-use tokio::timer::Interval;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::{Arc, Mutex, PoisonError, RwLock};
+use std::time::{Duration, Instant};
+use tokio::net::{TcpListener, UnixListener};
+use tokio::runtime::Runtime;
+use tokio::timer::{Delay, Interval};
 use warp::ws::Ws2;
 use warp::Filter;
 
+const USAGE: &str = "\
+Usage: flodgatt [--config PATH] [COMMAND]
+
+Commands:
+    check-config    Check the configuration, and that Postgres and Redis can be reached
+    print-config    Print the configuration (with passwords redacted)
+    (none)          Run the streaming server
+
+Options:
+    --config PATH   Read settings from a TOML config file, as well as from the environment
+    --version       Print the version
+    --help          Print this message
+";
+
+/// What to do, as given on the command line
+enum Command {
+    Serve,
+    CheckConfig,
+    PrintConfig,
+}
+
 fn main() -> Result<(), Error> {
+    let (command, config_flag) = match parse_args()? {
+        Some(args) => args,
+        None => return Ok(()), // printed the version or the help
+    };
+    let process_env: HashMap<String, String> = dotenv::vars().collect();
     config::merge_dotenv()?;
-    pretty_env_logger::try_init_timed()?;
-    let (postgres_cfg, redis_cfg, cfg) = config::from_env(dotenv::vars().collect())?;
+    logging::init()?;
+    let config_file = config::config_file(config_flag);
+    let env_vars = config::merge_config_file(config_file.as_deref(), dotenv::vars().collect())?;
+    let (postgres_cfg, redis_cfg, cfg) = config::from_env(env_vars.clone())?;
+    match command {
+        Command::Serve => (),
+        Command::PrintConfig => {
+            println!("{:#?}\n{:#?}\n{:#?}", postgres_cfg, redis_cfg, cfg);
+            return Ok(());
+        }
+        Command::CheckConfig => return check_config(&postgres_cfg, &redis_cfg, &cfg),
+    }
+    log::set_max_level(cfg.log_level.level_filter());
+    logging::set_format(*cfg.log_format);
     let poll_freq = *redis_cfg.polling_interval;
+    let current_poll_freq = Arc::new(RwLock::new(poll_freq));
+    let (queue_size, overflow) = (*cfg.event_queue_size, *cfg.event_queue_overflow);
+    metrics::log_events_older_than(*cfg.slow_event_threshold);
 
-    let request = Handler::new(&postgres_cfg, *cfg.whitelist_mode)?;
-    let shared_manager = RedisManager::try_from(&redis_cfg)?.into_arc();
+    let request = Handler::new(&postgres_cfg, &cfg)?;
+    let mut manager = RedisManager::try_from(&redis_cfg)?;
+    let revoke_request = request.clone();
+    manager.on_token_revoked(move |token_id| revoke_request.forget_access_token(token_id));
+    let filters_request = request.clone();
+    manager.on_filters_changed(move |user_id| filters_request.reload_filters(user_id));
+    let (id_request, name_request) = (request.clone(), request.clone());
+    manager.look_up_missing_hashtags(
+        move |name, found| id_request.hashtag_id(name, found),
+        move |id, found| name_request.hashtag_name(id, found),
+    );
+    manager.keep_events_for_replay(*cfg.replay_buffer_size, *cfg.replay_buffer_max_age);
+    manager.limit_connections(
+        *cfg.max_connections_per_user,
+        *cfg.max_connections_per_ip,
+        *cfg.max_connection_rate_per_ip,
+    );
+    let shared_manager = manager.into_arc();
+    let (stop_accepting, accepting_stopped) = oneshot::channel();
+    let shutdown = graceful_shutdown(
+        shared_manager.clone(),
+        stop_accepting,
+        *cfg.shutdown_timeout,
+        poll_freq,
+    )?;
+    let reload = reload_on_sighup(
+        request.clone(),
+        current_poll_freq.clone(),
+        process_env,
+        config_file,
+        env_vars,
+    )?;
 
     // Server Sent Events
-    let sse_manager = shared_manager.clone();
+    let (ip_manager, sse_manager) = (shared_manager.clone(), shared_manager.clone());
+    let admit_ip = move |ip| {
+        let manager = ip_manager.lock().unwrap_or_else(RedisManager::recover);
+        manager.admit_ip("sse", ip)
+    };
     let sse = request
-        .sse_subscription()
+        .sse_subscription(admit_ip)
         .and(warp::sse())
-        .map(move |subscription: Subscription, sse: warp::sse::Sse| {
-            log::info!("Incoming SSE request for {:?}", subscription.timeline);
-            let mut manager = sse_manager.lock().unwrap_or_else(RedisManager::recover);
-            let (event_tx, event_rx) = mpsc::channel(10);
-            manager.subscribe(&subscription, event_tx);
-            let sse_stream = SseStream::new(subscription);
-            sse_stream.send_events(sse, event_rx)
-        })
+        .and_then(
+            move |(subscription, mut permit): (Subscription, Permit), sse: warp::sse::Sse| {
+                let mut manager = sse_manager.lock().unwrap_or_else(RedisManager::recover);
+                manager
+                    .admit_user("sse", &mut permit, subscription.user_id)
+                    .map_err(warp::reject::custom)?;
+                let (event_tx, event_rx) = event_queue(queue_size, overflow);
+                manager.subscribe(&subscription, event_tx);
+                let sse_stream = SseStream::new(subscription, permit);
+                Ok::<_, warp::Rejection>(sse_stream.send_events(sse, event_rx))
+            },
+        )
         .with(warp::reply::with::header("Connection", "keep-alive"));
 
     // WebSocket
-    let ws_manager = shared_manager.clone();
+    let (ws_ip_manager, ws_manager) = (shared_manager.clone(), shared_manager.clone());
+    let ws_request = request.clone();
+    let admit_ws_ip = move |ip| {
+        let manager = ws_ip_manager.lock().unwrap_or_else(RedisManager::recover);
+        manager.admit_ip("ws", ip)
+    };
     let ws = request
-        .ws_subscription()
+        .ws_subscription(admit_ws_ip)
         .and(warp::ws::ws2())
-        .map(move |subscription: Subscription, ws: Ws2| {
-            log::info!("Incoming websocket request for {:?}", subscription.timeline);
-            let mut manager = ws_manager.lock().unwrap_or_else(RedisManager::recover);
-            let (event_tx, event_rx) = mpsc::channel(10);
-            manager.subscribe(&subscription, event_tx);
-            let token = subscription.access_token.clone().unwrap_or_default(); // token sent for security
-            let ws_stream = WsStream::new(subscription);
-
-            (
-                ws.on_upgrade(move |ws| ws_stream.send_to(ws, event_rx)),
-                token,
-            )
-        })
+        .map(
+            move |(subscription, mut permit): (Subscription, Permit), ws: Ws2| {
+                let (event_tx, event_rx) = event_queue(queue_size, overflow);
+                let token = subscription.access_token.clone().unwrap_or_default(); // token sent for security
+                let admitted = ws_manager
+                    .lock()
+                    .unwrap_or_else(RedisManager::recover)
+                    .admit_user("ws", &mut permit, subscription.user_id);
+                // (A client over the per-user limit still gets the upgrade, so that it can be
+                // sent the reason)
+                let ws_stream = admitted.map(|()| {
+                    WsStream::new(
+                        ws_manager.clone(),
+                        ws_request.clone(),
+                        event_tx,
+                        subscription,
+                        permit,
+                    )
+                });
+
+                (
+                    ws.on_upgrade(move |ws| match ws_stream {
+                        Ok(ws_stream) => Either::A(ws_stream.send_to(ws, event_rx)),
+                        Err(refusal) => Either::B(WsStream::refuse(ws, refusal)),
+                    }),
+                    token,
+                )
+            },
+        )
         .map(|(reply, token)| warp::reply::with_header(reply, "sec-websocket-protocol", token));
 
     #[cfg(feature = "stub_status")]
     #[rustfmt::skip]
     let status = {
         let (r1, r2, r3) = (shared_manager.clone(), shared_manager.clone(), shared_manager.clone());
+        let cache_request = request.clone();
         request.health().map(|| "OK")
             .or(request.status()
-                .map(move || r1.lock().unwrap_or_else(RedisManager::recover).count()))
+                .map(move || format!("{}\n{}",
+                    r1.lock().unwrap_or_else(RedisManager::recover).count(),
+                    cache_request.cache_status())))
             .or(request.status_backpresure()
                 .map(move || r2.lock().unwrap_or_else(RedisManager::recover).backpresure()))
             .or(request.status_per_timeline()
@@ -74,39 +188,230 @@ fn main() -> Result<(), Error> {
     #[cfg(not(feature = "stub_status"))]
     let status = request.health().map(|| "OK");
 
-    let cors = warp::cors()
-        .allow_any_origin()
-        .allow_methods(cfg.cors.allowed_methods)
-        .allow_headers(cfg.cors.allowed_headers);
+    // (Only alongside the streaming API if they don't have a listener of their own)
+    let metrics = request.metrics(*cfg.metrics && cfg.metrics_addr.is_none());
+
+    let streaming = request.cors_preflight().or(request
+        .cors_origin()
+        .and(ws.or(sse))
+        .map(|origin: Option<String>, reply| Handler::with_cors(reply, origin)));
 
     let streaming_server = move || {
         let manager = shared_manager.clone();
-        let stream = Interval::new(Instant::now(), poll_freq)
-            .map_err(|e| log::error!("{}", e))
-            .for_each(move |_| {
-                match manager
-                    .lock()
-                    .unwrap_or_else(RedisManager::recover)
-                    .send_msgs()
-                {
-                    Err(e) => Ok(log::error!("{}", e)),
-                    Ok(_) => Ok(()),
-                }
-            });
+        // (With a `Delay`, rather than an `Interval`, so that reloading can change the frequency)
+        let stream = loop_fn((), move |()| {
+            if let Err(e) = manager
+                .lock()
+                .unwrap_or_else(RedisManager::recover)
+                .send_msgs()
+            {
+                log::error!("{}", e);
+            }
+            let poll_freq = *current_poll_freq
+                .read()
+                .unwrap_or_else(PoisonError::into_inner);
+            Delay::new(Instant::now() + poll_freq)
+                .map(Loop::Continue)
+                .map_err(|e| log::error!("{}", e))
+        });
 
         warp::spawn(lazy(move || stream));
-        warp::serve(ws.or(sse).with(cors).or(status).recover(Handler::err))
+        warp::serve(streaming.or(status).or(metrics).recover(Handler::err))
     };
 
+    let mut runtime = Runtime::new()?;
+    runtime.spawn(reload);
+    if let (true, Some(metrics_addr)) = (*cfg.metrics, *cfg.metrics_addr) {
+        log::info!("Serving metrics on {}", metrics_addr);
+        let incoming = TcpListener::bind(&metrics_addr)?.incoming();
+        let metrics = request.metrics(true);
+        runtime.spawn(lazy(move || warp::serve(metrics).serve_incoming(incoming)));
+    }
     if let Some(socket) = &*cfg.unix_socket {
         log::info!("Using Unix socket {}", socket);
         fs::remove_file(socket).unwrap_or_default();
-        let incoming = UnixListener::bind(socket)?.incoming();
+        let incoming = until_stopped(UnixListener::bind(socket)?.incoming(), accepting_stopped);
         fs::set_permissions(socket, PermissionsExt::from_mode(0o666))?;
-        tokio::run(lazy(|| streaming_server().serve_incoming(incoming)));
+        runtime.spawn(lazy(|| streaming_server().serve_incoming(incoming)));
     } else {
         let server_addr = SocketAddr::new(*cfg.address, *cfg.port);
-        tokio::run(lazy(move || streaming_server().bind(server_addr)));
+        let incoming = until_stopped(
+            TcpListener::bind(&server_addr)?.incoming(),
+            accepting_stopped,
+        );
+        runtime.spawn(lazy(move || streaming_server().serve_incoming(incoming)));
     }
-    Err(Error::Unrecoverable) // only reached if poll_broadcast encounters an unrecoverable error
+    // only fails if the timer driving the shutdown fails
+    runtime
+        .block_on(shutdown)
+        .map_err(|()| Error::Unrecoverable)
+}
+
+/// The command and the config file (given with `--config PATH` or `--config=PATH`), or
+/// `None` once `--version` or `--help` has been answered
+fn parse_args() -> Result<Option<(Command, Option<String>)>, Error> {
+    let usage_err =
+        |msg: String| -> Error { config::Error::Config(format!("{}\n\n{}", msg, USAGE)).into() };
+    let mut args = env::args().skip(1);
+    let (mut command, mut path) = (Command::Serve, None);
+    while let Some(arg) = args.next() {
+        match arg.as_str() {
+            "--version" | "-V" => {
+                println!("flodgatt {}", env!("CARGO_PKG_VERSION"));
+                return Ok(None);
+            }
+            "--help" | "-h" => {
+                print!("{}", USAGE);
+                return Ok(None);
+            }
+            "check-config" => command = Command::CheckConfig,
+            "print-config" => command = Command::PrintConfig,
+            "--config" => match args.next() {
+                Some(arg) => path = Some(arg),
+                None => return Err(usage_err("--config must be followed by a path".to_string())),
+            },
+            arg if arg.starts_with("--config=") => {
+                path = Some(arg["--config=".len()..].to_string())
+            }
+            arg => return Err(usage_err(format!("unknown argument `{}`", arg))),
+        }
+    }
+    Ok(Some((command, path)))
+}
+
+/// Check that Flodgatt can connect to Postgres and to Redis (the configuration itself has
+/// already been checked, as it was read)
+fn check_config(
+    postgres_cfg: &config::Postgres,
+    redis_cfg: &config::Redis,
+    cfg: &config::Deployment,
+) -> Result<(), Error> {
+    println!("The configuration is valid");
+    let postgres = Handler::new(postgres_cfg, cfg)
+        .map(|_| ())
+        .map_err(Error::from);
+    let redis = RedisManager::try_from(redis_cfg)
+        .map(|_| ())
+        .map_err(Error::from);
+    for (name, result) in &[("Postgres", &postgres), ("Redis", &redis)] {
+        match result {
+            Ok(()) => println!("{}: connected", name),
+            Err(e) => eprintln!("{}: {}", name, e),
+        }
+    }
+    if postgres.is_err() || redis.is_err() {
+        process::exit(1);
+    }
+    Ok(())
+}
+
+/// On SIGHUP, read the configuration again and apply the settings that can change while
+/// running.  Changes to other settings are logged, but only take effect after a restart.
+fn reload_on_sighup(
+    request: Handler,
+    poll_freq: Arc<RwLock<Duration>>,
+    process_env: HashMap<String, String>,
+    config_file: Option<String>,
+    startup_vars: HashMap<String, String>,
+) -> Result<impl Future<Item = (), Error = ()>, Error> {
+    let signaled = Arc::new(AtomicBool::new(false));
+    signal_hook::flag::register(signal_hook::SIGHUP, signaled.clone())?;
+    let mut current_vars = startup_vars.clone();
+
+    Ok(Interval::new(Instant::now(), Duration::from_secs(1))
+        .map_err(|e| log::error!("{}", e))
+        .filter(move |_| signaled.swap(false, Ordering::AcqRel))
+        .for_each(move |_| {
+            let reloaded = config::reread_env_file(&process_env).and_then(|vars| {
+                let vars = config::merge_config_file(config_file.as_deref(), vars)?;
+                let cfg = config::from_env(vars.clone())?;
+                Ok((vars, cfg))
+            });
+            let (vars, (_, redis_cfg, cfg)) = match reloaded {
+                Ok(reloaded) => reloaded,
+                Err(e) => {
+                    log::error!("Could not reload the configuration: {}", e);
+                    return Ok(());
+                }
+            };
+            log::set_max_level(cfg.log_level.level_filter());
+            logging::set_format(*cfg.log_format);
+            *poll_freq.write().unwrap_or_else(PoisonError::into_inner) =
+                *redis_cfg.polling_interval;
+            request.reload(&cfg);
+
+            let (applied, _) = config::changed_vars(&current_vars, &vars);
+            let (_, need_restart) = config::changed_vars(&startup_vars, &vars);
+            log::warn!(
+                "Reloaded the configuration; applied changes to {:?}",
+                applied
+            );
+            if !need_restart.is_empty() {
+                log::warn!(
+                    "Changes to {:?} will take effect after a restart",
+                    need_restart
+                );
+            }
+            current_vars = vars;
+            Ok(())
+        }))
+}
+
+/// Once SIGTERM or SIGINT arrives, stop accepting connections, tell every client that we're
+/// shutting down, and wait (up to `timeout`) for them to disconnect.  Then unsubscribe from
+/// Redis, which resets the `subscribed:` keys.  A second signal exits immediately.
+fn graceful_shutdown(
+    manager: Arc<Mutex<RedisManager>>,
+    stop_accepting: oneshot::Sender<()>,
+    timeout: Duration,
+    poll_freq: Duration,
+) -> Result<impl Future<Item = (), Error = ()>, Error> {
+    let signaled = Arc::new(AtomicBool::new(false));
+    for signal in &[signal_hook::SIGTERM, signal_hook::SIGINT] {
+        signal_hook::flag::register_conditional_shutdown(*signal, 1, signaled.clone())?;
+        signal_hook::flag::register(*signal, signaled.clone())?;
+    }
+    let ticks = move || Interval::new(Instant::now(), poll_freq).map_err(|e| log::error!("{}", e));
+
+    Ok(ticks()
+        .take_while(move |_| Ok(!signaled.load(Ordering::Acquire)))
+        .for_each(|_| Ok(()))
+        .and_then(move |()| {
+            log::warn!("Shutting down (waiting up to {:?} for clients)", timeout);
+            stop_accepting.send(()).unwrap_or_default(); // Err if the server already stopped
+            manager
+                .lock()
+                .unwrap_or_else(RedisManager::recover)
+                .close_channels();
+            let deadline = Instant::now() + timeout;
+            ticks()
+                .take_while(move |_| Ok(open_connections() > 0 && Instant::now() < deadline))
+                .for_each(|_| Ok(()))
+                .map(move |()| manager)
+        })
+        .map(|manager| {
+            if open_connections() > 0 {
+                log::warn!("Dropping {} connections", open_connections());
+            }
+            manager
+                .lock()
+                .unwrap_or_else(RedisManager::recover)
+                .unsubscribe_all()
+                .unwrap_or_else(|e| log::error!("Could not unsubscribe from Redis: {}", e));
+        }))
+}
+
+/// Connections from `incoming`, until `stop` fires (or its sender is dropped)
+fn until_stopped<S>(
+    incoming: S,
+    stop: oneshot::Receiver<()>,
+) -> impl Stream<Item = S::Item, Error = io::Error>
+where
+    S: Stream<Error = io::Error>,
+{
+    incoming
+        .map(Some)
+        .select(stop.then(|_| Ok(None)).into_stream())
+        .take_while(|conn| Ok(conn.is_some()))
+        .filter_map(|conn| conn)
 }
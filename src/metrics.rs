@@ -0,0 +1,403 @@
+//! Counters, gauges and histograms for monitoring, rendered in the Prometheus text format
+//!
+//! Every label value is a `&'static str` so that the number of series stays bounded: timelines
+//! are labeled by their kind (`hashtag`, `user`, …) rather than by the specific tag or user.
+use hashbrown::HashMap;
+use lazy_static::lazy_static;
+use std::convert::TryFrom;
+use std::fmt::{self, Write as _};
+use std::sync::atomic::{AtomicI64, AtomicU64, Ordering};
+use std::sync::{PoisonError, RwLock};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+lazy_static! {
+    pub(crate) static ref METRICS: Metrics = Metrics::default();
+}
+
+pub(crate) struct Metrics {
+    pub(crate) connections: Family,
+    pub(crate) subscriptions: Family,
+    pub(crate) redis_events: Family,
+    pub(crate) events_delivered: Family,
+    pub(crate) events_filtered: Family,
+    pub(crate) channel_full: Family,
+    pub(crate) redis_input_buffer: Family,
+    pub(crate) redis_input_discarded: Family,
+    pub(crate) pg_pool_connections: Family,
+    pub(crate) pg_pool_max_connections: Family,
+    pub(crate) auth_rejections: Family,
+    pub(crate) connections_refused: Family,
+    pub(crate) cache_requests: Family,
+    pub(crate) event_latency: Histogram,
+    /// Events older than this (in ms) are logged; 0 to never log them
+    slow_event_threshold: AtomicU64,
+}
+
+/// A metric with one value for each combination of label values
+pub(crate) struct Family {
+    name: &'static str,
+    help: &'static str,
+    kind: Kind,
+    labels: &'static [&'static str],
+    values: RwLock<HashMap<Vec<&'static str>, AtomicI64>>,
+}
+
+/// A histogram with one set of buckets for each combination of label values
+pub(crate) struct Histogram {
+    name: &'static str,
+    help: &'static str,
+    labels: &'static [&'static str],
+    /// The upper bounds of the buckets, in milliseconds
+    bounds: &'static [u64],
+    values: RwLock<HashMap<Vec<&'static str>, Buckets>>,
+}
+
+struct Buckets {
+    counts: Vec<AtomicU64>,
+    sum_ms: AtomicU64,
+    count: AtomicU64,
+}
+
+#[derive(Clone, Copy)]
+enum Kind {
+    Counter,
+    Gauge,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        use Kind::{Counter, Gauge};
+        Self {
+            connections: Family::new(
+                "flodgatt_connections",
+                "Open client connections",
+                Gauge,
+                &["transport"],
+            ),
+            subscriptions: Family::new(
+                "flodgatt_subscriptions",
+                "Active timeline subscriptions (a WebSocket can carry several)",
+                Gauge,
+                &["transport", "timeline"],
+            ),
+            redis_events: Family::new(
+                "flodgatt_redis_events_received_total",
+                "Events received from Redis, by the kind of timeline of their channel",
+                Counter,
+                &["timeline"],
+            ),
+            events_delivered: Family::new(
+                "flodgatt_events_delivered_total",
+                "Events sent to clients",
+                Counter,
+                &["transport", "timeline"],
+            ),
+            events_filtered: Family::new(
+                "flodgatt_events_filtered_total",
+                "Events not sent to a client because of its language, block or keyword filters",
+                Counter,
+                &["transport", "reason"],
+            ),
+            channel_full: Family::new(
+                "flodgatt_channel_full_total",
+                "Events that could not be handed to a client because its channel was full",
+                Counter,
+                &["timeline"],
+            ),
+            redis_input_buffer: Family::new(
+                "flodgatt_redis_input_buffer_bytes",
+                "Bytes read from Redis but not yet processed",
+                Gauge,
+                &[],
+            ),
+            redis_input_discarded: Family::new(
+                "flodgatt_redis_input_discarded_bytes_total",
+                "Bytes read from Redis and discarded because the input buffer was full",
+                Counter,
+                &[],
+            ),
+            pg_pool_connections: Family::new(
+                "flodgatt_postgres_pool_connections",
+                "Connections in the Postgres pool",
+                Gauge,
+                &["state"],
+            ),
+            pg_pool_max_connections: Family::new(
+                "flodgatt_postgres_pool_max_connections",
+                "The maximum size of the Postgres pool",
+                Gauge,
+                &[],
+            ),
+            auth_rejections: Family::new(
+                "flodgatt_auth_rejections_total",
+                "Subscription requests rejected for lack of authorization",
+                Counter,
+                &["reason"],
+            ),
+            connections_refused: Family::new(
+                "flodgatt_connections_refused_total",
+                "Connections refused for going over a connection limit",
+                Counter,
+                &["transport", "reason"],
+            ),
+            cache_requests: Family::new(
+                "flodgatt_cache_requests_total",
+                "Lookups in the access token and blocks caches",
+                Counter,
+                &["cache", "result"],
+            ),
+            event_latency: Histogram::new(
+                "flodgatt_event_latency_seconds",
+                "Time since Mastodon queued an event, when flodgatt read it from Redis, handed \
+                 it to a client's channel, or handed it to a client's socket",
+                &["stage", "timeline"],
+                &[
+                    5, 10, 25, 50, 100, 250, 500, 1000, 2500, 5000, 10_000, 30_000, 60_000,
+                ],
+            ),
+            slow_event_threshold: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// All metrics, in the Prometheus text exposition format
+    pub(crate) fn render(&self) -> String {
+        let mut out = String::new();
+        for family in &[
+            &self.connections,
+            &self.subscriptions,
+            &self.redis_events,
+            &self.events_delivered,
+            &self.events_filtered,
+            &self.channel_full,
+            &self.redis_input_buffer,
+            &self.redis_input_discarded,
+            &self.pg_pool_connections,
+            &self.pg_pool_max_connections,
+            &self.auth_rejections,
+            &self.connections_refused,
+            &self.cache_requests,
+        ] {
+            family
+                .render(&mut out)
+                .expect("writing to a String cannot fail");
+        }
+        self.event_latency
+            .render(&mut out)
+            .expect("writing to a String cannot fail");
+        out
+    }
+
+    /// Record the age of an event (queued by Mastodon at `queued_at`, if known) as of `stage`,
+    /// and log it if it's older than the slow-event threshold as it's read from Redis (later
+    /// stages would log the same event again, once for every client)
+    pub(crate) fn observe_event_age(
+        &self,
+        stage: &'static str,
+        timeline: &'static str,
+        queued_at: Option<i64>,
+    ) {
+        let queued_at = match queued_at {
+            Some(queued_at) => queued_at,
+            None => return,
+        };
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default()
+            .as_millis();
+        let queued_at = u128::try_from(queued_at).unwrap_or_default();
+        let age_ms = u64::try_from(now.saturating_sub(queued_at)).unwrap_or(u64::max_value());
+        self.event_latency.observe(&[stage, timeline], age_ms);
+
+        let threshold = self.slow_event_threshold.load(Ordering::Relaxed);
+        if stage == "redis" && threshold > 0 && age_ms > threshold {
+            log::warn!(
+                "{} event was {}ms old when read from Redis (queued at {})",
+                timeline,
+                age_ms,
+                queued_at
+            );
+        }
+    }
+}
+
+/// Log events that are older than `threshold` (measured from when Mastodon queued them) when
+/// flodgatt reads them from Redis
+pub fn log_events_older_than(threshold: Option<Duration>) {
+    let threshold_ms = threshold.map_or(0, |t| {
+        u64::try_from(t.as_millis()).unwrap_or(u64::max_value())
+    });
+    METRICS
+        .slow_event_threshold
+        .store(threshold_ms, Ordering::Relaxed);
+}
+
+impl Family {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        kind: Kind,
+        labels: &'static [&'static str],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            kind,
+            labels,
+            values: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn inc(&self, label_values: &[&'static str]) {
+        self.add(label_values, 1);
+    }
+
+    pub(crate) fn dec(&self, label_values: &[&'static str]) {
+        self.add(label_values, -1);
+    }
+
+    pub(crate) fn add(&self, label_values: &[&'static str], n: i64) {
+        self.update(label_values, |value| value.fetch_add(n, Ordering::Relaxed));
+    }
+
+    pub(crate) fn set(&self, label_values: &[&'static str], n: i64) {
+        self.update(label_values, |value| value.swap(n, Ordering::Relaxed));
+    }
+
+    fn update(&self, label_values: &[&'static str], f: impl Fn(&AtomicI64) -> i64) {
+        debug_assert_eq!(label_values.len(), self.labels.len(), "{}", self.name);
+        with_series(
+            &self.values,
+            label_values,
+            || AtomicI64::new(0),
+            |value| {
+                f(value);
+            },
+        );
+    }
+
+    fn render(&self, out: &mut String) -> fmt::Result {
+        let kind = match self.kind {
+            Kind::Counter => "counter",
+            Kind::Gauge => "gauge",
+        };
+        writeln!(out, "# HELP {} {}", self.name, self.help)?;
+        writeln!(out, "# TYPE {} {}", self.name, kind)?;
+
+        let values = self.values.read().unwrap_or_else(PoisonError::into_inner);
+        if values.is_empty() && self.labels.is_empty() {
+            writeln!(out, "{} 0", self.name)?;
+        }
+        let mut series: Vec<_> = values.iter().collect();
+        series.sort_by_key(|&(label_values, _)| label_values);
+        for (label_values, value) in series {
+            let labels = label_pairs(self.labels, label_values).join(",");
+            let value = value.load(Ordering::Relaxed);
+            match labels.as_str() {
+                "" => writeln!(out, "{} {}", self.name, value)?,
+                labels => writeln!(out, "{}{{{}}} {}", self.name, labels, value)?,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Histogram {
+    fn new(
+        name: &'static str,
+        help: &'static str,
+        labels: &'static [&'static str],
+        bounds: &'static [u64],
+    ) -> Self {
+        Self {
+            name,
+            help,
+            labels,
+            bounds,
+            values: RwLock::new(HashMap::new()),
+        }
+    }
+
+    pub(crate) fn observe(&self, label_values: &[&'static str], value_ms: u64) {
+        debug_assert_eq!(label_values.len(), self.labels.len(), "{}", self.name);
+        let new_buckets = || Buckets {
+            counts: self.bounds.iter().map(|_| AtomicU64::new(0)).collect(),
+            sum_ms: AtomicU64::new(0),
+            count: AtomicU64::new(0),
+        };
+        with_series(&self.values, label_values, new_buckets, |buckets| {
+            if let Some(i) = self.bounds.iter().position(|&bound| value_ms <= bound) {
+                buckets.counts[i].fetch_add(1, Ordering::Relaxed);
+            }
+            buckets.sum_ms.fetch_add(value_ms, Ordering::Relaxed);
+            buckets.count.fetch_add(1, Ordering::Relaxed);
+        });
+    }
+
+    fn render(&self, out: &mut String) -> fmt::Result {
+        writeln!(out, "# HELP {} {}", self.name, self.help)?;
+        writeln!(out, "# TYPE {} histogram", self.name)?;
+
+        let values = self.values.read().unwrap_or_else(PoisonError::into_inner);
+        let mut series: Vec<_> = values.iter().collect();
+        series.sort_by_key(|&(label_values, _)| label_values);
+        for (label_values, buckets) in series {
+            let labels = label_pairs(self.labels, label_values);
+            let with_le = |le: String| [&labels[..], &[format!("le=\"{}\"", le)]].concat();
+
+            let mut cumulative_count = 0;
+            for (bound, count) in self.bounds.iter().zip(&buckets.counts) {
+                cumulative_count += count.load(Ordering::Relaxed);
+                let le = Duration::from_millis(*bound).as_secs_f64().to_string();
+                let bucket_labels = with_le(le).join(",");
+                writeln!(
+                    out,
+                    "{}_bucket{{{}}} {}",
+                    self.name, bucket_labels, cumulative_count
+                )?;
+            }
+            let count = buckets.count.load(Ordering::Relaxed);
+            let sum_ms = buckets.sum_ms.load(Ordering::Relaxed);
+            let inf_labels = with_le("+Inf".to_string()).join(",");
+            writeln!(out, "{}_bucket{{{}}} {}", self.name, inf_labels, count)?;
+
+            let labels = labels.join(",");
+            let sum = Duration::from_millis(sum_ms).as_secs_f64();
+            writeln!(out, "{}_sum{{{}}} {}", self.name, labels, sum)?;
+            writeln!(out, "{}_count{{{}}} {}", self.name, labels, count)?;
+        }
+        Ok(())
+    }
+}
+
+/// The `label="value"` pairs for a series
+fn label_pairs(labels: &[&str], label_values: &[&str]) -> Vec<String> {
+    labels
+        .iter()
+        .zip(label_values)
+        .map(|(label, value)| format!("{}=\"{}\"", label, value))
+        .collect()
+}
+
+/// Apply `f` to the series for `label_values`, creating it with `new` if needed
+fn with_series<T>(
+    values: &RwLock<HashMap<Vec<&'static str>, T>>,
+    label_values: &[&'static str],
+    new: impl FnOnce() -> T,
+    f: impl FnOnce(&T),
+) {
+    // Only the first update for a set of label values needs the write lock
+    if let Some(value) = values
+        .read()
+        .unwrap_or_else(PoisonError::into_inner)
+        .get(label_values)
+    {
+        return f(value);
+    }
+    let mut values = values.write().unwrap_or_else(PoisonError::into_inner);
+    f(values.entry(label_values.to_vec()).or_insert_with(new));
+}
+
+#[cfg(test)]
+mod test;
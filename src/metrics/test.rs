@@ -0,0 +1,59 @@
+use super::*;
+
+#[test]
+fn family_renders_one_sorted_line_per_label_set() {
+    let family = Family::new(
+        "test_total",
+        "A test",
+        Kind::Counter,
+        &["transport", "timeline"],
+    );
+    family.inc(&["ws", "public"]);
+    family.add(&["sse", "user"], 3);
+    family.inc(&["ws", "public"]);
+
+    let mut out = String::new();
+    family.render(&mut out).expect("render");
+    assert_eq!(
+        out,
+        "# HELP test_total A test\n\
+         # TYPE test_total counter\n\
+         test_total{transport=\"sse\",timeline=\"user\"} 3\n\
+         test_total{transport=\"ws\",timeline=\"public\"} 2\n"
+    );
+}
+
+#[test]
+fn unlabeled_gauge_renders_before_being_set() {
+    let gauge = Family::new("test_bytes", "A test", Kind::Gauge, &[]);
+    let mut out = String::new();
+    gauge.render(&mut out).expect("render");
+    assert!(out.ends_with("# TYPE test_bytes gauge\ntest_bytes 0\n"));
+
+    gauge.set(&[], 42);
+    gauge.dec(&[]);
+    out.clear();
+    gauge.render(&mut out).expect("render");
+    assert!(out.ends_with("test_bytes 41\n"));
+}
+
+#[test]
+fn histogram_renders_cumulative_buckets_in_seconds() {
+    let histogram = Histogram::new("test_seconds", "A test", &["stage"], &[10, 100]);
+    histogram.observe(&["socket"], 5);
+    histogram.observe(&["socket"], 50);
+    histogram.observe(&["socket"], 5000);
+
+    let mut out = String::new();
+    histogram.render(&mut out).expect("render");
+    assert_eq!(
+        out,
+        "# HELP test_seconds A test\n\
+         # TYPE test_seconds histogram\n\
+         test_seconds_bucket{stage=\"socket\",le=\"0.01\"} 1\n\
+         test_seconds_bucket{stage=\"socket\",le=\"0.1\"} 2\n\
+         test_seconds_bucket{stage=\"socket\",le=\"+Inf\"} 3\n\
+         test_seconds_sum{stage=\"socket\"} 5.055\n\
+         test_seconds_count{stage=\"socket\"} 3\n"
+    );
+}
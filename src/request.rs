@@ -1,4 +1,8 @@
 //! Parse the client request and return a Subscription
+mod cache;
+mod cors;
+mod filter;
+mod live;
 mod postgres;
 mod query;
 mod timeline;
@@ -7,27 +11,42 @@ mod err;
 mod subscription;
 
 pub use err::{Error, Timeline as TimelineErr};
-pub use subscription::{Blocks, Subscription};
+pub use filter::Filters;
+#[cfg(test)]
+pub(crate) use filter::{Filter, FilterContext};
+pub use query::{WsMsg, WsStreamMsg};
+pub use subscription::{Blocks, LiveBlocks, LiveFilters, Subscription};
 pub use timeline::Timeline;
 
-#[cfg(feature = "bench")]
+#[cfg(any(test, feature = "bench"))]
 pub use timeline::{Content, Reach, Stream};
 
-#[cfg(not(feature = "bench"))]
+#[cfg(not(any(test, feature = "bench")))]
 use timeline::{Content, Reach, Stream};
 
+use self::cors::Cors;
 pub use self::postgres::PgPool;
 use self::query::Query;
-use crate::config::Postgres;
+use crate::config::{Deployment, Postgres};
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::response::{Permit, Refusal};
+use crate::Id;
+use futures::future::{self, Either};
+use futures::Future;
+use std::net::{IpAddr, SocketAddr};
+use std::sync::Arc;
 use warp::filters::BoxedFilter;
 use warp::http::StatusCode;
 use warp::path;
 use warp::reply;
-use warp::{Filter, Rejection};
+use warp::{Filter, Rejection, Reply};
 
 #[cfg(test)]
 mod sse_test;
 #[cfg(test)]
+mod test;
+#[cfg(test)]
 mod ws_test;
 
 type Result<T> = std::result::Result<T, err::Error>;
@@ -46,13 +65,15 @@ macro_rules! parse_sse_query {
             .and(query::Media::to_filter())
             .and(query::Hashtag::to_filter())
             .and(query::List::to_filter())
-            .map(|auth: query::Auth, media: query::Media, hashtag: query::Hashtag, list: query::List| {
+            .and(query::Since::to_filter())
+            .map(|auth: query::Auth, media: query::Media, hashtag: query::Hashtag, list: query::List, since: query::Since| {
                 Query {
                     access_token: auth.access_token,
-                    stream: $endpoint.to_string(),
+                    stream: Some($endpoint.to_string()),
                     media: media.is_truthy(),
                     hashtag: hashtag.tag,
                     list: list.list,
+                    since: since.since,
                 }
             },
         )
@@ -63,16 +84,50 @@ macro_rules! parse_sse_query {
 #[derive(Clone)]
 pub struct Handler {
     pg_conn: PgPool,
+    cors: Cors,
+    /// The proxies whose `X-Forwarded-For` headers we believe
+    trusted_proxies: Arc<Vec<IpAddr>>,
 }
 
 impl Handler {
-    pub fn new(postgres_cfg: &Postgres, whitelist_mode: bool) -> Result<Self> {
+    pub fn new(postgres_cfg: &Postgres, cfg: &Deployment) -> Result<Self> {
+        let pg_conn = PgPool::new(postgres_cfg, *cfg.whitelist_mode)?;
+        pg_conn.refresh_blocks_every(*postgres_cfg.blocks_refresh);
         Ok(Self {
-            pg_conn: PgPool::new(postgres_cfg, whitelist_mode)?,
+            pg_conn,
+            cors: Cors::new(cfg.cors.clone()),
+            trusted_proxies: Arc::new(cfg.trusted_proxies.to_vec()),
         })
     }
 
-    pub fn sse_subscription(&self) -> BoxedFilter<(Subscription,)> {
+    /// A `Handler` for tests that never get as far as querying Postgres
+    #[cfg(test)]
+    pub(crate) fn unconnected() -> Self {
+        Self {
+            pg_conn: PgPool::unconnected(),
+            cors: Cors::new(Default::default()),
+            trusted_proxies: Arc::new(Vec::new()),
+        }
+    }
+
+    /// Apply the settings from a reloaded configuration that can change while running
+    pub fn reload(&self, cfg: &Deployment) {
+        self.pg_conn.set_whitelist_mode(*cfg.whitelist_mode);
+        self.cors.update(cfg.cors.clone());
+    }
+
+    /// The subscription an SSE request asks for, once Postgres has authorized it.  The client
+    /// is first admitted from its IP address by `admit_ip` (before anything is queried, so
+    /// that a client over the per-IP limits costs no queries), and its `Permit` comes along
+    /// with the subscription.
+    pub fn sse_subscription<A>(&self, admit_ip: A) -> BoxedFilter<((Subscription, Permit),)>
+    where
+        A: Fn(Option<IpAddr>) -> std::result::Result<Permit, Refusal>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
         let pg_conn = self.pg_conn.clone();
         any_of!(
             parse_sse_query!( path => "api" / "v1" / "streaming" / "user" / "notification"
@@ -96,16 +151,105 @@ impl Handler {
         // parameter, we need to update our Query if the header has a token
         .and(query::OptionalAccessToken::from_sse_header())
         .and_then(Query::update_access_token)
-        .and_then(move |q| Subscription::query_postgres(q, pg_conn.clone()))
+        .and(query::LastEventId::from_sse_header())
+        .and_then(Query::update_since)
+        .and(self.client_ip())
+        .and_then(move |q: Query, ip: Option<IpAddr>| admit_then_query(&pg_conn, &admit_ip, q, ip))
         .boxed()
     }
 
-    pub fn ws_subscription(&self) -> BoxedFilter<(Subscription,)> {
+    /// The subscription a WebSocket request asks for (to `Timeline::empty()` if it doesn't
+    /// name a `stream`), once Postgres has authorized it.  As with `sse_subscription`, the
+    /// client is first admitted from its IP address by `admit_ip`.
+    pub fn ws_subscription<A>(&self, admit_ip: A) -> BoxedFilter<((Subscription, Permit),)>
+    where
+        A: Fn(Option<IpAddr>) -> std::result::Result<Permit, Refusal>
+            + Clone
+            + Send
+            + Sync
+            + 'static,
+    {
         let pg_conn = self.pg_conn.clone();
         parse_ws_query()
             .and(query::OptionalAccessToken::from_ws_header())
             .and_then(Query::update_access_token)
-            .and_then(move |q| Subscription::query_postgres(q, pg_conn.clone()))
+            .and(self.client_ip())
+            .and_then(move |q: Query, ip: Option<IpAddr>| {
+                admit_then_query(&pg_conn, &admit_ip, q, ip)
+            })
+            .boxed()
+    }
+
+    /// Authorize a subscription requested with a `subscribe` message over an open WebSocket
+    pub fn ws_msg_subscription(
+        &self,
+        msg: WsStreamMsg,
+        access_token: Option<String>,
+    ) -> impl Future<Item = Subscription, Error = Rejection> {
+        let q = msg.into_query(access_token);
+        self.pg_conn
+            .spawn_blocking(move |pool| Subscription::query_postgres(q, pool))
+    }
+
+    /// Reload a user's keyword filters (on Postgres's threads), for all of their connections
+    pub fn reload_filters(&self, user_id: Id) {
+        self.pg_conn
+            .spawn_blocking(move |pool| pool.reload_filters(user_id))
+            .forget();
+    }
+
+    /// Query Postgres (on its own threads) for the ID of the hashtag with this name, then
+    /// call `found` with it (if the tag exists)
+    pub fn hashtag_id(&self, name: String, found: impl FnOnce(Option<i64>) + Send + 'static) {
+        self.pg_conn
+            .spawn_blocking(move |pool| Ok(found(pool.select_hashtag_id(&name).ok())))
+            .forget();
+    }
+
+    /// Query Postgres (on its own threads) for the name of the hashtag with this ID, then
+    /// call `found` with it (if the tag exists)
+    pub fn hashtag_name(&self, id: i64, found: impl FnOnce(Option<String>) + Send + 'static) {
+        self.pg_conn
+            .spawn_blocking(move |pool| Ok(found(pool.select_hashtag_name(id).ok())))
+            .forget();
+    }
+
+    /// Stop accepting a revoked access token (which may still be cached)
+    pub fn forget_access_token(&self, token_id: i64) {
+        self.pg_conn.forget_access_token(token_id)
+    }
+
+    /// The hit and miss counts of the access token and blocks caches
+    pub fn cache_status(&self) -> String {
+        self.pg_conn.cache_status()
+    }
+
+    /// Answer CORS preflight requests for the streaming API
+    pub fn cors_preflight(&self) -> BoxedFilter<(impl Reply,)> {
+        self.cors.preflight()
+    }
+
+    /// The request's `Origin` header, if any; requests from origins that aren't allowed are
+    /// rejected
+    pub fn cors_origin(&self) -> BoxedFilter<(Option<String>,)> {
+        self.cors.origin()
+    }
+
+    /// Add CORS headers to a reply, given the request's (allowed) `Origin` header
+    pub fn with_cors(reply: impl Reply, origin: Option<String>) -> impl Reply {
+        Cors::allow_origin(reply, origin)
+    }
+
+    /// The client's IP address (see `client_ip`)
+    pub fn client_ip(&self) -> BoxedFilter<(Option<IpAddr>,)> {
+        let trusted_proxies = self.trusted_proxies.clone();
+        warp::header::optional::<String>("x-forwarded-for")
+            .and(warp::addr::remote())
+            .map(
+                move |forwarded: Option<String>, remote: Option<SocketAddr>| {
+                    client_ip(forwarded.as_deref(), remote, &trusted_proxies)
+                },
+            )
             .boxed()
     }
 
@@ -127,12 +271,36 @@ impl Handler {
         warp::path!("api" / "v1" / "streaming" / "status" / "backpresure").boxed()
     }
 
+    /// Prometheus metrics, if `enabled`
+    pub fn metrics(&self, enabled: bool) -> BoxedFilter<(String,)> {
+        let pg_conn = self.pg_conn.clone();
+        warp::path!("metrics")
+            .and(warp::path::end())
+            .and_then(move || match enabled {
+                true => {
+                    pg_conn.update_metrics();
+                    Ok(METRICS.render())
+                }
+                false => Err(warp::reject::not_found()),
+            })
+            .boxed()
+    }
+
     pub fn err(r: Rejection) -> std::result::Result<impl warp::Reply, warp::Rejection> {
         use StatusCode as Code;
+        // (Refusals are already logged, by `RedisManager::admit`)
+        if let Some(refusal) = r.find_cause::<Refusal>() {
+            let msg = refusal.reason();
+            return Ok(reply::with_status(
+                reply::json(&msg),
+                Code::TOO_MANY_REQUESTS,
+            ));
+        }
         let (msg, code) = match &r.cause().map(|cause| cause.to_string()).as_deref() {
             Some(PgPool::BAD_TOKEN) => (PgPool::BAD_TOKEN, Code::UNAUTHORIZED),
             Some(PgPool::PG_NULL) => (PgPool::PG_NULL, Code::BAD_REQUEST),
             Some(PgPool::MISSING_HASHTAG) => (PgPool::MISSING_HASHTAG, Code::BAD_REQUEST),
+            Some(Cors::FORBIDDEN_ORIGIN) => (Cors::FORBIDDEN_ORIGIN, Code::FORBIDDEN),
             Some(PgPool::SERVER_ERR) | Some(_) => (PgPool::SERVER_ERR, Code::INTERNAL_SERVER_ERROR),
             None if r.is_not_found() => return Err(r),
 
@@ -142,27 +310,80 @@ impl Handler {
         if code == Code::INTERNAL_SERVER_ERROR {
             log::error!("Internal error: {:?}", &r);
         } else {
-            log::info!("Request rejected: {} - {:?}", code, &r);
+            let event = match code {
+                Code::UNAUTHORIZED => "auth_failure",
+                _ => "rejected",
+            };
+            let fields = serde_json::json!({ "status": code.as_u16(), "reason": msg });
+            logging::record(log::Level::Info, event, fields);
         };
         Ok(reply::with_status(reply::json(&msg), code))
     }
 }
 
+/// Admit a client from its `ip` with `admit_ip`, and then (unless it's refused) query
+/// Postgres for the subscription `q` asks for, which comes with the client's `Permit`
+fn admit_then_query<A>(
+    pg_conn: &PgPool,
+    admit_ip: &A,
+    q: Query,
+    ip: Option<IpAddr>,
+) -> impl Future<Item = (Subscription, Permit), Error = Rejection>
+where
+    A: Fn(Option<IpAddr>) -> std::result::Result<Permit, Refusal>,
+{
+    match admit_ip(ip) {
+        Ok(permit) => Either::A(
+            pg_conn
+                .spawn_blocking(move |pool| Subscription::query_postgres(q, pool))
+                .map(move |subscription| (subscription, permit)),
+        ),
+        Err(refusal) => Either::B(future::err(warp::reject::custom(refusal))),
+    }
+}
+
 fn parse_ws_query() -> BoxedFilter<(Query,)> {
     use query::*;
     path!("api" / "v1" / "streaming")
         .and(path::end())
-        .and(warp::query())
+        .and(Stream::to_filter())
         .and(Auth::to_filter())
         .and(Media::to_filter())
         .and(Hashtag::to_filter())
         .and(List::to_filter())
-        .map(|s: Stream, a: Auth, m: Media, h: Hashtag, l: List| Query {
-            access_token: a.access_token,
-            stream: s.stream,
-            media: m.is_truthy(),
-            hashtag: h.tag,
-            list: l.list,
-        })
+        .and(Since::to_filter())
+        .map(
+            |s: Stream, a: Auth, m: Media, h: Hashtag, l: List, since: Since| Query {
+                access_token: a.access_token,
+                stream: s.stream,
+                media: m.is_truthy(),
+                hashtag: h.tag,
+                list: l.list,
+                since: since.since,
+            },
+        )
         .boxed()
 }
+
+/// The client's IP address: the address the connection came from (if it came over TCP),
+/// unless that's one of the `trusted_proxies` or the connection came over the Unix socket
+/// (which only a proxy can reach).  Then it's the last address in `X-Forwarded-For` that
+/// isn't a trusted proxy's, since a client can put whatever it likes before those.
+fn client_ip(
+    forwarded: Option<&str>,
+    remote: Option<SocketAddr>,
+    trusted_proxies: &[IpAddr],
+) -> Option<IpAddr> {
+    let mut client = remote.map(|addr| addr.ip());
+    if client.map_or(false, |ip| !trusted_proxies.contains(&ip)) {
+        return client;
+    }
+    for addr in forwarded.into_iter().flat_map(|addrs| addrs.rsplit(',')) {
+        match addr.trim().parse() {
+            Ok(ip) if trusted_proxies.contains(&ip) => client = Some(ip),
+            Ok(ip) => return Some(ip),
+            Err(_) => break,
+        }
+    }
+    client
+}
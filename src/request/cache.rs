@@ -0,0 +1,67 @@
+//! A bounded cache whose entries expire, for data we'd otherwise re-query from Postgres
+use lru::LruCache;
+use std::hash::Hash;
+use std::time::{Duration, Instant};
+
+pub(crate) struct Cache<K: Hash + Eq, V> {
+    entries: LruCache<K, (Instant, V)>,
+    ttl: Duration,
+    hits: u64,
+    misses: u64,
+}
+
+impl<K: Hash + Eq + Clone, V: Clone> Cache<K, V> {
+    pub(crate) fn new(capacity: usize, ttl: Duration) -> Self {
+        Self {
+            entries: LruCache::new(capacity),
+            ttl,
+            hits: 0,
+            misses: 0,
+        }
+    }
+
+    /// The value for `key`, unless it's missing or has expired
+    pub(crate) fn get(&mut self, key: &K) -> Option<V> {
+        let value = match self.entries.get(key) {
+            Some((added, value)) if added.elapsed() < self.ttl => Some(value.clone()),
+            Some(_expired) => {
+                self.entries.pop(key);
+                None
+            }
+            None => None,
+        };
+        match value {
+            Some(_) => self.hits += 1,
+            None => self.misses += 1,
+        }
+        value
+    }
+
+    pub(crate) fn put(&mut self, key: K, value: V) {
+        self.entries.put(key, (Instant::now(), value));
+    }
+
+    /// Remove every entry whose value matches `predicate`
+    pub(crate) fn remove_where(&mut self, predicate: impl Fn(&V) -> bool) {
+        let keys: Vec<K> = self
+            .entries
+            .iter()
+            .filter(|(_, (_, value))| predicate(value))
+            .map(|(key, _)| key.clone())
+            .collect();
+        for key in keys {
+            self.entries.pop(&key);
+        }
+    }
+
+    pub(crate) fn hits(&self) -> u64 {
+        self.hits
+    }
+
+    pub(crate) fn misses(&self) -> u64 {
+        self.misses
+    }
+}
+
+#[cfg(test)]
+mod test;
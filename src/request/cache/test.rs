@@ -0,0 +1,40 @@
+use super::*;
+use std::thread;
+
+#[test]
+fn cache_counts_hits_and_misses() {
+    let mut cache = Cache::new(10, Duration::from_secs(60));
+    assert_eq!(cache.get(&"token"), None);
+    cache.put("token", 1);
+    assert_eq!(cache.get(&"token"), Some(1));
+    assert_eq!(cache.get(&"token"), Some(1));
+    assert_eq!((cache.hits(), cache.misses()), (2, 1));
+}
+
+#[test]
+fn cache_entries_expire() {
+    let mut cache = Cache::new(10, Duration::from_millis(20));
+    cache.put("token", 1);
+    thread::sleep(Duration::from_millis(30));
+    assert_eq!(cache.get(&"token"), None);
+}
+
+#[test]
+fn cache_is_bounded() {
+    let mut cache = Cache::new(2, Duration::from_secs(60));
+    for (i, token) in ["a", "b", "c"].iter().enumerate() {
+        cache.put(*token, i);
+    }
+    assert_eq!(cache.get(&"a"), None);
+    assert_eq!(cache.get(&"c"), Some(2));
+}
+
+#[test]
+fn cache_removes_matching_entries() {
+    let mut cache = Cache::new(10, Duration::from_secs(60));
+    cache.put("a", (1, "alice"));
+    cache.put("b", (2, "bob"));
+    cache.remove_where(|(token_id, _)| *token_id == 2);
+    assert_eq!(cache.get(&"a"), Some((1, "alice")));
+    assert_eq!(cache.get(&"b"), None);
+}
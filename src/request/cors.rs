@@ -0,0 +1,130 @@
+//! Cross-Origin Resource Sharing (CORS) for the streaming API, with settings that can be
+//! replaced while running (unlike `warp::cors`, which is fixed when the server starts)
+use crate::config;
+
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
+use warp::filters::BoxedFilter;
+use warp::http::header::{self, HeaderValue};
+use warp::http::{Response, StatusCode};
+use warp::{path, Filter, Reply};
+
+#[derive(Clone)]
+pub(super) struct Cors(Arc<RwLock<config::Cors>>);
+
+impl Cors {
+    pub(crate) const FORBIDDEN_ORIGIN: &'static str = "Error: Origin not allowed";
+
+    pub(super) fn new(cfg: config::Cors) -> Self {
+        Self(Arc::new(RwLock::new(cfg)))
+    }
+
+    pub(super) fn update(&self, cfg: config::Cors) {
+        *self.0.write().unwrap_or_else(PoisonError::into_inner) = cfg;
+    }
+
+    fn settings(&self) -> RwLockReadGuard<config::Cors> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The request's `Origin` (if it has one), or a rejection if that origin isn't allowed.
+    /// (Browsers don't apply CORS to WebSockets, so refusing the request is what keeps other
+    /// sites out.)
+    pub(super) fn origin(&self) -> BoxedFilter<(Option<String>,)> {
+        let cors = self.clone();
+        warp::header::optional::<String>("origin")
+            .and_then(move |origin: Option<String>| match origin {
+                Some(origin) if !cors.settings().allows_origin(&origin) => {
+                    log::info!("Forbidden request from {}", origin);
+                    Err(warp::reject::custom(Self::FORBIDDEN_ORIGIN))
+                }
+                origin => Ok(origin),
+            })
+            .boxed()
+    }
+
+    /// Answers preflight requests (`OPTIONS` requests with an `Origin` and an
+    /// `Access-Control-Request-Method`) to the streaming API
+    pub(super) fn preflight(&self) -> BoxedFilter<(Response<&'static str>,)> {
+        let cors = self.clone();
+        path!("api" / "v1" / "streaming")
+            .and(warp::options())
+            .and(warp::header::<String>("origin"))
+            .and(warp::header::<String>("access-control-request-method"))
+            .and(warp::header::optional::<String>(
+                "access-control-request-headers",
+            ))
+            .map(
+                move |origin: String, method: String, headers: Option<String>| {
+                    cors.preflight_response(
+                        &origin,
+                        &method,
+                        headers.as_deref().unwrap_or_default(),
+                    )
+                },
+            )
+            .boxed()
+    }
+
+    fn preflight_response(
+        &self,
+        origin: &str,
+        method: &str,
+        headers: &str,
+    ) -> Response<&'static str> {
+        let cfg = self.settings();
+        let allows = |allowed: &[String], requested: &str| {
+            allowed.iter().any(|a| a.eq_ignore_ascii_case(requested))
+        };
+        let headers_allowed = headers
+            .split(',')
+            .map(str::trim)
+            .filter(|h| !h.is_empty())
+            .all(|h| allows(&cfg.allowed_headers, h));
+
+        let response =
+            if cfg.allows_origin(origin) && allows(&cfg.allowed_methods, method) && headers_allowed
+            {
+                Response::builder()
+                    .header(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin)
+                    .header(
+                        header::ACCESS_CONTROL_ALLOW_METHODS,
+                        cfg.allowed_methods.join(", "),
+                    )
+                    .header(
+                        header::ACCESS_CONTROL_ALLOW_HEADERS,
+                        cfg.allowed_headers.join(", "),
+                    )
+                    .header(header::VARY, "origin")
+                    .body("")
+            } else {
+                log::info!(
+                    "Forbidden CORS preflight for {} {} from {}",
+                    method,
+                    headers,
+                    origin
+                );
+                Response::builder()
+                    .status(StatusCode::FORBIDDEN)
+                    .body("CORS request forbidden")
+            };
+        response.unwrap_or_else(|e| {
+            log::error!("Could not build a CORS preflight response: {}", e);
+            let mut response = Response::new("");
+            *response.status_mut() = StatusCode::INTERNAL_SERVER_ERROR;
+            response
+        })
+    }
+
+    /// Adds `Access-Control-Allow-Origin` to the reply to a cross-origin request (whose
+    /// origin `origin` has already checked)
+    pub(super) fn allow_origin<T: Reply>(reply: T, origin: Option<String>) -> impl Reply {
+        let mut response = reply.into_response();
+        let headers = response.headers_mut();
+        // Whether or not this is a cross-origin request, the reply depends on the origin
+        headers.insert(header::VARY, HeaderValue::from_static("origin"));
+        if let Some(origin) = origin.and_then(|origin| HeaderValue::from_str(&origin).ok()) {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin);
+        }
+        response
+    }
+}
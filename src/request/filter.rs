@@ -0,0 +1,126 @@
+//! Keyword filters (Mastodon's `custom_filters`) applied to statuses before sending them
+use super::timeline::{Content, Stream, Timeline};
+
+use hashbrown::HashSet;
+use std::convert::TryFrom;
+
+/// The keyword filters a user has set up
+#[derive(Clone, Default, Debug, PartialEq)]
+pub struct Filters(pub(crate) Vec<Filter>);
+
+/// A single keyword filter
+#[derive(Clone, Debug, PartialEq)]
+pub struct Filter {
+    phrase: String,
+    contexts: HashSet<FilterContext>,
+    whole_word: bool,
+}
+
+/// Where in the UI a `Filter` applies
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum FilterContext {
+    Home,
+    Notifications,
+    Public,
+    Thread,
+}
+
+impl Filters {
+    /// Whether a status with the given spoiler text and (HTML) content should be hidden from
+    /// a subscriber to `timeline`
+    pub(crate) fn hide(&self, timeline: Timeline, spoiler_text: &str, content: &str) -> bool {
+        if self.0.is_empty() {
+            return false;
+        }
+        let context = FilterContext::from(timeline);
+        let text = [spoiler_text, "\n\n", &plain_text(content)]
+            .concat()
+            .to_lowercase();
+
+        self.0
+            .iter()
+            .filter(|filter| filter.contexts.contains(&context))
+            .any(|filter| filter.matches(&text))
+    }
+}
+
+impl Filter {
+    pub(crate) fn new(phrase: &str, contexts: HashSet<FilterContext>, whole_word: bool) -> Self {
+        Self {
+            phrase: phrase.to_lowercase(),
+            contexts,
+            whole_word,
+        }
+    }
+
+    /// Test whether (lowercase, plain) `text` contains the filter's phrase.
+    ///
+    /// Whole-word filters follow Mastodon's rules: the phrase must start (or end) at a word
+    /// boundary only if it starts (or ends) with a word character.
+    fn matches(&self, text: &str) -> bool {
+        let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+        let needs_start_boundary = self.phrase.starts_with(is_word_char);
+        let needs_end_boundary = self.phrase.ends_with(is_word_char);
+
+        text.match_indices(&self.phrase).any(|(i, phrase)| {
+            let before = text[..i].chars().next_back();
+            let after = text[i + phrase.len()..].chars().next();
+            !self.whole_word
+                || (!(needs_start_boundary && before.map_or(false, is_word_char))
+                    && !(needs_end_boundary && after.map_or(false, is_word_char)))
+        })
+    }
+}
+
+impl From<Timeline> for FilterContext {
+    fn from(timeline: Timeline) -> Self {
+        use {Content::*, Stream::*};
+        match timeline {
+            Timeline(User(_), _, Notification) => Self::Notifications,
+            Timeline(User(_), _, _) | Timeline(List(_), _, _) => Self::Home,
+            Timeline(Direct(_), _, _) => Self::Thread,
+            Timeline(Public, _, _) | Timeline(Hashtag(_), _, _) | Timeline(Unset, _, _) => {
+                Self::Public
+            }
+        }
+    }
+}
+
+impl TryFrom<&str> for FilterContext {
+    type Error = ();
+
+    fn try_from(s: &str) -> Result<Self, ()> {
+        match s {
+            "home" => Ok(Self::Home),
+            "notifications" => Ok(Self::Notifications),
+            "public" => Ok(Self::Public),
+            "thread" => Ok(Self::Thread),
+            _unsupported => Err(()),
+        }
+    }
+}
+
+/// Strip the HTML tags from a status's content and unescape the entities Mastodon uses
+fn plain_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' if in_tag => {
+                in_tag = false;
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => (),
+        }
+    }
+    text.replace("&lt;", "<")
+        .replace("&gt;", ">")
+        .replace("&quot;", "\"")
+        .replace("&#39;", "'")
+        .replace("&amp;", "&")
+}
+
+#[cfg(test)]
+mod test;
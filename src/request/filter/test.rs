@@ -0,0 +1,47 @@
+use super::super::timeline::Reach;
+use super::*;
+use crate::Id;
+
+fn filters(phrase: &str, whole_word: bool) -> Filters {
+    let contexts = vec![FilterContext::Home, FilterContext::Public]
+        .into_iter()
+        .collect();
+    Filters(vec![Filter::new(phrase, contexts, whole_word)])
+}
+
+const PUBLIC: Timeline = Timeline(Stream::Public, Reach::Federated, Content::All);
+
+#[test]
+fn filter_matches_content_case_insensitively() {
+    let content = "<p>I love <span>Rust</span>!</p>";
+    assert!(filters("rust", false).hide(PUBLIC, "", content));
+    assert!(filters("RUST", true).hide(PUBLIC, "", content));
+    assert!(!filters("python", false).hide(PUBLIC, "", content));
+}
+
+#[test]
+fn filter_matches_spoiler_text() {
+    assert!(filters("spoilers", true).hide(PUBLIC, "Movie spoilers", "<p>It was him</p>"));
+}
+
+#[test]
+fn whole_word_filter_respects_word_boundaries() {
+    let content = "<p>Trusty crustaceans</p>";
+    assert!(filters("rust", false).hide(PUBLIC, "", content));
+    assert!(!filters("rust", true).hide(PUBLIC, "", content));
+    assert!(filters("#rust", true).hide(PUBLIC, "", "<p>learning#rust</p>"));
+}
+
+#[test]
+fn filter_only_applies_in_its_contexts() {
+    let direct = Timeline(Stream::Direct(1), Reach::Federated, Content::All);
+    let notifications = Timeline(Stream::User(Id(1)), Reach::Federated, Content::Notification);
+    assert!(!filters("rust", false).hide(direct, "", "<p>rust</p>"));
+    assert!(!filters("rust", false).hide(notifications, "", "<p>rust</p>"));
+}
+
+#[test]
+fn html_is_reduced_to_plain_text() {
+    assert!(filters("fish & chips", true).hide(PUBLIC, "", "<p>fish &amp; chips</p>"));
+    assert!(!filters("foobar", false).hide(PUBLIC, "", "<p>foo</p><p>bar</p>"));
+}
@@ -0,0 +1,63 @@
+//! Per-user data shared by all of that user's open connections, so that it can be refreshed
+//! once for the user rather than once per connection
+use crate::Id;
+
+use hashbrown::HashMap;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, RwLock, Weak};
+
+/// The shared data of every user with an open connection; an entry lives only as long as
+/// some connection holds its `Arc`
+pub(crate) struct Live<T>(Arc<Mutex<HashMap<Id, Weak<RwLock<T>>>>>);
+
+impl<T> Live<T> {
+    /// The data shared by the user's open connections, if they have any
+    pub(crate) fn get(&self, user_id: Id) -> Option<Arc<RwLock<T>>> {
+        self.lock().get(&user_id).and_then(Weak::upgrade)
+    }
+
+    /// Share `value` (loaded for a new connection) with the user's other connections,
+    /// unless one of them shared its own while `value` was being loaded; then that's used
+    /// instead, so that the user's connections are always refreshed together
+    pub(crate) fn share(&self, user_id: Id, value: T) -> Arc<RwLock<T>> {
+        let mut live = self.lock();
+        if let Some(shared) = live.get(&user_id).and_then(Weak::upgrade) {
+            return shared;
+        }
+        let shared = Arc::new(RwLock::new(value));
+        live.insert(user_id, Arc::downgrade(&shared));
+        shared
+    }
+
+    /// The data of every user who still has an open connection (forgetting the rest)
+    pub(crate) fn users(&self) -> Vec<(Id, Arc<RwLock<T>>)> {
+        let mut users = Vec::new();
+        self.lock()
+            .retain(|user_id, shared| match shared.upgrade() {
+                Some(shared) => {
+                    users.push((*user_id, shared));
+                    true
+                }
+                None => false, // all of the user's connections have closed
+            });
+        users
+    }
+
+    fn lock(&self) -> MutexGuard<HashMap<Id, Weak<RwLock<T>>>> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl<T> Clone for Live<T> {
+    fn clone(&self) -> Self {
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Default for Live<T> {
+    fn default() -> Self {
+        Self(Arc::new(Mutex::new(HashMap::new())))
+    }
+}
+
+#[cfg(test)]
+mod test;
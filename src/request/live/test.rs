@@ -0,0 +1,27 @@
+use super::*;
+
+#[test]
+fn live_data_is_shared_by_two_connections_opened_at_once() {
+    let live = Live::default();
+    // Both connections found nothing shared, so each loaded its own data
+    let (first, second) = (live.share(Id(1), "first"), live.share(Id(1), "second"));
+
+    assert!(Arc::ptr_eq(&first, &second));
+    assert_eq!(*second.read().expect("test"), "first");
+    let users = live.users();
+    assert_eq!(users.len(), 1);
+    assert!(Arc::ptr_eq(&users[0].1, &first));
+}
+
+#[test]
+fn live_data_is_forgotten_once_every_connection_closes() {
+    let live = Live::default();
+    let first = live.share(Id(1), 1);
+    let second = live.get(Id(1)).expect("test");
+    drop(first);
+    assert!(live.get(Id(1)).is_some());
+
+    drop(second);
+    assert!(live.get(Id(1)).is_none());
+    assert!(live.users().is_empty());
+}
@@ -1,20 +1,45 @@
 //! Postgres queries
+use super::cache::Cache;
 use super::err;
+use super::filter::{Filter, FilterContext, Filters};
+use super::live::Live;
+use super::subscription::{Blocks, LiveBlocks, LiveFilters};
 use super::timeline::{Scope, UserData};
-use crate::config;
+use crate::config::{self, PgSslMode};
+use crate::metrics::METRICS;
 use crate::Id;
 
-use ::postgres::{self, SimpleQueryMessage};
+use ::postgres::config::SslMode;
+use ::postgres::types::FromSql;
+use ::postgres::{self, Row};
+use futures_cpupool::{Builder as CpuPoolBuilder, CpuFuture, CpuPool};
 use hashbrown::HashSet;
+use openssl::ssl::{SslConnector, SslFiletype, SslMethod, SslVerifyMode};
+use postgres_openssl::MakeTlsConnector;
 use r2d2_postgres::PostgresConnectionManager;
 use std::convert::TryFrom;
+use std::sync::atomic::{AtomicBool, AtomicU64, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::thread;
+use std::time::Duration;
 #[allow(deprecated)] // one fn is deprecated, not whole module
 use warp::reject;
 
 #[derive(Clone)]
 pub struct PgPool {
-    conn: r2d2::Pool<PostgresConnectionManager<postgres::NoTls>>,
-    whitelist_mode: bool,
+    conn: r2d2::Pool<PostgresConnectionManager<MakeTlsConnector>>,
+    /// Shared so that it can be changed (for every clone) when the configuration is reloaded
+    whitelist_mode: Arc<AtomicBool>,
+    live_blocks: Live<Blocks>,
+    live_filters: Live<Filters>,
+    /// Threads for the (blocking) queries made while setting up a subscription
+    workers: CpuPool,
+    /// Users by access token
+    user_cache: Arc<Mutex<Cache<String, UserData>>>,
+    /// How many access tokens have been revoked (changed with the `user_cache` locked), so
+    /// that a user looked up while a token was being revoked isn't cached
+    revocations: Arc<AtomicU64>,
+    blocks_cache: Arc<Mutex<Cache<Id, Blocks>>>,
 }
 
 type Result<T> = std::result::Result<T, err::Error>;
@@ -25,6 +50,7 @@ impl PgPool {
     pub(crate) const SERVER_ERR: &'static str = "Error: Internal server error";
     pub(crate) const PG_NULL: &'static str = "Error: Unexpected null from Postgres";
     pub(crate) const MISSING_HASHTAG: &'static str = "Error: Hashtag does not exist";
+    const MAX_CONNECTIONS: u32 = 10;
 
     pub(crate) fn new(pg_cfg: &config::Postgres, whitelist_mode: bool) -> Result<Self> {
         let mut cfg = postgres::Config::new();
@@ -33,163 +59,386 @@ impl PgPool {
             .port(*pg_cfg.port)
             .dbname(&pg_cfg.database);
         if let Some(password) = &*pg_cfg.password {
-            cfg.password(password);
+            cfg.password(password.expose());
         };
+        if let Some(application_name) = &*pg_cfg.application_name {
+            cfg.application_name(application_name);
+        };
+        if let Some(connect_timeout) = *pg_cfg.connect_timeout {
+            cfg.connect_timeout(connect_timeout);
+        };
+        cfg.ssl_mode(match *pg_cfg.ssl_mode {
+            PgSslMode::Disable | PgSslMode::Allow => SslMode::Disable,
+            PgSslMode::Prefer => SslMode::Prefer,
+            PgSslMode::Require | PgSslMode::VerifyCa | PgSslMode::VerifyFull => SslMode::Require,
+        });
+        let tls = Self::tls_connector(pg_cfg)?;
 
-        cfg.connect(postgres::NoTls)?; // Test connection, letting us immediately exit with an error
-                                       // when Postgres isn't running instead of timing out below
-        let manager = PostgresConnectionManager::new(cfg, postgres::NoTls);
+        // Test connection, letting us immediately exit with an error when Postgres isn't running
+        // instead of timing out below.  With `allow`, we only use TLS if the server requires it.
+        if let Err(e) = cfg.connect(tls.clone()) {
+            match *pg_cfg.ssl_mode {
+                PgSslMode::Allow => cfg.ssl_mode(SslMode::Require).connect(tls.clone())?,
+                _ => Err(e)?,
+            };
+        }
+        let manager = PostgresConnectionManager::new(cfg, tls);
+        let conn = r2d2::Pool::builder()
+            .max_size(Self::MAX_CONNECTIONS)
+            .build(manager)?;
+        let (cache_size, cache_ttl) = (*pg_cfg.cache_size, *pg_cfg.cache_ttl);
+        Ok(Self::with_conn(conn, whitelist_mode, cache_size, cache_ttl))
+    }
 
-        Ok(Self {
-            conn: r2d2::Pool::builder().max_size(10).build(manager)?,
-            whitelist_mode,
-        })
+    /// A pool for tests that need one but never query it (it only tries to connect, to a port
+    /// nothing listens on, when it's queried)
+    #[cfg(test)]
+    pub(crate) fn unconnected() -> Self {
+        let mut cfg = postgres::Config::new();
+        cfg.host("localhost").port(1).user("test");
+        let tls = SslConnector::builder(SslMethod::tls())
+            .expect("test")
+            .build();
+        let manager = PostgresConnectionManager::new(cfg, MakeTlsConnector::new(tls));
+        let conn = r2d2::Pool::builder()
+            .min_idle(Some(0))
+            .connection_timeout(Duration::from_millis(100))
+            .build_unchecked(manager);
+        Self::with_conn(conn, false, 10, Duration::from_secs(60))
     }
 
-    fn is_safe(txt: &str) -> bool {
-        txt.chars()
-            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+    fn with_conn(
+        conn: r2d2::Pool<PostgresConnectionManager<MakeTlsConnector>>,
+        whitelist_mode: bool,
+        cache_size: usize,
+        cache_ttl: Duration,
+    ) -> Self {
+        Self {
+            conn,
+            whitelist_mode: Arc::new(AtomicBool::new(whitelist_mode)),
+            live_blocks: Live::default(),
+            live_filters: Live::default(),
+            workers: CpuPoolBuilder::new()
+                .pool_size(Self::MAX_CONNECTIONS as usize)
+                .name_prefix("postgres-")
+                .create(),
+            user_cache: Arc::new(Mutex::new(Cache::new(cache_size, cache_ttl))),
+            revocations: Arc::new(AtomicU64::new(0)),
+            blocks_cache: Arc::new(Mutex::new(Cache::new(cache_size, cache_ttl))),
+        }
     }
 
-    pub(crate) fn select_user(self, token: &Option<String>) -> Rejectable<UserData> {
-        let mut conn = self.conn.get().map_err(reject::custom)?;
+    /// Run `f` (which may block on Postgres) on the pool's own threads, so that it can't hold
+    /// up the runtime threads that deliver events
+    pub(crate) fn spawn_blocking<F, T>(&self, f: F) -> CpuFuture<T, warp::Rejection>
+    where
+        F: FnOnce(Self) -> Rejectable<T> + Send + 'static,
+        T: Send + 'static,
+    {
+        let pool = self.clone();
+        self.workers.spawn_fn(move || f(pool))
+    }
 
-        if let Some(token) = token {
-            if !Self::is_safe(token) {
-                Err(reject::custom(Self::BAD_TOKEN))?;
-            };
+    /// Build the TLS connector for the `DB_SSLMODE`, following libpq's rules for when to verify
+    /// the server's certificate and hostname
+    fn tls_connector(pg_cfg: &config::Postgres) -> Result<MakeTlsConnector> {
+        let mut builder = SslConnector::builder(SslMethod::tls())?;
+        if let Some(ca_file) = &*pg_cfg.ssl_root_cert {
+            builder.set_ca_file(ca_file)?;
+        }
+        if let Some(cert_file) = &*pg_cfg.ssl_cert {
+            builder.set_certificate_chain_file(cert_file)?;
+        }
+        if let Some(key_file) = &*pg_cfg.ssl_key {
+            builder.set_private_key_file(key_file, SslFiletype::PEM)?;
+        }
 
-            let rows = conn
-                .simple_query(&format!("
-SELECT oauth_access_tokens.resource_owner_id, users.account_id, users.chosen_languages, oauth_access_tokens.scopes
-  FROM oauth_access_tokens
-INNER JOIN users ON oauth_access_tokens.resource_owner_id = users.id
-  WHERE oauth_access_tokens.token='{}' AND oauth_access_tokens.revoked_at IS NULL
-LIMIT 1", &token.to_owned())
-                ).map_err(reject::custom)?;
+        if !pg_cfg.verify_cert() {
+            builder.set_verify(SslVerifyMode::NONE);
+        }
 
-            let row = match rows.get(0) {
-                Some(postgres::SimpleQueryMessage::Row(row)) => row,
-                _ => Err(reject::custom(Self::PG_NULL))?, // Wildcard required by #[non_exhaustive]
-            };
+        let mut connector = MakeTlsConnector::new(builder.build());
+        if !pg_cfg.verify_hostname() {
+            connector.set_callback(|connect_cfg, _domain| {
+                connect_cfg.set_verify_hostname(false);
+                Ok(())
+            });
+        }
+        Ok(connector)
+    }
 
-            let id = Id(get_col_or_reject(row, 1)?.parse().map_err(reject::custom)?);
-
-            let allowed_langs: HashSet<_> = row
-                .try_get(2)
-                .map_err(reject::custom)? // looks like `Some("{en,eo,es}")`
-                .map_or_else(HashSet::new, |str| {
-                    str.trim_start_matches('{')
-                        .trim_end_matches('}')
-                        .split(',')
-                        .map(String::from)
-                        .collect()
-                });
-
-            let mut scopes: HashSet<Scope> = get_col_or_reject(row, 3)?
-                .split(' ')
-                .filter_map(|scope| Scope::try_from(scope).ok())
-                .collect();
-            // We don't need to separately track read auth - it's just all three others
-            if scopes.contains(&Scope::Read) {
-                scopes = vec![Scope::Statuses, Scope::Notifications, Scope::Lists]
-                    .into_iter()
-                    .collect()
+    pub(crate) fn select_user(self, token: &Option<String>) -> Rejectable<UserData> {
+        if let Some(token) = token {
+            if let Some(user) = self.lock_user_cache().get(token) {
+                return Ok(user);
             }
-
-            Ok(UserData {
-                id,
-                allowed_langs,
-                scopes,
-            })
-        } else if self.whitelist_mode {
+            let revocations = self.revocations.load(Ordering::Acquire);
+            let user = self.select_user_by_token(token)?;
+            let mut user_cache = self.lock_user_cache();
+            if self.revocations.load(Ordering::Acquire) != revocations {
+                // The token may have been revoked after we queried it, so check it again (and
+                // don't cache the answer, in case another revocation is under way)
+                drop(user_cache);
+                return self.select_user_by_token(token);
+            }
+            user_cache.put(token.clone(), user.clone());
+            Ok(user)
+        } else if self.whitelist_mode.load(Ordering::Acquire) {
+            METRICS.auth_rejections.inc(&["whitelist_mode"]);
             Err(reject::custom(Self::BAD_TOKEN))
         } else {
             Ok(UserData::public())
         }
     }
 
+    /// Query Postgres for the user with an (unrevoked) access token
+    fn select_user_by_token(&self, token: &str) -> Rejectable<UserData> {
+        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let rows = conn
+                .query("
+SELECT oauth_access_tokens.resource_owner_id, users.account_id, users.chosen_languages, oauth_access_tokens.scopes, oauth_access_tokens.id
+  FROM oauth_access_tokens
+INNER JOIN users ON oauth_access_tokens.resource_owner_id = users.id
+  WHERE oauth_access_tokens.token = $1 AND oauth_access_tokens.revoked_at IS NULL
+LIMIT 1",
+                       &[&token],
+                ).map_err(reject::custom)?;
+        let row = rows.get(0).ok_or_else(|| {
+            METRICS.auth_rejections.inc(&["invalid_token"]);
+            reject::custom(Self::PG_NULL)
+        })?;
+
+        let id = Id(get_col(row, 1)?);
+        let allowed_langs: HashSet<String> = row
+            .try_get::<_, Option<Vec<String>>>(2)
+            .map_err(reject::custom)?
+            .unwrap_or_default()
+            .into_iter()
+            .collect();
+
+        let mut scopes: HashSet<Scope> = get_col::<&str>(row, 3)?
+            .split(' ')
+            .filter_map(|scope| Scope::try_from(scope).ok())
+            .collect();
+        // We don't need to separately track read auth - it's just all three others
+        if scopes.contains(&Scope::Read) {
+            scopes = vec![Scope::Statuses, Scope::Notifications, Scope::Lists]
+                .into_iter()
+                .collect()
+        }
+
+        Ok(UserData {
+            id,
+            allowed_langs,
+            scopes,
+            token_id: Some(get_col(row, 4)?),
+        })
+    }
+
+    pub(crate) fn set_whitelist_mode(&self, whitelist_mode: bool) {
+        self.whitelist_mode.store(whitelist_mode, Ordering::Release);
+    }
+
+    /// Forget a revoked access token, so that it has to be checked against Postgres again
+    pub(crate) fn forget_access_token(&self, token_id: i64) {
+        let mut user_cache = self.lock_user_cache();
+        self.revocations.fetch_add(1, Ordering::AcqRel);
+        user_cache.remove_where(|user| user.token_id == Some(token_id));
+    }
+
     pub(crate) fn select_hashtag_id(self, tag_name: &str) -> Rejectable<i64> {
-        if !Self::is_safe(tag_name) {
-            Err(reject::custom(Self::MISSING_HASHTAG))?;
-        };
+        let mut conn = self.conn.get().map_err(reject::custom)?;
+        let rows = conn
+            .query("SELECT id FROM tags WHERE name = $1 LIMIT 1", &[&tag_name])
+            .map_err(reject::custom)?;
+        match rows.get(0) {
+            Some(row) => get_col(row, 0),
+            None => Err(reject::custom(Self::MISSING_HASHTAG)),
+        }
+    }
 
+    pub(crate) fn select_hashtag_name(self, tag_id: i64) -> Rejectable<String> {
         let mut conn = self.conn.get().map_err(reject::custom)?;
         let rows = conn
-            .simple_query(&format!(
-                "SELECT id FROM tags WHERE name='{}' LIMIT 1",
-                &tag_name
-            ))
+            .query("SELECT name FROM tags WHERE id = $1 LIMIT 1", &[&tag_id])
             .map_err(reject::custom)?;
-        match rows.get(0).ok_or_else(|| reject::custom(Self::PG_NULL))? {
-            SimpleQueryMessage::Row(row) => get_col_or_reject(row, 0),
-            _ => Err(reject::custom(Self::MISSING_HASHTAG))?,
+        match rows.get(0) {
+            Some(row) => get_col(row, 0),
+            None => Err(reject::custom(Self::MISSING_HASHTAG)),
         }
-        .map(|s| s.parse().map_err(reject::custom))?
     }
 
-    /// Query Postgres for everyone the user has blocked or muted
+    /// Update the metrics for how many of the pool's connections are in use, and for how
+    /// often the caches have been hit
+    pub(crate) fn update_metrics(&self) {
+        let state = self.conn.state();
+        let in_use = state.connections - state.idle_connections;
+        let pool = &METRICS.pg_pool_connections;
+        pool.set(&["idle"], i64::from(state.idle_connections));
+        pool.set(&["in_use"], i64::from(in_use));
+        METRICS
+            .pg_pool_max_connections
+            .set(&[], i64::from(self.conn.max_size()));
+
+        let requests = &METRICS.cache_requests;
+        let (users, blocks) = (self.lock_user_cache(), self.lock_blocks_cache());
+        requests.set(&["access_token", "hit"], users.hits() as i64);
+        requests.set(&["access_token", "miss"], users.misses() as i64);
+        requests.set(&["blocks", "hit"], blocks.hits() as i64);
+        requests.set(&["blocks", "miss"], blocks.misses() as i64);
+    }
+
+    /// The `LiveBlocks` for a user, shared with any connections the user already has open
+    pub(crate) fn live_blocks(self, user_id: Id) -> Rejectable<LiveBlocks> {
+        if user_id == UserData::public().id {
+            return Ok(LiveBlocks::default()); // logged-out users can't block anyone
+        }
+        if let Some(blocks) = self.live_blocks.get(user_id) {
+            return Ok(LiveBlocks(blocks));
+        }
+
+        let cached_blocks = self.lock_blocks_cache().get(&user_id);
+        let blocks = match cached_blocks {
+            Some(blocks) => blocks,
+            None => self.clone().select_blocks(user_id)?,
+        };
+        // (Another of the user's connections may have shared its blocks during the query)
+        Ok(LiveBlocks(self.live_blocks.share(user_id, blocks)))
+    }
+
+    /// The `LiveFilters` for a user, shared with any connections the user already has open
+    pub(crate) fn live_filters(self, user_id: Id) -> Rejectable<LiveFilters> {
+        if user_id == UserData::public().id {
+            return Ok(LiveFilters::default());
+        }
+        if let Some(filters) = self.live_filters.get(user_id) {
+            return Ok(LiveFilters(filters));
+        }
+        let filters = self.clone().select_filters(user_id)?;
+        Ok(LiveFilters(self.live_filters.share(user_id, filters)))
+    }
+
+    /// Re-query the filters of a user with open connections (a no-op for anyone else)
+    pub(crate) fn reload_filters(self, user_id: Id) -> Rejectable<()> {
+        if let Some(filters) = self.live_filters.get(user_id) {
+            let new_filters = self.select_filters(user_id).map_err(|e| {
+                log::error!("Could not reload filters for user {}: {:?}", user_id, e);
+                e
+            })?;
+            *filters.write().unwrap_or_else(PoisonError::into_inner) = new_filters;
+        }
+        Ok(())
+    }
+
+    /// Re-query the blocks and filters for every user every `interval`, for as long as the
+    /// user has any connection open.
     ///
-    /// **NOTE**: because we check this when the user connects, it will not include any blocks
-    /// the user adds until they refresh/reconnect.
-    pub(crate) fn select_blocked_users(self, user_id: Id) -> Rejectable<HashSet<Id>> {
-        let mut conn = self.conn.get().map_err(reject::custom)?;
-        conn.simple_query(&format!(
-            "SELECT target_account_id FROM blocks WHERE account_id = {0}
-                 UNION SELECT target_account_id FROM mutes WHERE account_id = {0}",
-            &*user_id
-        ))
-        .map_err(reject::custom)?
-        .iter()
-        .try_fold(HashSet::new(), |mut set, row| match row {
-            SimpleQueryMessage::Row(row) => {
-                set.insert(get_col_or_reject(row, 0)?.parse().map_err(reject::custom)?);
-                Ok(set)
+    /// This runs on its own thread so that the queries never delay sending events.
+    pub(crate) fn refresh_blocks_every(&self, interval: Duration) {
+        let pool = self.clone();
+        thread::spawn(move || loop {
+            thread::sleep(interval);
+            pool.refresh_blocks();
+            pool.refresh_filters();
+        });
+    }
+
+    fn refresh_blocks(&self) {
+        for (user_id, blocks) in self.live_blocks.users() {
+            match self.clone().select_blocks(user_id) {
+                Ok(new_blocks) => {
+                    *blocks.write().unwrap_or_else(PoisonError::into_inner) = new_blocks
+                }
+                Err(e) => log::error!("Could not refresh blocks for user {}: {:?}", user_id, e),
             }
-            _ => Ok(set),
-        })
+        }
     }
 
-    /// Query Postgres for everyone who has blocked the user
-    ///
-    /// **NOTE**: because we check this when the user connects, it will not include any blocks
-    /// the user adds until they refresh/reconnect.
-    pub(crate) fn select_blocking_users(self, user_id: Id) -> Rejectable<HashSet<Id>> {
-        let mut conn = self.conn.get().map_err(reject::custom)?;
-        conn.simple_query(&format!(
-            "SELECT account_id FROM blocks WHERE target_account_id = {}",
-            &*user_id
-        ))
-        .map_err(reject::custom)?
-        .iter()
-        .try_fold(HashSet::new(), |mut set, row| match row {
-            SimpleQueryMessage::Row(row) => {
-                set.insert(get_col_or_reject(row, 0)?.parse().map_err(reject::custom)?);
-                Ok(set)
+    fn refresh_filters(&self) {
+        for (user_id, filters) in self.live_filters.users() {
+            match self.clone().select_filters(user_id) {
+                Ok(new_filters) => {
+                    *filters.write().unwrap_or_else(PoisonError::into_inner) = new_filters
+                }
+                Err(e) => log::error!("Could not refresh filters for user {}: {:?}", user_id, e),
             }
-            _ => Ok(set),
-        })
+        }
     }
 
-    /// Query Postgres for all current domain blocks
-    ///
-    /// **NOTE**: because we check this when the user connects, it will not include any blocks
-    /// the user adds until they refresh/reconnect.
-    pub(crate) fn select_blocked_domains(self, user_id: Id) -> Rejectable<HashSet<String>> {
+    fn lock_user_cache(&self) -> MutexGuard<Cache<String, UserData>> {
+        self.user_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    fn lock_blocks_cache(&self) -> MutexGuard<Cache<Id, Blocks>> {
+        self.blocks_cache
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// The hit and miss counts of the access token and blocks caches
+    pub(crate) fn cache_status(&self) -> String {
+        let (users, blocks) = (self.lock_user_cache(), self.lock_blocks_cache());
+        format!(
+            "Access token cache: {} hits, {} misses\nBlocks cache: {} hits, {} misses",
+            users.hits(),
+            users.misses(),
+            blocks.hits(),
+            blocks.misses()
+        )
+    }
+
+    /// Query Postgres (in one round trip) for everyone the user has blocked or muted, everyone
+    /// who has blocked the user, and all the domains the user has blocked
+    fn select_blocks(self, user_id: Id) -> Rejectable<Blocks> {
         let mut conn = self.conn.get().map_err(reject::custom)?;
-        conn.simple_query(&format!(
-            "SELECT domain FROM account_domain_blocks WHERE account_id = {}",
-            &*user_id,
-        ))
+        let rows = conn
+            .query(
+                "SELECT
+  ARRAY(SELECT target_account_id FROM blocks WHERE account_id = $1
+          UNION SELECT target_account_id FROM mutes WHERE account_id = $1),
+  ARRAY(SELECT account_id FROM blocks WHERE target_account_id = $1),
+  ARRAY(SELECT domain FROM account_domain_blocks WHERE account_id = $1)",
+                &[&*user_id],
+            )
+            .map_err(reject::custom)?;
+        let row = rows.get(0).ok_or_else(|| reject::custom(Self::PG_NULL))?;
+
+        let ids = |col| -> Rejectable<HashSet<Id>> {
+            Ok(get_col::<Vec<i64>>(row, col)?.into_iter().map(Id).collect())
+        };
+        let blocks = Blocks {
+            blocked_users: ids(0)?,
+            blocking_users: ids(1)?,
+            blocked_domains: get_col::<Vec<String>>(row, 2)?.into_iter().collect(),
+        };
+        self.lock_blocks_cache().put(user_id, blocks.clone());
+        Ok(blocks)
+    }
+
+    /// Query Postgres for the user's unexpired keyword filters
+    fn select_filters(self, user_id: Id) -> Rejectable<Filters> {
+        if user_id == UserData::public().id {
+            return Ok(Filters::default());
+        }
+        let mut conn = self.conn.get().map_err(reject::custom)?;
+        conn.query(
+            "SELECT phrase, context, whole_word FROM custom_filters
+                 WHERE account_id = $1 AND (expires_at IS NULL OR expires_at > NOW())",
+            &[&*user_id],
+        )
         .map_err(reject::custom)?
         .iter()
-        .try_fold(HashSet::new(), |mut set, row| match row {
-            SimpleQueryMessage::Row(row) => {
-                set.insert(get_col_or_reject(row, 0)?.to_string());
-                Ok(set)
-            }
-            _ => Ok(set),
+        .map(|row| -> Rejectable<Filter> {
+            let contexts = get_col::<Vec<&str>>(row, 1)?
+                .into_iter()
+                .filter_map(|context| FilterContext::try_from(context).ok())
+                .collect();
+            Ok(Filter::new(get_col(row, 0)?, contexts, get_col(row, 2)?))
         })
+        .collect::<Rejectable<Vec<_>>>()
+        .map(Filters)
     }
 
     /// Test whether a user owns a list
@@ -197,24 +446,22 @@ LIMIT 1", &token.to_owned())
         // For the Postgres query, `id` = list number; `account_id` = user.id
         let mut conn = self.conn.get().map_err(reject::custom)?;
         let rows = conn
-            .simple_query(&format!(
-                "SELECT id, account_id FROM lists WHERE id={} LIMIT 1",
-                &list_id,
-            ))
+            .query(
+                "SELECT id, account_id FROM lists WHERE id = $1 LIMIT 1",
+                &[&list_id],
+            )
             .map_err(reject::custom)?;
 
-        match rows.get(0).ok_or_else(|| reject::custom(Self::PG_NULL))? {
-            SimpleQueryMessage::Row(row) => {
-                Ok(Id(get_col_or_reject(row, 1)?.parse().map_err(reject::custom)?) == user_id)
-            }
-            _ => Err(reject::custom(Self::MISSING_HASHTAG))?,
+        match rows.get(0) {
+            Some(row) => Ok(Id(get_col(row, 1)?) == user_id),
+            None => Err(reject::custom(Self::MISSING_HASHTAG)),
         }
     }
 }
 
-fn get_col_or_reject(row: &postgres::row::SimpleQueryRow, col: usize) -> Rejectable<&str> {
-    Ok(row
-        .try_get(col)
+/// Read a (non-null) column of a typed row
+fn get_col<'a, T: FromSql<'a>>(row: &'a Row, col: usize) -> Rejectable<T> {
+    row.try_get::<_, Option<T>>(col)
         .map_err(reject::custom)?
-        .ok_or_else(|| reject::custom(PgPool::PG_NULL))?)
+        .ok_or_else(|| reject::custom(PgPool::PG_NULL))
 }
@@ -1,15 +1,21 @@
 //! Validate query prarams with type checking
+use super::Timeline;
+
 use serde_derive::Deserialize;
+use serde_json::Value;
 use warp::filters::BoxedFilter;
 use warp::Filter as WarpFilter;
 
 #[derive(Debug)]
 pub(crate) struct Query {
     pub(crate) access_token: Option<String>,
-    pub(crate) stream: String,
+    /// The timeline asked for; a WebSocket connection may instead subscribe with messages
+    pub(crate) stream: Option<String>,
     pub(crate) media: bool,
     pub(crate) hashtag: String,
     pub(crate) list: i64,
+    /// The ID of the last event the client received, if it's resuming
+    pub(crate) since: Option<u64>,
 }
 
 impl Query {
@@ -25,15 +31,82 @@ impl Query {
             None => Ok(self),
         }
     }
+
+    pub(crate) fn update_since(self, since: Option<u64>) -> Result<Self, warp::reject::Rejection> {
+        match since {
+            Some(since) => Ok(Self {
+                since: Some(since),
+                ..self
+            }),
+            None => Ok(self),
+        }
+    }
 }
 
-macro_rules! make_query_type {
-    (Stream => $parameter:tt:$type:ty) => {
-        #[derive(Deserialize, Debug, Default)]
-        pub(crate) struct Stream {
-            pub(crate) $parameter: $type,
+/// A message sent by a client over an open WebSocket to change its subscriptions
+#[derive(Deserialize, Debug)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum WsMsg {
+    Subscribe(WsStreamMsg),
+    Unsubscribe(WsStreamMsg),
+}
+
+/// The timeline a `WsMsg` refers to, e.g. `{"stream":"hashtag","tag":"rust"}`
+#[derive(Deserialize, Debug)]
+pub struct WsStreamMsg {
+    stream: String,
+    #[serde(default)]
+    tag: String,
+    #[serde(default)]
+    list: Value, // clients send list IDs as either strings or numbers
+    #[serde(default)]
+    since: Value, // as with lists, event IDs may be strings or numbers
+}
+
+impl WsStreamMsg {
+    /// The `stream` array that events for this timeline are sent with
+    pub(crate) fn stream_field(&self) -> Vec<String> {
+        match self.stream.as_str() {
+            "hashtag" | "hashtag:local" => vec![self.stream.clone(), self.tag.clone()],
+            "list" => vec![self.stream.clone(), self.list_id().to_string()],
+            _ => vec![self.stream.clone()],
         }
-    };
+    }
+
+    /// Whether this names one of the streams a client can subscribe to
+    pub(crate) fn is_known_stream(&self) -> bool {
+        Timeline::STREAMS.contains(&self.stream.as_str())
+    }
+
+    pub(crate) fn into_query(self, access_token: Option<String>) -> Query {
+        Query {
+            access_token,
+            list: self.list_id(),
+            since: self.since(),
+            stream: Some(self.stream),
+            media: false,
+            hashtag: self.tag,
+        }
+    }
+
+    fn since(&self) -> Option<u64> {
+        match &self.since {
+            Value::String(id) => id.parse().ok(),
+            Value::Number(id) => id.as_u64(),
+            _ => None,
+        }
+    }
+
+    fn list_id(&self) -> i64 {
+        match &self.list {
+            Value::String(id) => id.parse().unwrap_or_default(),
+            Value::Number(id) => id.as_i64().unwrap_or_default(),
+            _ => 0,
+        }
+    }
+}
+
+macro_rules! make_query_type {
     ($name:tt => $parameter:tt:$type:ty) => {
         #[derive(Deserialize, Debug, Default)]
         pub(crate) struct $name {
@@ -58,7 +131,8 @@ impl Media {
 make_query_type!(Hashtag => tag: String);
 make_query_type!(List => list: i64);
 make_query_type!(Auth => access_token: Option<String>);
-make_query_type!(Stream => stream: String);
+make_query_type!(Since => since: Option<u64>);
+make_query_type!(Stream => stream: Option<String>);
 impl ToString for Stream {
     fn to_string(&self) -> String {
         format!("{:?}", self)
@@ -86,3 +160,15 @@ impl OptionalAccessToken {
         from_header.or(no_token).unify().boxed()
     }
 }
+
+pub(super) struct LastEventId;
+
+impl LastEventId {
+    /// The `Last-Event-ID` header an SSE client sends when it reconnects
+    pub(super) fn from_sse_header() -> warp::filters::BoxedFilter<(Option<u64>,)> {
+        let from_header = warp::header::header::<u64>("last-event-id").map(Some);
+        let no_id = warp::any().map(|| None);
+
+        from_header.or(no_id).unify().boxed()
+    }
+}
@@ -5,12 +5,15 @@
 // use mock_postgres as postgres;
 // #[cfg(not(test))]
 
+use super::filter::Filters;
 use super::postgres::PgPool;
 use super::query::Query;
 use super::{Content, Reach, Stream, Timeline};
+use crate::metrics::METRICS;
 use crate::Id;
 
 use hashbrown::HashSet;
+use std::sync::{Arc, PoisonError, RwLock, RwLockReadGuard};
 
 use warp::reject::Rejection;
 
@@ -18,10 +21,19 @@ use warp::reject::Rejection;
 pub struct Subscription {
     pub timeline: Timeline,
     pub allowed_langs: HashSet<String>,
-    /// [Blocks](./request/struct.Blocks.html)
-    pub blocks: Blocks,
+    /// [LiveBlocks](./request/struct.LiveBlocks.html)
+    pub blocks: LiveBlocks,
+    /// [LiveFilters](./request/struct.LiveFilters.html)
+    pub filters: LiveFilters,
     pub hashtag_name: Option<String>,
     pub access_token: Option<String>,
+    /// The ID of the access token, so that the subscription can be closed if it's revoked
+    pub token_id: Option<i64>,
+    /// The authenticated user, if any (for logging, which must never include the token)
+    pub user_id: Option<Id>,
+    /// The ID of the last event the client received, if it's resuming; the events after it
+    /// that are still available are replayed
+    pub since: Option<u64>,
 }
 
 /// Blocked and muted users and domains
@@ -32,14 +44,64 @@ pub struct Blocks {
     pub blocking_users: HashSet<Id>,
 }
 
+/// A user's [Blocks](./request/struct.Blocks.html), shared by all of that user's connections
+///
+/// While any of the user's connections are open, the `PgPool` periodically re-queries these
+/// blocks, so blocks and mutes the user adds take effect without the client reconnecting.
+#[derive(Clone, Default, Debug)]
+pub struct LiveBlocks(pub(super) Arc<RwLock<Blocks>>);
+
+impl LiveBlocks {
+    pub fn read(&self) -> RwLockReadGuard<Blocks> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl PartialEq for LiveBlocks {
+    fn eq(&self, other: &Self) -> bool {
+        *self.read() == *other.read()
+    }
+}
+
+/// A user's keyword [Filters](./request/struct.Filters.html), shared by all of that user's
+/// connections
+///
+/// They're reloaded once for the user when Mastodon says they've changed, and re-queried along
+/// with the user's blocks, so connections that never see the `filters_changed` event (such as
+/// those to public timelines) also pick up the change.
+#[derive(Clone, Default, Debug)]
+pub struct LiveFilters(pub(super) Arc<RwLock<Filters>>);
+
+impl LiveFilters {
+    pub fn read(&self) -> RwLockReadGuard<Filters> {
+        self.0.read().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl From<Filters> for LiveFilters {
+    fn from(filters: Filters) -> Self {
+        Self(Arc::new(RwLock::new(filters)))
+    }
+}
+
+impl PartialEq for LiveFilters {
+    fn eq(&self, other: &Self) -> bool {
+        *self.read() == *other.read()
+    }
+}
+
 impl Default for Subscription {
     fn default() -> Self {
         Self {
             timeline: Timeline(Stream::Unset, Reach::Local, Content::Notification),
             allowed_langs: HashSet::new(),
-            blocks: Blocks::default(),
+            blocks: LiveBlocks::default(),
+            filters: LiveFilters::default(),
             hashtag_name: None,
             access_token: None,
+            token_id: None,
+            user_id: None,
+            since: None,
         }
     }
 }
@@ -57,6 +119,7 @@ impl Subscription {
                     Timeline(Hashtag(tag), reach, stream)
                 }
                 Timeline(List(list_id), _, _) if !pool.user_owns_list(user.id, list_id)? => {
+                    METRICS.auth_rejections.inc(&["not_list_owner"]);
                     Err(warp::reject::custom("Error: Missing access token"))?
                 }
                 other_tl => other_tl,
@@ -71,13 +134,13 @@ impl Subscription {
         Ok(Subscription {
             timeline,
             allowed_langs: user.allowed_langs,
-            blocks: Blocks {
-                blocking_users: pool.clone().select_blocking_users(user.id)?,
-                blocked_users: pool.clone().select_blocked_users(user.id)?,
-                blocked_domains: pool.select_blocked_domains(user.id)?,
-            },
+            filters: pool.clone().live_filters(user.id)?,
+            blocks: pool.live_blocks(user.id)?,
             hashtag_name,
+            user_id: q.access_token.as_ref().map(|_| user.id),
+            token_id: user.token_id,
             access_token: q.access_token,
+            since: q.since,
         })
     }
 }
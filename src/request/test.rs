@@ -0,0 +1,50 @@
+use super::timeline::UserData;
+use super::*;
+
+fn ip(addr: &str) -> Option<IpAddr> {
+    Some(addr.parse().expect("test"))
+}
+
+#[test]
+fn client_ip_ignores_x_forwarded_for_from_untrusted_clients() {
+    let remote = Some(SocketAddr::new("203.0.113.7".parse().expect("test"), 5000));
+    let trusted = [ip("10.0.0.2").expect("test")];
+    assert_eq!(
+        client_ip(Some("192.0.2.1"), remote, &trusted),
+        ip("203.0.113.7")
+    );
+}
+
+#[test]
+fn client_ip_skips_trusted_proxies_in_x_forwarded_for() {
+    let remote = Some(SocketAddr::new("10.0.0.2".parse().expect("test"), 5000));
+    let trusted = [ip("10.0.0.1").expect("test"), ip("10.0.0.2").expect("test")];
+    let forwarded = Some("198.51.100.9, 192.0.2.1, 10.0.0.1");
+    assert_eq!(client_ip(forwarded, remote, &trusted), ip("192.0.2.1"));
+    assert_eq!(client_ip(None, remote, &trusted), ip("10.0.0.2"));
+}
+
+#[test]
+fn client_ip_trusts_x_forwarded_for_over_the_unix_socket() {
+    assert_eq!(client_ip(Some("192.0.2.1"), None, &[]), ip("192.0.2.1"));
+    assert_eq!(client_ip(None, None, &[]), None);
+}
+
+#[test]
+fn ws_query_without_a_stream_asks_for_no_timeline() -> std::result::Result<(), Rejection> {
+    let q = warp::test::request()
+        .path("/api/v1/streaming?access_token=TOKEN")
+        .filter(&parse_ws_query())?;
+    assert_eq!(q.stream, None);
+    assert_eq!(q.access_token, Some("TOKEN".to_string()));
+    assert_eq!(
+        Timeline::from_query_and_user(&q, &UserData::public())?,
+        Timeline::empty()
+    );
+
+    let q = warp::test::request()
+        .path("/api/v1/streaming?stream=public")
+        .filter(&parse_ws_query())?;
+    assert_eq!(q.stream, Some("public".to_string()));
+    Ok(())
+}
@@ -1,9 +1,10 @@
 pub use self::inner::{Content, Reach, Scope, Stream};
 use super::err::Timeline as Error;
 use super::query::Query;
+use crate::metrics::METRICS;
+use crate::Id;
 pub(crate) use inner::UserData;
 
-use lru::LruCache;
 use warp::reject::Rejection;
 
 mod err;
@@ -35,6 +36,32 @@ impl Timeline {
         }
     }
 
+    pub(crate) fn user(&self) -> Option<Id> {
+        if let Self(Stream::User(id), _, _) = self {
+            Some(*id)
+        } else {
+            None
+        }
+    }
+
+    /// The kind of timeline, without any user, list or hashtag (e.g., `hashtag:local`)
+    pub(crate) fn kind(&self) -> &'static str {
+        use {Content::*, Reach::*, Stream::*};
+        match self {
+            Timeline(Public, Federated, All) => "public",
+            Timeline(Public, Local, All) => "public:local",
+            Timeline(Public, Federated, Media) => "public:media",
+            Timeline(Public, Local, Media) => "public:local:media",
+            Timeline(Hashtag(_), Federated, _) => "hashtag",
+            Timeline(Hashtag(_), Local, _) => "hashtag:local",
+            Timeline(User(_), _, Notification) => "user:notification",
+            Timeline(User(_), _, _) => "user",
+            Timeline(List(_), _, _) => "list",
+            Timeline(Direct(_), _, _) => "direct",
+            Timeline(_, _, _) => "unset",
+        }
+    }
+
     pub(crate) fn to_redis_raw_timeline(&self, hashtag: Option<&String>) -> Result<String> {
         use {Content::*, Error::*, Reach::*, Stream::*};
 
@@ -62,9 +89,42 @@ impl Timeline {
         })
     }
 
-    pub fn from_redis_text(timeline: &str, cache: &mut LruCache<String, i64>) -> Result<Self> {
+    /// The `stream` array Mastodon sends with each event over a multiplexed WebSocket
+    ///
+    /// Lets clients with several subscriptions on one socket tell which of them an event
+    /// belongs to (e.g., `["hashtag", "rust"]` or `["list", "12"]`).
+    pub(crate) fn to_stream_field(&self, hashtag: Option<&String>) -> Result<Vec<String>> {
         use {Content::*, Error::*, Reach::*, Stream::*};
-        let mut tag_id = |t: &str| cache.get(&t.to_string()).map_or(Err(BadTag), |id| Ok(*id));
+        let name = |name: &str| vec![name.to_string()];
+        let with_tag = |name: &str| -> Result<Vec<String>> {
+            Ok(vec![
+                name.to_string(),
+                hashtag.ok_or(MissingHashtag)?.clone(),
+            ])
+        };
+
+        Ok(match self {
+            Timeline(Public, Federated, All) => name("public"),
+            Timeline(Public, Local, All) => name("public:local"),
+            Timeline(Public, Federated, Media) => name("public:media"),
+            Timeline(Public, Local, Media) => name("public:local:media"),
+            Timeline(Hashtag(_id), Federated, All) => with_tag("hashtag")?,
+            Timeline(Hashtag(_id), Local, All) => with_tag("hashtag:local")?,
+            Timeline(User(_id), Federated, All) => name("user"),
+            Timeline(User(_id), Federated, Notification) => name("user:notification"),
+            Timeline(List(id), Federated, All) => vec!["list".to_string(), id.to_string()],
+            Timeline(Direct(_id), Federated, All) => name("direct"),
+            Timeline(_one, _two, _three) => Err(Error::InvalidInput)?,
+        })
+    }
+
+    /// Parse a Redis channel name, using `hashtag_id` to find the ID of a hashtag's name
+    pub fn from_redis_text(
+        timeline: &str,
+        hashtag_id: impl Fn(&str) -> Option<i64>,
+    ) -> Result<Self> {
+        use {Content::*, Error::*, Reach::*, Stream::*};
+        let tag_id = |t: &str| hashtag_id(t).ok_or(BadTag);
 
         Ok(match &timeline.split(':').collect::<Vec<&str>>()[..] {
             ["public"] => Timeline(Public, Federated, All),
@@ -81,13 +141,38 @@ impl Timeline {
         })
     }
 
+    /// The streams a client can ask for (those `from_query_and_user` accepts)
+    pub(crate) const STREAMS: [&'static str; 10] = [
+        "public",
+        "public:local",
+        "public:media",
+        "public:local:media",
+        "hashtag",
+        "hashtag:local",
+        "user",
+        "user:notification",
+        "list",
+        "direct",
+    ];
+    pub(crate) const NONEXISTENT_ENDPOINT: &'static str = "Error: Nonexistent endpoint";
+
+    /// The timeline the query asks for; `Timeline::empty()` if it doesn't name one (a
+    /// WebSocket connection opened without a `stream`, which subscribes with messages)
     pub(crate) fn from_query_and_user(
         q: &Query,
         user: &UserData,
     ) -> std::result::Result<Self, Rejection> {
         use {warp::reject::custom, Content::*, Reach::*, Scope::*, Stream::*};
+        let missing_scope = || {
+            METRICS.auth_rejections.inc(&["missing_scope"]);
+            custom("Error: Missing access token")
+        };
+        let stream = match &q.stream {
+            Some(stream) => stream.as_str(),
+            None => return Ok(Timeline::empty()),
+        };
 
-        Ok(match q.stream.as_ref() {
+        Ok(match stream {
             "public" => match q.media {
                 true => Timeline(Public, Federated, Media),
                 false => Timeline(Public, Federated, All),
@@ -103,23 +188,23 @@ impl Timeline {
             "hashtag:local" => Timeline(Hashtag(0), Local, All),
             "user" => match user.scopes.contains(&Statuses) {
                 true => Timeline(User(user.id), Federated, All),
-                false => Err(custom("Error: Missing access token"))?,
+                false => Err(missing_scope())?,
             },
             "user:notification" => match user.scopes.contains(&Statuses) {
                 true => Timeline(User(user.id), Federated, Notification),
-                false => Err(custom("Error: Missing access token"))?,
+                false => Err(missing_scope())?,
             },
             "list" => match user.scopes.contains(&Lists) {
                 true => Timeline(List(q.list), Federated, All),
-                false => Err(warp::reject::custom("Error: Missing access token"))?,
+                false => Err(missing_scope())?,
             },
             "direct" => match user.scopes.contains(&Statuses) {
                 true => Timeline(Direct(*user.id), Federated, All),
-                false => Err(custom("Error: Missing access token"))?,
+                false => Err(missing_scope())?,
             },
             other => {
                 log::warn!("Request for nonexistent endpoint: `{}`", other);
-                Err(custom("Error: Nonexistent endpoint"))?
+                Err(custom(Self::NONEXISTENT_ENDPOINT))?
             }
         })
     }
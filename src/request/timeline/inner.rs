@@ -58,6 +58,8 @@ pub(crate) struct UserData {
     pub(crate) id: Id,
     pub(crate) allowed_langs: HashSet<String>,
     pub(crate) scopes: HashSet<Scope>,
+    /// The ID of the access token the user authenticated with
+    pub(crate) token_id: Option<i64>,
 }
 
 impl UserData {
@@ -66,6 +68,7 @@ impl UserData {
             id: Id(-1),
             allowed_langs: HashSet::new(),
             scopes: HashSet::new(),
+            token_id: None,
         }
     }
 }
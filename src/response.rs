@@ -1,13 +1,16 @@
 //! Stream the updates appropriate for a given `User`/`timeline` pair from Redis.
 
 pub use event::Event;
+pub use queue::channel as event_queue;
 pub use redis::Manager as RedisManager;
-pub use stream::{Sse as SseStream, Ws as WsStream};
+pub use redis::{Permit, Refusal};
+pub use stream::{open_connections, Sse as SseStream, Ws as WsStream};
 
 pub(self) use event::err::Event as EventErr;
 pub(self) use event::Payload;
 
 pub(crate) mod event;
+mod queue;
 mod redis;
 mod stream;
 
@@ -28,30 +28,49 @@ pub(crate) trait Payload {
     fn involved_users(&self) -> HashSet<Id>;
     fn author(&self) -> &Id;
     fn sent_from(&self) -> &str;
+    fn content(&self) -> &str;
+    fn spoiler_text(&self) -> &str;
 }
 
 impl Event {
-    pub(crate) fn to_json_string(&self) -> String {
+    /// Serialize the `Event` for a WebSocket client, tagged with the `stream` it belongs to
+    /// and its ID (which the client can later resume from with the `since` parameter)
+    pub(crate) fn to_json_string(&self, stream: &[String], id: u64) -> String {
         if let Event::Ping = self {
             "{}".to_string()
         } else {
-            let event = &self.event_name();
+            let (event, id) = (&self.event_name(), id.to_string());
             let sendable_event = match self.payload() {
-                Some(payload) => SendableEvent::WithPayload { event, payload },
-                None => SendableEvent::NoPayload { event },
+                Some(payload) => SendableEvent::WithPayload {
+                    id,
+                    stream,
+                    event,
+                    payload,
+                },
+                None => SendableEvent::NoPayload { id, stream, event },
             };
             serde_json::to_string(&sendable_event).expect("Guaranteed: SendableEvent is Serialize")
         }
     }
 
-    pub(crate) fn to_warp_reply(&self) -> Option<(impl ServerSentEvent, impl ServerSentEvent)> {
+    /// The `Event` for an SSE client, with an ID the client can resume from with the
+    /// `Last-Event-ID` header (along with the reply's approximate length in bytes)
+    pub(crate) fn to_warp_reply(&self, id: u64) -> Option<(impl ServerSentEvent, usize)> {
         if let Event::Ping = self {
             None
         } else {
-            Some((
-                warp::sse::event(self.event_name()),
-                warp::sse::data(self.payload().unwrap_or_else(String::new)),
-            ))
+            let (event, data) = (
+                self.event_name(),
+                self.payload().unwrap_or_else(String::new),
+            );
+            let len =
+                "id:\nevent:\ndata:\n\n".len() + id.to_string().len() + event.len() + data.len();
+            let reply = (
+                warp::sse::id(id),
+                warp::sse::event(event),
+                warp::sse::data(data),
+            );
+            Some((reply, len))
         }
     }
 
@@ -75,6 +94,16 @@ impl Event {
         }
     }
 
+    /// When Mastodon queued the event (in milliseconds since the Unix epoch), if it says
+    pub(crate) fn queued_at(&self) -> Option<i64> {
+        match self {
+            Self::TypeSafe(CheckedEvent::Update { queued_at, .. })
+            | Self::TypeSafe(CheckedEvent::Conversation { queued_at, .. })
+            | Self::Dynamic(DynEvent { queued_at, .. }) => *queued_at,
+            Self::TypeSafe(_) | Self::Ping => None,
+        }
+    }
+
     fn event_name(&self) -> String {
         String::from(match self {
             Self::TypeSafe(checked) => match checked {
@@ -146,8 +175,17 @@ impl TryFrom<&str> for Event {
 #[derive(Serialize, Debug, Clone)]
 #[serde(untagged)]
 enum SendableEvent<'a> {
-    WithPayload { event: &'a str, payload: String },
-    NoPayload { event: &'a str },
+    WithPayload {
+        id: String,
+        stream: &'a [String],
+        event: &'a str,
+        payload: String,
+    },
+    NoPayload {
+        id: String,
+        stream: &'a [String],
+        event: &'a str,
+    },
 }
 
 fn escaped<T: Serialize + std::fmt::Debug>(content: T) -> String {
@@ -99,4 +99,19 @@ impl Payload for Status {
         let sender_username = &self.account.acct;
         sender_username.split('@').nth(1).unwrap_or_default() // default occurs when sent from local instance
     }
+
+    /// The HTML content of the `Status` (or of the status it boosts)
+    fn content(&self) -> &str {
+        match &self.reblog {
+            Some(boosted_status) => &boosted_status.content,
+            None => &self.content,
+        }
+    }
+
+    fn spoiler_text(&self) -> &str {
+        match &self.reblog {
+            Some(boosted_status) => &boosted_status.spoiler_text,
+            None => &self.spoiler_text,
+        }
+    }
 }
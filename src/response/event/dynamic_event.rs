@@ -37,6 +37,8 @@ pub struct DynStatus {
     pub(crate) mentioned_users: HashSet<Id>,
     pub(crate) replied_to_user: Option<Id>,
     pub(crate) boosted_user: Option<Id>,
+    pub(crate) content: String,
+    pub(crate) spoiler_text: String,
 }
 
 type Result<T> = std::result::Result<T, err::Event>;
@@ -53,6 +55,11 @@ impl DynEvent {
 }
 impl DynStatus {
     pub(crate) fn new(payload: &Value) -> Result<Self> {
+        // for boosts, filter on the text of the boosted status
+        let status = match &payload["reblog"] {
+            Value::Null => payload,
+            boosted_status => boosted_status,
+        };
         Ok(Self {
             id: Id::try_from(&payload["account"]["id"])?,
             username: payload["account"]["acct"]
@@ -63,6 +70,11 @@ impl DynStatus {
             mentioned_users: HashSet::new(),
             replied_to_user: Id::try_from(&payload["in_reply_to_account_id"]).ok(),
             boosted_user: Id::try_from(&payload["reblog"]["account"]["id"]).ok(),
+            content: status["content"].as_str().unwrap_or_default().to_string(),
+            spoiler_text: status["spoiler_text"]
+                .as_str()
+                .unwrap_or_default()
+                .to_string(),
         })
     }
 }
@@ -111,4 +123,12 @@ impl Payload for DynStatus {
         let sender_username = &self.username;
         sender_username.split('@').nth(1).unwrap_or_default() // default occurs when sent from local instance
     }
+
+    fn content(&self) -> &str {
+        &self.content
+    }
+
+    fn spoiler_text(&self) -> &str {
+        &self.spoiler_text
+    }
 }
@@ -0,0 +1,192 @@
+//! A bounded queue of events for a single client.  When a client can't keep up, its queue
+//! overflows according to an `OverflowPolicy` instead of holding up every other client.
+use crate::config::OverflowPolicy;
+
+use futures::task::AtomicTask;
+use futures::{Async, Poll, Stream};
+use std::collections::VecDeque;
+use std::fmt;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+
+/// Create a queue that holds up to `capacity` items
+pub fn channel<T>(capacity: usize, policy: OverflowPolicy) -> (Sender<T>, Receiver<T>) {
+    let shared = Arc::new(Shared {
+        items: Mutex::new(VecDeque::with_capacity(capacity)),
+        capacity,
+        policy,
+        senders: AtomicUsize::new(1),
+        closed: AtomicBool::new(false),
+        disconnected: Mutex::new(None),
+        task: AtomicTask::new(),
+    });
+    (Sender(shared.clone()), Receiver(shared))
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum QueueErr {
+    /// The receiver is gone (or was disconnected), so nothing more can be sent; or, when
+    /// returned by the `Receiver`, the server closed the queue because it is shutting down
+    Closed,
+    /// The queue was full, so an item was dropped or the receiver was disconnected
+    Overflowed,
+    /// The access token the client connected with was revoked, so the receiver was
+    /// disconnected
+    Revoked,
+}
+
+struct Shared<T> {
+    items: Mutex<VecDeque<T>>,
+    capacity: usize,
+    policy: OverflowPolicy,
+    senders: AtomicUsize,
+    closed: AtomicBool,
+    /// Why the receiver was disconnected (if it was); taken when reported to the receiver
+    disconnected: Mutex<Option<QueueErr>>,
+    task: AtomicTask,
+}
+
+pub struct Sender<T>(Arc<Shared<T>>);
+
+pub struct Receiver<T>(Arc<Shared<T>>);
+
+impl<T> Shared<T> {
+    fn lock_items(&self) -> MutexGuard<VecDeque<T>> {
+        self.items.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    /// Stop accepting items, and tell the receiver why (once it has taken any queued items)
+    fn disconnect(&self, reason: QueueErr) {
+        *self
+            .disconnected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner) = Some(reason);
+        self.closed.store(true, Ordering::Release);
+        self.task.notify();
+    }
+}
+
+impl<T> Sender<T> {
+    /// Add `item` to the queue without blocking.  If the queue is full, this applies the
+    /// queue's `OverflowPolicy` and returns `QueueErr::Overflowed`.
+    pub fn send(&self, item: T) -> Result<(), QueueErr> {
+        use OverflowPolicy::*;
+        if self.0.closed.load(Ordering::Acquire) {
+            return Err(QueueErr::Closed);
+        }
+        let mut items = self.0.lock_items();
+        let result = if items.len() < self.0.capacity {
+            items.push_back(item);
+            Ok(())
+        } else {
+            match self.0.policy {
+                DropOldest => {
+                    items.pop_front();
+                    items.push_back(item);
+                }
+                DropNewest => (),
+                Disconnect => {
+                    items.clear();
+                    drop(items);
+                    self.0.disconnect(QueueErr::Overflowed);
+                    return Err(QueueErr::Overflowed);
+                }
+            }
+            Err(QueueErr::Overflowed)
+        };
+        drop(items);
+        self.0.task.notify();
+        result
+    }
+
+    /// Whether the queue was closed or disconnected, or its receiver dropped
+    pub fn is_closed(&self) -> bool {
+        self.0.closed.load(Ordering::Acquire)
+    }
+
+    /// Disconnect the receiver because its access token was revoked; unlike `close`, this
+    /// drops any queued items, which the client is no longer allowed to see
+    pub fn revoke(&self) {
+        self.0.lock_items().clear();
+        self.0.disconnect(QueueErr::Revoked);
+    }
+
+    /// Close the queue (for every sender).  The receiver still gets any queued items, then
+    /// `QueueErr::Closed`, and then the stream ends.
+    pub fn close(&self) {
+        if !self.0.closed.load(Ordering::Acquire) {
+            self.0.disconnect(QueueErr::Closed);
+        }
+    }
+}
+
+impl<T> Clone for Sender<T> {
+    fn clone(&self) -> Self {
+        self.0.senders.fetch_add(1, Ordering::AcqRel);
+        Self(self.0.clone())
+    }
+}
+
+impl<T> Drop for Sender<T> {
+    fn drop(&mut self) {
+        if self.0.senders.fetch_sub(1, Ordering::AcqRel) == 1 {
+            self.0.task.notify(); // so that the receiver sees that the queue has ended
+        }
+    }
+}
+
+impl<T> Stream for Receiver<T> {
+    type Item = T;
+    /// Why the queue was disconnected, which is returned once (after which the stream ends)
+    type Error = QueueErr;
+
+    fn poll(&mut self) -> Poll<Option<T>, QueueErr> {
+        self.0.task.register();
+        if let Some(item) = self.0.lock_items().pop_front() {
+            return Ok(Async::Ready(Some(item)));
+        }
+
+        let disconnected = self
+            .0
+            .disconnected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .take();
+        if let Some(reason) = disconnected {
+            Err(reason)
+        } else if self.0.closed.load(Ordering::Acquire)
+            || self.0.senders.load(Ordering::Acquire) == 0
+        {
+            Ok(Async::Ready(None))
+        } else {
+            Ok(Async::NotReady)
+        }
+    }
+}
+
+impl<T> Drop for Receiver<T> {
+    fn drop(&mut self) {
+        self.0.closed.store(true, Ordering::Release);
+        self.0.lock_items().clear();
+    }
+}
+
+impl std::error::Error for QueueErr {}
+
+impl fmt::Display for QueueErr {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            QueueErr::Closed => write!(f, "the client's event queue is closed"),
+            QueueErr::Overflowed => {
+                write!(
+                    f,
+                    "the client fell too far behind, and its event queue overflowed"
+                )
+            }
+            QueueErr::Revoked => write!(f, "the client's access token was revoked"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
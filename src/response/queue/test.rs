@@ -0,0 +1,69 @@
+use super::*;
+
+/// Everything the receiver yields once all senders are gone
+fn drain(rx: Receiver<u32>) -> Vec<Result<u32, QueueErr>> {
+    rx.wait().collect()
+}
+
+#[test]
+fn queue_drops_oldest_items() {
+    let (tx, rx) = channel(2, OverflowPolicy::DropOldest);
+    assert_eq!(tx.send(1), Ok(()));
+    assert_eq!(tx.send(2), Ok(()));
+    assert_eq!(tx.send(3), Err(QueueErr::Overflowed));
+    drop(tx);
+
+    assert_eq!(drain(rx), vec![Ok(2), Ok(3)]);
+}
+
+#[test]
+fn queue_drops_newest_items() {
+    let (tx, rx) = channel(2, OverflowPolicy::DropNewest);
+    assert_eq!(tx.send(1), Ok(()));
+    assert_eq!(tx.send(2), Ok(()));
+    assert_eq!(tx.send(3), Err(QueueErr::Overflowed));
+    drop(tx);
+
+    assert_eq!(drain(rx), vec![Ok(1), Ok(2)]);
+}
+
+#[test]
+fn queue_disconnects_receiver_on_overflow() {
+    let (tx, rx) = channel(2, OverflowPolicy::Disconnect);
+    let other_tx = tx.clone();
+    assert_eq!(tx.send(1), Ok(()));
+    assert_eq!(tx.send(2), Ok(()));
+    assert_eq!(tx.send(3), Err(QueueErr::Overflowed));
+    assert_eq!(other_tx.send(4), Err(QueueErr::Closed));
+
+    // The receiver learns it was disconnected, even though `tx` is still around
+    assert_eq!(drain(rx), vec![Err(QueueErr::Overflowed)]);
+}
+
+#[test]
+fn queue_closes_when_receiver_drops() {
+    let (tx, rx) = channel(2, OverflowPolicy::DropOldest);
+    drop(rx);
+    assert_eq!(tx.send(1), Err(QueueErr::Closed));
+}
+
+#[test]
+fn queue_delivers_queued_items_before_closing() {
+    let (tx, rx) = channel(2, OverflowPolicy::DropOldest);
+    let other_tx = tx.clone();
+    assert_eq!(tx.send(1), Ok(()));
+    tx.close();
+    assert_eq!(other_tx.send(2), Err(QueueErr::Closed));
+
+    assert_eq!(drain(rx), vec![Ok(1), Err(QueueErr::Closed)]);
+}
+
+#[test]
+fn queue_drops_queued_items_when_revoked() {
+    let (tx, rx) = channel(2, OverflowPolicy::DropOldest);
+    assert_eq!(tx.send(1), Ok(()));
+    tx.revoke();
+    assert_eq!(tx.send(2), Err(QueueErr::Closed));
+
+    assert_eq!(drain(rx), vec![Err(QueueErr::Revoked)]);
+}
@@ -1,11 +1,14 @@
 mod connection;
+mod hashtags;
 mod manager;
 mod msg;
 
 pub(self) use super::{Event, EventErr};
-pub(self) use connection::RedisConn;
+pub(self) use connection::{RedisConn, MIN_INPUT_LEN};
+pub(self) use hashtags::Hashtags;
 pub use manager::Error;
 pub use manager::Manager;
+pub use manager::{Permit, Refusal};
 
 #[cfg(feature = "bench")]
 pub use msg::{RedisMsg, RedisParseOutput};
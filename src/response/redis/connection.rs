@@ -1,88 +1,186 @@
 mod err;
+#[cfg_attr(feature = "bench", allow(dead_code))] // only used by the real connection and tests
+mod sentinel;
+#[cfg(not(any(test, feature = "bench")))]
+mod stream;
 pub(super) use connection::*;
 pub use err::RedisConnErr;
 #[cfg(any(test, feature = "bench"))]
 pub(self) use mock_connection as connection;
 
+use std::ops::Range;
+
+/// The initial (and smallest) size of the input buffer
+pub(super) const MIN_INPUT_LEN: usize = 4096 * 4;
+
+/// The part of `input` to read the next block of input into, growing `input` (up to
+/// `max_len`) if there isn't room for a full block after `start`
+fn next_block(input: &mut Vec<u8>, start: usize, max_len: usize) -> Range<usize> {
+    const BLOCK: usize = 4096 * 2;
+    if input.len() < start + BLOCK && input.len() < max_len {
+        let new_len = (input.len() * 2).max(start + BLOCK).min(max_len);
+        input.resize(new_len, 0);
+        log::info!("Resizing input buffer to {} KiB.", input.len() / 1024);
+    }
+    start..(start + BLOCK).min(input.len())
+}
+
 #[cfg(not(any(test, feature = "bench")))]
 mod connection {
     use super::super::Error as ManagerErr;
-    use super::super::RedisCmd;
+    use super::super::{Hashtags, RedisCmd};
     use super::err::RedisConnErr;
+    use super::sentinel::{host_port, Sentinel};
+    use super::stream::RedisStream;
+    use super::{next_block, MIN_INPUT_LEN};
     use crate::config::Redis;
     use crate::request::Timeline;
 
     use futures::{Async, Poll};
-    use lru::LruCache;
+    use openssl::ssl::{SslConnector, SslFiletype, SslMethod};
     use std::io::{self, Read, Write};
-    use std::net::TcpStream;
-    use std::time::Duration;
+    use std::time::{Duration, Instant};
 
     type Result<T> = std::result::Result<T, RedisConnErr>;
 
     #[derive(Debug)]
     pub struct RedisConn {
-        primary: TcpStream,
-        secondary: TcpStream,
+        primary: RedisStream,
+        secondary: RedisStream,
+        addr: String,
+        sentinel: Option<Sentinel>,
+        opts: ConnectOpts,
         pub(in super::super) namespace: Option<String>,
-        // TODO: eventually, it might make sense to have Mastodon publish to timelines with
-        //       the tag number instead of the tag name.  This would save us from dealing
-        //       with a cache here and would be consistent with how lists/users are handled.
-        pub(in super::super) tag_name_cache: LruCache<i64, String>,
         pub(in super::super) input: Vec<u8>,
+        pub(in super::super) max_input_len: usize,
+    }
+
+    /// Everything needed to (re)open a connection, besides the address
+    #[derive(Debug)]
+    struct ConnectOpts {
+        tls: Option<SslConnector>,
+        /// The name to expect on the server's TLS certificate, if not the host connected to
+        /// (which changes with each failover, when using Sentinel)
+        server_name: Option<String>,
+        user: Option<String>,
+        password: Option<String>,
+        db: Option<u32>,
     }
 
     impl RedisConn {
+        /// How long to wait for the reply to a command sent while connecting
+        const REPLY_TIMEOUT: Duration = Duration::from_secs(5);
+
         pub(in super::super) fn new(redis_cfg: &Redis) -> Result<Self> {
-            let addr = [&*redis_cfg.host, ":", &*redis_cfg.port.to_string()].concat();
+            let mut sentinel = if redis_cfg.sentinels.is_empty() {
+                None
+            } else {
+                let master_name = redis_cfg.sentinel_master.to_string();
+                Some(Sentinel::new(redis_cfg.sentinels.to_vec(), master_name))
+            };
+            let addr = match &mut sentinel {
+                Some(sentinel) => sentinel.master_addr()?,
+                None if redis_cfg.host.starts_with('/') => redis_cfg.host.to_string(), // Unix socket
+                None => host_port(&redis_cfg.host, &redis_cfg.port.to_string()),
+            };
+            let tls = if *redis_cfg.tls {
+                Some(Self::tls_connector(redis_cfg)?)
+            } else {
+                None
+            };
+            let opts = ConnectOpts {
+                tls,
+                server_name: redis_cfg.tls_server_name.clone().0,
+                user: redis_cfg.user.clone().0,
+                password: redis_cfg
+                    .password
+                    .as_ref()
+                    .map(|pass| pass.expose().to_string()),
+                db: *redis_cfg.db,
+            };
 
-            let conn = Self::new_connection(&addr, redis_cfg.password.as_ref())?;
-            conn.set_nonblocking(true)
-                .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
+            let (primary, secondary) = Self::connect(&addr, &opts)?;
             Ok(Self {
-                primary: conn,
-                secondary: Self::new_connection(&addr, redis_cfg.password.as_ref())?,
-                tag_name_cache: LruCache::new(1000),
+                primary,
+                secondary,
+                addr,
+                sentinel,
+                opts,
                 namespace: redis_cfg.namespace.clone().0,
-                input: vec![0; 4096 * 4],
+                input: vec![0; MIN_INPUT_LEN],
+                max_input_len: *redis_cfg.input_buffer_max,
             })
         }
 
+        /// Replace both connections with new ones (e.g., after Redis restarted or failed over).
+        /// This does *not* restore any subscriptions.  `NotReady` means Sentinel is still
+        /// being asked for the current master.
+        pub(in super::super) fn reconnect(&mut self) -> Poll<(), RedisConnErr> {
+            if let Some(sentinel) = &mut self.sentinel {
+                match sentinel.poll_master_addr() {
+                    Some(master_addr) => self.addr = master_addr?,
+                    None => return Ok(Async::NotReady),
+                }
+            }
+            let (primary, secondary) = Self::connect(&self.addr, &self.opts)?;
+            self.primary = primary;
+            self.secondary = secondary;
+            Ok(Async::Ready(()))
+        }
+
+        /// Poll the primary connection for new input.  Returns an error if the connection is
+        /// closed or broken, or if Sentinel has announced a new master.
         pub(in super::super) fn poll_redis(&mut self, i: usize) -> Poll<Option<usize>, ManagerErr> {
-            const BLOCK: usize = 4096 * 2;
-            if self.input.len() < i + BLOCK {
-                self.input.resize(self.input.len() * 2, 0);
-                log::info!("Resizing input buffer to {} KiB.", self.input.len() / 1024);
-                // log::info!("Current buffer: {}", String::from_utf8_lossy(&self.input));
+            if let Some(new_master) = self
+                .sentinel
+                .as_mut()
+                .and_then(Sentinel::poll_switch_master)
+            {
+                if new_master != self.addr {
+                    Err(RedisConnErr::MasterSwitched(new_master))?
+                }
             }
 
             use Async::*;
-            match self.primary.read(&mut self.input[i..i + BLOCK]) {
-                Ok(n) if n == 0 => Ok(Ready(None)),
+            let block = next_block(&mut self.input, i, self.max_input_len);
+            if block.is_empty() {
+                return Ok(NotReady); // the `Manager` needs to make room first
+            }
+            match self.primary.read(&mut self.input[block]) {
+                Ok(n) if n == 0 => {
+                    let closed = io::Error::from(io::ErrorKind::UnexpectedEof);
+                    Err(RedisConnErr::with_addr(&self.addr, closed))?
+                }
                 Ok(n) => Ok(Ready(Some(n))),
                 Err(e) if matches!(e.kind(), io::ErrorKind::WouldBlock) => Ok(NotReady),
-                Err(e) => {
-                    Ready(log::error!("{}", e));
-                    Ok(Ready(None))
-                }
+                Err(e) => Err(RedisConnErr::with_addr(&self.addr, e))?,
             }
         }
 
-        pub(crate) fn send_cmd(&mut self, cmd: RedisCmd, timelines: &[Timeline]) -> Result<()> {
-            let namespace = self.namespace.take();
+        pub(in super::super) fn send_cmd(
+            &mut self,
+            cmd: RedisCmd,
+            timelines: &[Timeline],
+            hashtags: &Hashtags,
+        ) -> Result<()> {
+            let namespace = &self.namespace;
             let timelines: Result<Vec<String>> = timelines
                 .iter()
                 .map(|tl| {
-                    let hashtag = tl.tag().and_then(|id| self.tag_name_cache.get(&id));
-                    match &namespace {
-                        Some(ns) => Ok(format!("{}:{}", ns, tl.to_redis_raw_timeline(hashtag)?)),
-                        None => Ok(tl.to_redis_raw_timeline(hashtag)?),
+                    let hashtag = tl.tag().and_then(|id| hashtags.name(id));
+                    match namespace {
+                        Some(ns) => Ok(format!(
+                            "{}:{}",
+                            ns,
+                            tl.to_redis_raw_timeline(hashtag.as_ref())?
+                        )),
+                        None => Ok(tl.to_redis_raw_timeline(hashtag.as_ref())?),
                     }
                 })
                 .collect();
 
             let (primary_cmd, secondary_cmd) = cmd.into_sendable(&timelines?[..]);
-            self.primary.write_all(&primary_cmd)?;
+            self.write_primary(&primary_cmd)?;
 
             // We also need to set a key to tell the Puma server that we've subscribed or
             // unsubscribed to the channel because it stops publishing updates when it thinks
@@ -93,10 +191,81 @@ mod connection {
             Ok(())
         }
 
-        fn new_connection(addr: &str, pass: Option<&String>) -> Result<TcpStream> {
-            let mut conn = TcpStream::connect(&addr)?;
-            if let Some(password) = pass {
-                Self::auth_connection(&mut conn, &addr, password)?;
+        /// Send a PING on the primary connection, to detect a connection that's silently gone
+        /// dead; Redis answers it with a `pong` message
+        pub(in super::super) fn send_ping(&mut self) -> Result<()> {
+            self.write_primary(b"*1\r\n$4\r\nPING\r\n")?;
+            Ok(())
+        }
+
+        /// Subscribe to the channels Mastodon publishes to when it revokes an access token
+        pub(in super::super) fn subscribe_to_revocations(&mut self) -> Result<()> {
+            let pattern = match &self.namespace {
+                Some(ns) => format!("{}:timeline:access_token:*", ns),
+                None => "timeline:access_token:*".to_string(),
+            };
+            let cmd = format!(
+                "*2\r\n$10\r\npsubscribe\r\n${}\r\n{}\r\n",
+                pattern.len(),
+                pattern
+            );
+            self.write_primary(cmd.as_bytes())
+        }
+
+        /// Write all of `cmd` to the (non-blocking) primary connection.  A non-blocking write
+        /// can fail with `WouldBlock` part way through (and TLS must then retry with the same
+        /// data), so the connection blocks, with a timeout, for the length of the write.
+        fn write_primary(&mut self, cmd: &[u8]) -> Result<()> {
+            self.primary.set_nonblocking(false)?;
+            let written = self.primary.write_all(cmd);
+            self.primary.set_nonblocking(true)?;
+            Ok(written?)
+        }
+
+        fn tls_connector(redis_cfg: &Redis) -> Result<SslConnector> {
+            let mut builder = SslConnector::builder(SslMethod::tls())?;
+            if let Some(ca_file) = &*redis_cfg.tls_ca_file {
+                builder.set_ca_file(ca_file)?;
+            }
+            if let Some(cert_file) = &*redis_cfg.tls_cert_file {
+                builder.set_certificate_chain_file(cert_file)?;
+            }
+            if let Some(key_file) = &*redis_cfg.tls_key_file {
+                builder.set_private_key_file(key_file, SslFiletype::PEM)?;
+            }
+            Ok(builder.build())
+        }
+
+        /// Open the primary (non-blocking) and secondary connections
+        fn connect(addr: &str, opts: &ConnectOpts) -> Result<(RedisStream, RedisStream)> {
+            let host = addr.rsplitn(2, ':').last().unwrap_or(addr);
+            let host = host.trim_start_matches('[').trim_end_matches(']'); // an IPv6 address
+            let server_name = opts.server_name.as_deref().unwrap_or(host);
+            let new_connection = || {
+                let stream = RedisStream::connect(addr, opts.tls.as_ref(), server_name)?;
+                Self::new_connection(stream, addr, opts)
+            };
+
+            let primary = new_connection()?;
+            primary
+                .set_nonblocking(true)
+                .and_then(|()| primary.set_write_timeout(Some(Self::REPLY_TIMEOUT)))
+                .map_err(|e| RedisConnErr::with_addr(addr, e))?;
+            let mut secondary = new_connection()?;
+            // Only the secondary connection needs a database; PubSub ignores them
+            if let Some(db) = opts.db {
+                Self::select_db(&mut secondary, addr, db)?;
+            }
+            Ok((primary, secondary))
+        }
+
+        fn new_connection(
+            mut conn: RedisStream,
+            addr: &str,
+            opts: &ConnectOpts,
+        ) -> Result<RedisStream> {
+            if let Some(password) = &opts.password {
+                Self::auth_connection(&mut conn, &addr, opts.user.as_ref(), password)?;
             }
 
             Self::validate_connection(&mut conn, &addr)?;
@@ -106,52 +275,91 @@ mod connection {
             Ok(conn)
         }
 
-        fn auth_connection(conn: &mut TcpStream, addr: &str, pass: &str) -> Result<()> {
-            conn.write_all(
-                &[
-                    b"*2\r\n$4\r\nauth\r\n$",
-                    pass.len().to_string().as_bytes(),
-                    b"\r\n",
-                    pass.as_bytes(),
-                    b"\r\n",
-                ]
-                .concat(),
-            )
-            .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let mut buffer = vec![0_u8; 5];
-            conn.read_exact(&mut buffer)
+        fn auth_connection(
+            conn: &mut RedisStream,
+            addr: &str,
+            user: Option<&String>,
+            pass: &str,
+        ) -> Result<()> {
+            let cmd = match user {
+                Some(user) => format!(
+                    "*3\r\n$4\r\nauth\r\n${}\r\n{}\r\n${}\r\n{}\r\n",
+                    user.len(),
+                    user,
+                    pass.len(),
+                    pass
+                ),
+                None => format!("*2\r\n$4\r\nauth\r\n${}\r\n{}\r\n", pass.len(), pass),
+            };
+            conn.write_all(cmd.as_bytes())
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            if String::from_utf8_lossy(&buffer) != "+OK\r\n" {
-                Err(RedisConnErr::IncorrectPassword(pass.to_string()))?
+            let reply = Self::read_reply(conn, addr)?;
+            match &*reply {
+                r if r.starts_with("+OK\r\n") => Ok(()),
+                r if r.starts_with("-WRONGPASS") => Err(RedisConnErr::WrongPass(
+                    user.map_or_else(|| "default".to_string(), String::clone),
+                )),
+                _ => Err(RedisConnErr::IncorrectPassword),
             }
-            Ok(())
         }
 
-        fn validate_connection(conn: &mut TcpStream, addr: &str) -> Result<()> {
-            conn.write_all(b"PING\r\n")
+        fn select_db(conn: &mut RedisStream, addr: &str, db: u32) -> Result<()> {
+            let db = db.to_string();
+            let cmd = format!("*2\r\n$6\r\nSELECT\r\n${}\r\n{}\r\n", db.len(), db);
+            conn.write_all(cmd.as_bytes())
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let mut buffer = vec![0_u8; 100];
-            conn.read(&mut buffer)
+            let reply = Self::read_reply(conn, addr)?;
+            match &*reply {
+                r if r.starts_with("+OK\r\n") => Ok(()),
+                _ => Err(RedisConnErr::InvalidRedisReply(reply.clone())),
+            }
+        }
+
+        /// Read the (one-line) reply to a command sent while connecting, treating `-NOPERM` as
+        /// an error.  Reads until the whole line arrives, since the connection's short read
+        /// timeout can expire first.
+        fn read_reply(conn: &mut RedisStream, addr: &str) -> Result<String> {
+            use io::ErrorKind::{TimedOut, UnexpectedEof, WouldBlock};
+            let deadline = Instant::now() + Self::REPLY_TIMEOUT;
+            let (mut input, mut buffer) = (Vec::new(), [0_u8; 100]);
+            while !input.windows(2).any(|w| w == b"\r\n") {
+                match conn.read(&mut buffer) {
+                    Ok(0) => Err(RedisConnErr::with_addr(&addr, UnexpectedEof.into()))?,
+                    Ok(n) => input.extend_from_slice(&buffer[..n]),
+                    Err(e) if [WouldBlock, TimedOut].contains(&e.kind()) => {
+                        if Instant::now() >= deadline {
+                            Err(RedisConnErr::with_addr(&addr, e))?
+                        }
+                    }
+                    Err(e) => Err(RedisConnErr::with_addr(&addr, e))?,
+                }
+            }
+            let reply = String::from_utf8_lossy(&input).to_string();
+            if reply.starts_with("-NOPERM") {
+                Err(RedisConnErr::NoPerm(reply.trim_end().to_string()))?
+            }
+            Ok(reply)
+        }
+
+        fn validate_connection(conn: &mut RedisStream, addr: &str) -> Result<()> {
+            conn.write_all(b"PING\r\n")
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let reply = String::from_utf8_lossy(&buffer);
+            let reply = Self::read_reply(conn, addr)?;
             match &*reply {
                 r if r.starts_with("+PONG\r\n") => Ok(()),
                 r if r.starts_with("-NOAUTH") => Err(RedisConnErr::MissingPassword),
                 r if r.starts_with("HTTP/1.") => Err(RedisConnErr::NotRedis(addr.to_string())),
-                _ => Err(RedisConnErr::InvalidRedisReply(reply.to_string())),
+                _ => Err(RedisConnErr::InvalidRedisReply(reply.clone())),
             }
         }
 
-        fn set_connection_name(conn: &mut TcpStream, addr: &str) -> Result<()> {
+        fn set_connection_name(conn: &mut RedisStream, addr: &str) -> Result<()> {
             conn.write_all(b"*3\r\n$6\r\nCLIENT\r\n$7\r\nSETNAME\r\n$8\r\nflodgatt\r\n")
                 .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let mut buffer = vec![0_u8; 100];
-            conn.read(&mut buffer)
-                .map_err(|e| RedisConnErr::with_addr(&addr, e))?;
-            let reply = String::from_utf8_lossy(&buffer);
+            let reply = Self::read_reply(conn, addr)?;
             match &*reply {
                 r if r.starts_with("+OK\r\n") => Ok(()),
-                _ => Err(RedisConnErr::InvalidRedisReply(reply.to_string())),
+                _ => Err(RedisConnErr::InvalidRedisReply(reply.clone())),
             }
         }
     }
@@ -159,13 +367,13 @@ mod connection {
 #[cfg(any(test, feature = "bench"))]
 mod mock_connection {
     use super::super::Error as ManagerErr;
-    use super::super::RedisCmd;
+    use super::super::{Hashtags, RedisCmd};
     use super::err::RedisConnErr;
+    use super::{next_block, MIN_INPUT_LEN};
     use crate::config::Redis;
     use crate::request::Timeline;
 
     use futures::{Async, Poll};
-    use lru::LruCache;
     use std::collections::VecDeque;
 
     type Result<T> = std::result::Result<T, RedisConnErr>;
@@ -173,38 +381,47 @@ mod mock_connection {
     #[derive(Debug)]
     pub struct RedisConn {
         pub(in super::super) namespace: Option<String>,
-        pub(in super::super) tag_name_cache: LruCache<i64, String>,
         pub(in super::super) input: Vec<u8>,
+        pub(in super::super) max_input_len: usize,
         pub(in super::super) test_input: VecDeque<u8>,
     }
 
     impl RedisConn {
         pub(in super::super) fn new(redis_cfg: &Redis) -> Result<Self> {
             Ok(Self {
-                tag_name_cache: LruCache::new(1000),
                 namespace: redis_cfg.namespace.clone().0,
-                input: vec![0; 4096 * 4],
+                input: vec![0; MIN_INPUT_LEN],
+                max_input_len: *redis_cfg.input_buffer_max,
                 test_input: VecDeque::new(),
             })
         }
 
         pub fn poll_redis(&mut self, start: usize) -> Poll<Option<usize>, ManagerErr> {
-            const BLOCK: usize = 4096 * 2;
-            if self.input.len() < start + BLOCK {
-                self.input.resize(self.input.len() * 2, 0);
-                log::info!("Resizing input buffer to {} KiB.", self.input.len() / 1024);
-            }
-
-            for i in 0..BLOCK {
-                if let Some(byte) = self.test_input.pop_front() {
-                    self.input[start + i] = byte;
-                } else if i > 0 {
-                    return Ok(Async::Ready(Some(i)));
-                } else {
-                    return Ok(Async::Ready(None));
+            let block = next_block(&mut self.input, start, self.max_input_len);
+            let mut len = 0;
+            for byte in &mut self.input[block] {
+                match self.test_input.pop_front() {
+                    Some(test_byte) => *byte = test_byte,
+                    None => break,
                 }
+                len += 1;
             }
-            Ok(Async::Ready(Some(BLOCK)))
+            match len {
+                0 => Ok(Async::Ready(None)),
+                len => Ok(Async::Ready(Some(len))),
+            }
+        }
+
+        pub(in super::super) fn reconnect(&mut self) -> Poll<(), RedisConnErr> {
+            Ok(Async::Ready(()))
+        }
+
+        pub(in super::super) fn subscribe_to_revocations(&mut self) -> Result<()> {
+            Ok(())
+        }
+
+        pub(in super::super) fn send_ping(&mut self) -> Result<()> {
+            Ok(())
         }
 
         pub fn add(&mut self, input: &[u8]) {
@@ -212,11 +429,19 @@ mod mock_connection {
                 self.test_input.push_back(*byte)
             }
         }
-        pub(crate) fn send_cmd(&mut self, cmd: RedisCmd, timelines: &[Timeline]) -> Result<()> {
+        pub(in super::super) fn send_cmd(
+            &mut self,
+            cmd: RedisCmd,
+            timelines: &[Timeline],
+            hashtags: &Hashtags,
+        ) -> Result<()> {
             // stub - does nothing; silences some unused-code warnings
             let timelines: Result<Vec<String>> = timelines
                 .iter()
-                .map(|tl| Ok(tl.to_redis_raw_timeline(None).expect("test")))
+                .map(|tl| {
+                    let hashtag = tl.tag().and_then(|id| hashtags.name(id));
+                    Ok(tl.to_redis_raw_timeline(hashtag.as_ref()).expect("test"))
+                })
                 .collect();
 
             let _ = cmd.into_sendable(&timelines?);
@@ -6,9 +6,14 @@ pub enum RedisConnErr {
     ConnectionErr { addr: String, inner: std::io::Error },
     InvalidRedisReply(String),
     UnknownRedisErr(std::io::Error),
-    IncorrectPassword(String),
+    IncorrectPassword,
+    WrongPass(String),
+    NoPerm(String),
     MissingPassword,
     NotRedis(String),
+    TlsErr(String),
+    NoSentinelMaster(String),
+    MasterSwitched(String),
     TimelineErr(request::TimelineErr),
 }
 
@@ -38,10 +43,19 @@ impl fmt::Display for RedisConnErr {
             UnknownRedisErr(io_err) => {
                 format!("Unexpected failure communicating with Redis: {}", io_err)
             }
-            IncorrectPassword(attempted_password) => format!(
-                "Incorrect Redis password.  You supplied `{}`.\n \
-                 Please supply correct password with REDIS_PASSWORD environmental variable.",
-                attempted_password
+            IncorrectPassword => "Incorrect Redis password.\n \
+                 Please supply correct password with REDIS_PASSWORD environmental variable."
+                .to_string(),
+            WrongPass(user) => format!(
+                "Redis rejected the password for the user `{}`.\n \
+                 Please supply the correct user and password with the REDIS_USER and \
+                 REDIS_PASSWORD environmental variables.",
+                user
+            ),
+            NoPerm(reply) => format!(
+                "The Redis user does not have permission to run a command flodgatt needs: `{}`.\n \
+                 Please update the ACL for the user set with REDIS_USER.",
+                reply
             ),
             MissingPassword => "Invalid authentication for Redis.  Redis is configured to require \
                                 a password, but you did not provide one. \n\
@@ -52,6 +66,19 @@ impl fmt::Display for RedisConnErr {
                  REDIS_PORT environmental variables and try again.",
                 addr
             ),
+            TlsErr(msg) => format!(
+                "Could not establish a TLS connection to Redis: {}\n\
+                 Please check the REDIS_TLS_* environmental variables.",
+                msg
+            ),
+            NoSentinelMaster(master_name) => format!(
+                "No Redis Sentinel could provide the address of the master `{}`.  Please update \
+                 the REDIS_SENTINELS and/or REDIS_SENTINEL_MASTER environmental variables.",
+                master_name
+            ),
+            MasterSwitched(new_addr) => {
+                format!("Redis Sentinel switched to a new master at {}", new_addr)
+            }
             TimelineErr(inner) => format!("{}", inner),
         };
         write!(f, "{}", msg)
@@ -64,6 +91,12 @@ impl From<request::TimelineErr> for RedisConnErr {
     }
 }
 
+impl From<openssl::error::ErrorStack> for RedisConnErr {
+    fn from(e: openssl::error::ErrorStack) -> RedisConnErr {
+        RedisConnErr::TlsErr(e.to_string())
+    }
+}
+
 impl From<std::io::Error> for RedisConnErr {
     fn from(e: std::io::Error) -> RedisConnErr {
         RedisConnErr::UnknownRedisErr(e)
@@ -0,0 +1,270 @@
+//! Discovers the current Redis master through Redis Sentinel, and watches for failovers
+use super::err::RedisConnErr;
+
+use std::io::{self, Read, Write};
+use std::net::{TcpStream, ToSocketAddrs};
+use std::str;
+use std::sync::mpsc::{self, Receiver, TryRecvError};
+use std::thread;
+use std::time::{Duration, Instant};
+
+type Result<T> = std::result::Result<T, RedisConnErr>;
+
+#[derive(Debug)]
+pub(super) struct Sentinel {
+    addrs: Vec<String>,
+    master_name: String,
+    /// A connection to the last sentinel that answered, subscribed to `+switch-master`
+    conn: Option<TcpStream>,
+    input: Vec<u8>,
+    /// When to next try to resubscribe, after losing the connection
+    resubscribe_at: Instant,
+    /// A lookup of the master (and new subscription) running on its own thread, so that the
+    /// `Manager` isn't blocked while the sentinels answer
+    lookup: Option<Receiver<Result<(String, TcpStream)>>>,
+}
+
+/// The subset of RESP replies we expect from a sentinel
+#[derive(Debug, PartialEq)]
+enum Reply {
+    Array(Vec<String>),
+    Null,
+    Error(String),
+}
+
+impl Sentinel {
+    const TIMEOUT: Duration = Duration::from_secs(1);
+    const RESUBSCRIBE_DELAY: Duration = Duration::from_secs(5);
+
+    pub(super) fn new(addrs: Vec<String>, master_name: String) -> Self {
+        Self {
+            addrs,
+            master_name,
+            conn: None,
+            input: Vec::new(),
+            resubscribe_at: Instant::now(),
+            lookup: None,
+        }
+    }
+
+    /// Ask each sentinel in turn for the address of the current master, then subscribe to
+    /// the first sentinel that knows it for notice of any failover.  This blocks until the
+    /// sentinels answer, so it's only for use before the `Manager` is shared.
+    pub(super) fn master_addr(&mut self) -> Result<String> {
+        let found = Self::find_master(&self.addrs, &self.master_name);
+        self.found_master(found)
+    }
+
+    /// Like `master_addr`, but without blocking: the sentinels are asked on another thread,
+    /// and this returns `None` until they've answered
+    pub(super) fn poll_master_addr(&mut self) -> Option<Result<String>> {
+        if self.lookup.is_none() {
+            let (tx, rx) = mpsc::channel();
+            let (addrs, master_name) = (self.addrs.clone(), self.master_name.clone());
+            thread::spawn(move || tx.send(Self::find_master(&addrs, &master_name)));
+            self.lookup = Some(rx);
+        }
+        let found = match self.lookup.as_ref().map(Receiver::try_recv) {
+            Some(Ok(found)) => found,
+            Some(Err(TryRecvError::Empty)) | None => return None,
+            Some(Err(TryRecvError::Disconnected)) => {
+                Err(RedisConnErr::NoSentinelMaster(self.master_name.clone()))
+            }
+        };
+        self.lookup = None;
+        Some(self.found_master(found))
+    }
+
+    fn found_master(&mut self, found: Result<(String, TcpStream)>) -> Result<String> {
+        self.input.clear();
+        let (master_addr, conn) = found?;
+        self.conn = Some(conn);
+        Ok(master_addr)
+    }
+
+    fn find_master(addrs: &[String], master_name: &str) -> Result<(String, TcpStream)> {
+        for addr in addrs {
+            match Self::query_master_addr(addr, master_name) {
+                Ok((master_addr, conn)) => {
+                    log::info!("Sentinel at {} reports master at {}", addr, master_addr);
+                    return Ok((master_addr, conn));
+                }
+                Err(e) => log::warn!("Could not get the Redis master from a sentinel: {}", e),
+            }
+        }
+        Err(RedisConnErr::NoSentinelMaster(master_name.to_string()))
+    }
+
+    /// Check (without blocking) whether a sentinel has announced a new master; returns the
+    /// new master's address if so.
+    ///
+    /// If the subscription was lost, this instead subscribes again (on another thread), and
+    /// returns the current master's address once it has, since it may have changed meanwhile.
+    pub(super) fn poll_switch_master(&mut self) -> Option<String> {
+        let conn = match self.conn.as_mut() {
+            Some(conn) => conn,
+            None => return self.resubscribe(),
+        };
+        let mut buffer = [0_u8; 1024];
+        match conn.read(&mut buffer) {
+            Ok(0) => self.conn_lost(io::ErrorKind::UnexpectedEof.into()),
+            Ok(n) => self.input.extend_from_slice(&buffer[..n]),
+            Err(e) if e.kind() == io::ErrorKind::WouldBlock => (),
+            Err(e) => self.conn_lost(e),
+        }
+
+        let mut new_master = None;
+        while let Some((reply, len)) = parse_reply(&self.input) {
+            self.input.drain(..len);
+            if let Reply::Array(msg) = reply {
+                new_master = self.switch_master_addr(&msg).or(new_master);
+            }
+        }
+        new_master
+    }
+
+    fn conn_lost(&mut self, e: io::Error) {
+        log::warn!("Lost the connection to Redis Sentinel: {}", e);
+        self.conn = None;
+        self.resubscribe_at = Instant::now();
+    }
+
+    fn resubscribe(&mut self) -> Option<String> {
+        if Instant::now() < self.resubscribe_at {
+            return None;
+        }
+        match self.poll_master_addr()? {
+            Ok(master_addr) => Some(master_addr),
+            Err(e) => {
+                log::error!(
+                    "Could not resubscribe to Redis Sentinel (retrying in {:?}): {}",
+                    Self::RESUBSCRIBE_DELAY,
+                    e
+                );
+                self.resubscribe_at = Instant::now() + Self::RESUBSCRIBE_DELAY;
+                None
+            }
+        }
+    }
+
+    /// The new master's address, if `msg` is a `+switch-master` notification for our master
+    fn switch_master_addr(&self, msg: &[String]) -> Option<String> {
+        match msg {
+            [kind, channel, payload] if kind == "message" && channel == "+switch-master" => {
+                // The payload is `<master name> <old ip> <old port> <new ip> <new port>`
+                match payload.split(' ').collect::<Vec<_>>()[..] {
+                    [name, _, _, ip, port] if name == self.master_name => Some(host_port(ip, port)),
+                    _ => None,
+                }
+            }
+            _ => None,
+        }
+    }
+
+    fn query_master_addr(addr: &str, master_name: &str) -> Result<(String, TcpStream)> {
+        let with_addr = |e| RedisConnErr::with_addr(addr, e);
+        let socket_addr = addr
+            .to_socket_addrs()
+            .map_err(with_addr)?
+            .next()
+            .ok_or_else(|| with_addr(io::ErrorKind::AddrNotAvailable.into()))?;
+        let mut conn =
+            TcpStream::connect_timeout(&socket_addr, Self::TIMEOUT).map_err(with_addr)?;
+        conn.set_read_timeout(Some(Self::TIMEOUT))
+            .map_err(with_addr)?;
+
+        let cmd = resp_cmd(&["SENTINEL", "get-master-addr-by-name", master_name]);
+        conn.write_all(&cmd).map_err(with_addr)?;
+        let master_addr = match Self::read_reply(&mut conn).map_err(with_addr)? {
+            Reply::Array(host_and_port) if host_and_port.len() == 2 => {
+                host_port(&host_and_port[0], &host_and_port[1])
+            }
+            Reply::Null => Err(RedisConnErr::NoSentinelMaster(master_name.to_string()))?,
+            Reply::Error(e) => Err(RedisConnErr::InvalidRedisReply(e))?,
+            Reply::Array(other) => Err(RedisConnErr::InvalidRedisReply(other.join(" ")))?,
+        };
+
+        conn.write_all(&resp_cmd(&["SUBSCRIBE", "+switch-master"]))
+            .map_err(with_addr)?;
+        conn.set_nonblocking(true).map_err(with_addr)?;
+        Ok((master_addr, conn))
+    }
+
+    fn read_reply(conn: &mut TcpStream) -> io::Result<Reply> {
+        let (mut input, mut buffer) = (Vec::new(), [0_u8; 1024]);
+        loop {
+            match conn.read(&mut buffer)? {
+                0 => Err(io::Error::from(io::ErrorKind::UnexpectedEof))?,
+                n => input.extend_from_slice(&buffer[..n]),
+            }
+            if let Some((reply, _len)) = parse_reply(&input) {
+                return Ok(reply);
+            }
+        }
+    }
+}
+
+/// A `host:port` address, with the host in brackets if it's an IPv6 address
+pub(super) fn host_port(host: &str, port: &str) -> String {
+    if host.contains(':') && !host.starts_with('[') {
+        format!("[{}]:{}", host, port)
+    } else {
+        format!("{}:{}", host, port)
+    }
+}
+
+fn resp_cmd(args: &[&str]) -> Vec<u8> {
+    let mut cmd = format!("*{}\r\n", args.len());
+    for arg in args {
+        cmd.push_str(&format!("${}\r\n{}\r\n", arg.len(), arg));
+    }
+    cmd.into_bytes()
+}
+
+/// Parse a single reply from the start of `input`, returning it along with its length in
+/// bytes (or `None` if the reply is incomplete)
+fn parse_reply(input: &[u8]) -> Option<(Reply, usize)> {
+    let (first, mut pos) = line(input, 0)?;
+    let reply = match (first.get(..1)?, &first[1..]) {
+        ("-", msg) => Reply::Error(msg.to_string()),
+        ("+", msg) => Reply::Array(vec![msg.to_string()]),
+        ("*", "-1") => Reply::Null,
+        ("*", len) => {
+            let mut items = Vec::new();
+            for _ in 0..len.parse::<usize>().ok()? {
+                let (header, next) = line(input, pos)?;
+                pos = next;
+                match (header.get(..1)?, &header[1..]) {
+                    ("$", "-1") => items.push(String::new()),
+                    ("$", len) => {
+                        let end = pos + len.parse::<usize>().ok()?;
+                        let item = input.get(pos..end)?;
+                        items.push(String::from_utf8_lossy(item).to_string());
+                        pos = end + "\r\n".len();
+                    }
+                    (_, item) => items.push(item.to_string()), // integers and simple strings
+                }
+            }
+            Reply::Array(items)
+        }
+        (_, _) => Reply::Error(first.to_string()),
+    };
+    if pos > input.len() {
+        None
+    } else {
+        Some((reply, pos))
+    }
+}
+
+/// The line starting at `start` (without its `\r\n`) and the position after it
+fn line(input: &[u8], start: usize) -> Option<(&str, usize)> {
+    let rest = input.get(start..)?;
+    let len = rest.windows(2).position(|w| w == b"\r\n")?;
+    Some((
+        str::from_utf8(&rest[..len]).ok()?,
+        start + len + "\r\n".len(),
+    ))
+}
+
+#[cfg(test)]
+mod test;
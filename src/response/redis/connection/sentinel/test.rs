@@ -0,0 +1,203 @@
+use super::*;
+use std::net::TcpListener;
+use std::thread;
+use std::time::Instant;
+
+/// A stand-in for Redis Sentinel that answers one `get-master-addr-by-name` query with
+/// `reply` and then sends each of `notifications` to the subscribed connection
+fn stand_in(reply: &'static str, notifications: Vec<String>) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("addr").to_string();
+    thread::spawn(move || {
+        let (conn, _) = listener.accept().expect("accept");
+        serve(conn, reply, notifications);
+        thread::sleep(Duration::from_secs(1)); // keep the connection open while the test polls
+    });
+    addr
+}
+
+/// A stand-in whose first connection closes right after answering with `first_reply`, and
+/// whose second answers with `second_reply`
+fn stand_in_that_drops_the_subscription(
+    first_reply: &'static str,
+    second_reply: &'static str,
+) -> String {
+    let listener = TcpListener::bind("127.0.0.1:0").expect("bind");
+    let addr = listener.local_addr().expect("addr").to_string();
+    thread::spawn(move || {
+        let (conn, _) = listener.accept().expect("accept");
+        serve(conn, first_reply, Vec::new()); // and then close it
+        let (conn, _) = listener.accept().expect("accept");
+        serve(conn, second_reply, Vec::new());
+        thread::sleep(Duration::from_secs(1));
+    });
+    addr
+}
+
+fn serve(mut conn: TcpStream, reply: &str, notifications: Vec<String>) {
+    let mut buffer = [0_u8; 1024];
+    let n = conn.read(&mut buffer).expect("read");
+    assert_eq!(
+        &buffer[..n],
+        &b"*3\r\n$8\r\nSENTINEL\r\n$23\r\nget-master-addr-by-name\r\n$8\r\nmymaster\r\n"[..]
+    );
+    conn.write_all(reply.as_bytes()).expect("write");
+
+    let n = conn.read(&mut buffer).expect("read");
+    assert_eq!(
+        &buffer[..n],
+        &b"*2\r\n$9\r\nSUBSCRIBE\r\n$14\r\n+switch-master\r\n"[..]
+    );
+    conn.write_all(b"*3\r\n$9\r\nsubscribe\r\n$14\r\n+switch-master\r\n:1\r\n")
+        .expect("write");
+    for notification in notifications {
+        conn.write_all(notification.as_bytes()).expect("write");
+    }
+}
+
+fn switch_master(payload: &str) -> String {
+    format!(
+        "*3\r\n$7\r\nmessage\r\n$14\r\n+switch-master\r\n${}\r\n{}\r\n",
+        payload.len(),
+        payload
+    )
+}
+
+fn poll_until_switch(sentinel: &mut Sentinel) -> Option<String> {
+    let start = Instant::now();
+    while start.elapsed() < Duration::from_millis(500) {
+        if let Some(addr) = sentinel.poll_switch_master() {
+            return Some(addr);
+        }
+        thread::sleep(Duration::from_millis(10));
+    }
+    None
+}
+
+const MASTER: &str = "*2\r\n$9\r\n127.0.0.1\r\n$4\r\n6380\r\n";
+
+#[test]
+fn sentinel_resolves_master_addr() -> Result<()> {
+    let addr = stand_in(MASTER, Vec::new());
+    let mut sentinel = Sentinel::new(vec![addr], "mymaster".to_string());
+
+    assert_eq!(sentinel.master_addr()?, "127.0.0.1:6380");
+    assert_eq!(poll_until_switch(&mut sentinel), None);
+    Ok(())
+}
+
+#[test]
+fn sentinel_skips_sentinels_that_do_not_know_the_master() -> Result<()> {
+    let unreachable = TcpListener::bind("127.0.0.1:0")?.local_addr()?.to_string(); // closed on drop
+    let unknown = stand_in("*-1\r\n", Vec::new());
+    let known = stand_in(MASTER, Vec::new());
+    let mut sentinel = Sentinel::new(vec![unreachable, unknown, known], "mymaster".to_string());
+
+    assert_eq!(sentinel.master_addr()?, "127.0.0.1:6380");
+    Ok(())
+}
+
+#[test]
+fn sentinel_errors_when_no_sentinel_knows_the_master() {
+    let unknown = stand_in("*-1\r\n", Vec::new());
+    let mut sentinel = Sentinel::new(vec![unknown], "mymaster".to_string());
+
+    assert!(matches!(
+        sentinel.master_addr(),
+        Err(RedisConnErr::NoSentinelMaster(_))
+    ));
+}
+
+#[test]
+fn sentinel_follows_switch_master_for_its_master_only() -> Result<()> {
+    let notifications = vec![
+        switch_master("othermaster 127.0.0.1 6390 127.0.0.1 6391"),
+        switch_master("mymaster 127.0.0.1 6380 127.0.0.1 6381"),
+    ];
+    let addr = stand_in(MASTER, notifications);
+    let mut sentinel = Sentinel::new(vec![addr], "mymaster".to_string());
+
+    assert_eq!(sentinel.master_addr()?, "127.0.0.1:6380");
+    assert_eq!(
+        poll_until_switch(&mut sentinel),
+        Some("127.0.0.1:6381".to_string())
+    );
+    Ok(())
+}
+
+#[test]
+fn sentinel_resubscribes_and_reports_a_failover_it_missed() -> Result<()> {
+    let failed_over = "*2\r\n$9\r\n127.0.0.1\r\n$4\r\n6381\r\n";
+    let addr = stand_in_that_drops_the_subscription(MASTER, failed_over);
+    let mut sentinel = Sentinel::new(vec![addr], "mymaster".to_string());
+
+    assert_eq!(sentinel.master_addr()?, "127.0.0.1:6380");
+    assert_eq!(
+        poll_until_switch(&mut sentinel),
+        Some("127.0.0.1:6381".to_string())
+    );
+    assert!(sentinel.conn.is_some());
+    Ok(())
+}
+
+#[test]
+fn sentinel_looks_up_the_master_without_blocking() -> Result<()> {
+    let listener = TcpListener::bind("127.0.0.1:0")?;
+    let silent = listener.local_addr()?.to_string();
+    thread::spawn(move || {
+        let _conn = listener.accept().expect("accept");
+        thread::sleep(Duration::from_secs(2)); // never answers
+    });
+    let mut sentinel = Sentinel::new(vec![silent], "mymaster".to_string());
+
+    let start = Instant::now();
+    assert!(sentinel.poll_master_addr().is_none());
+    assert!(start.elapsed() < Sentinel::TIMEOUT);
+
+    let known = stand_in(MASTER, Vec::new());
+    let mut sentinel = Sentinel::new(vec![known], "mymaster".to_string());
+    let found = loop {
+        if let Some(found) = sentinel.poll_master_addr() {
+            break found;
+        }
+        assert!(start.elapsed() < Duration::from_millis(500));
+        thread::sleep(Duration::from_millis(10));
+    };
+    assert_eq!(found?, "127.0.0.1:6380");
+    assert!(sentinel.conn.is_some());
+    Ok(())
+}
+
+#[test]
+fn sentinel_brackets_ipv6_master_addrs() {
+    let sentinel = Sentinel::new(Vec::new(), "mymaster".to_string());
+    let msg: Vec<String> = vec![
+        "message".to_string(),
+        "+switch-master".to_string(),
+        "mymaster ::1 6380 2001:db8::2 6381".to_string(),
+    ];
+    assert_eq!(
+        sentinel.switch_master_addr(&msg),
+        Some("[2001:db8::2]:6381".to_string())
+    );
+    assert_eq!(host_port("10.0.0.2", "6379"), "10.0.0.2:6379");
+    assert_eq!(host_port("[::1]", "6379"), "[::1]:6379");
+}
+
+#[test]
+fn parse_reply_waits_for_complete_input() {
+    let msg = switch_master("mymaster 127.0.0.1 6380 127.0.0.1 6381");
+    for i in 0..msg.len() {
+        assert_eq!(parse_reply(&msg.as_bytes()[..i]), None);
+    }
+    let (reply, len) = parse_reply(msg.as_bytes()).expect("complete");
+    assert_eq!(len, msg.len());
+    assert_eq!(
+        reply,
+        Reply::Array(vec![
+            "message".to_string(),
+            "+switch-master".to_string(),
+            "mymaster 127.0.0.1 6380 127.0.0.1 6381".to_string()
+        ])
+    );
+}
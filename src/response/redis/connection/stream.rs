@@ -0,0 +1,93 @@
+//! The socket underlying a Redis connection, which may or may not use TLS
+use super::err::RedisConnErr;
+
+use openssl::ssl::{SslConnector, SslStream};
+use std::io::{self, Read, Write};
+use std::net::TcpStream;
+use std::os::unix::net::UnixStream;
+use std::time::Duration;
+
+#[derive(Debug)]
+pub(super) enum RedisStream {
+    Tcp(TcpStream),
+    Tls(SslStream<TcpStream>),
+    Unix(UnixStream),
+}
+
+impl RedisStream {
+    /// Connect to `addr` (a `host:port` or the path to a Unix socket), negotiating TLS (and
+    /// expecting a certificate for `server_name`) when given a `SslConnector`
+    pub(super) fn connect(
+        addr: &str,
+        tls: Option<&SslConnector>,
+        server_name: &str,
+    ) -> Result<Self, RedisConnErr> {
+        if addr.starts_with('/') {
+            if tls.is_some() {
+                log::warn!("Not using TLS for the Unix socket at {}", addr);
+            }
+            let unix = UnixStream::connect(addr).map_err(|e| RedisConnErr::with_addr(addr, e))?;
+            return Ok(Self::Unix(unix));
+        }
+        let tcp = TcpStream::connect(addr).map_err(|e| RedisConnErr::with_addr(addr, e))?;
+        match tls {
+            Some(connector) => connector
+                .connect(server_name, tcp)
+                .map(Self::Tls)
+                .map_err(|e| RedisConnErr::TlsErr(format!("{} ({})", e, addr))),
+            None => Ok(Self::Tcp(tcp)),
+        }
+    }
+
+    pub(super) fn set_nonblocking(&self, nonblocking: bool) -> io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => tcp.set_nonblocking(nonblocking),
+            Self::Tls(tls) => tls.get_ref().set_nonblocking(nonblocking),
+            Self::Unix(unix) => unix.set_nonblocking(nonblocking),
+        }
+    }
+
+    pub(super) fn set_read_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => tcp.set_read_timeout(timeout),
+            Self::Tls(tls) => tls.get_ref().set_read_timeout(timeout),
+            Self::Unix(unix) => unix.set_read_timeout(timeout),
+        }
+    }
+
+    pub(super) fn set_write_timeout(&self, timeout: Option<Duration>) -> io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => tcp.set_write_timeout(timeout),
+            Self::Tls(tls) => tls.get_ref().set_write_timeout(timeout),
+            Self::Unix(unix) => unix.set_write_timeout(timeout),
+        }
+    }
+}
+
+impl Read for RedisStream {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(tcp) => tcp.read(buf),
+            Self::Tls(tls) => tls.read(buf),
+            Self::Unix(unix) => unix.read(buf),
+        }
+    }
+}
+
+impl Write for RedisStream {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Tcp(tcp) => tcp.write(buf),
+            Self::Tls(tls) => tls.write(buf),
+            Self::Unix(unix) => unix.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Tcp(tcp) => tcp.flush(),
+            Self::Tls(tls) => tls.flush(),
+            Self::Unix(unix) => unix.flush(),
+        }
+    }
+}
@@ -0,0 +1,116 @@
+//! The names and IDs of the hashtags with live subscriptions
+//!
+//! Mastodon publishes hashtag timelines by name, but Flodgatt identifies them by ID.  A tag is
+//! registered when a timeline for it is subscribed to, and released once that timeline has
+//! been unsubscribed from Redis, so both are known for as long as its events can arrive.
+use hashbrown::HashMap;
+use std::hash::Hash;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// Called back with what a lookup found
+type Found<V> = Box<dyn FnOnce(Option<V>) + Send>;
+type IdOf = Box<dyn Fn(String, Found<i64>) + Send>;
+type NameOf = Box<dyn Fn(i64, Found<String>) + Send>;
+/// What lookups of unregistered tags found (`None` while under way, or if the tag doesn't
+/// exist), and when
+type LookedUp<K, V> = Arc<Mutex<HashMap<K, (Instant, Option<V>)>>>;
+
+/// How long to keep what a lookup found, before looking the tag up again
+const LOOKUP_TTL: Duration = Duration::from_secs(60);
+
+#[derive(Default)]
+pub(super) struct Hashtags {
+    ids: HashMap<String, i64>,
+    /// Each tag's name, and how many subscribed timelines (`hashtag` and `hashtag:local`)
+    /// are for it
+    names: HashMap<i64, (String, usize)>,
+    /// Looks up tags that aren't registered (in Postgres, off the caller's thread)
+    fallback: Option<(IdOf, NameOf)>,
+    looked_up_ids: LookedUp<String, i64>,
+    looked_up_names: LookedUp<i64, String>,
+}
+
+impl Hashtags {
+    pub(super) fn register(&mut self, id: i64, name: &str) {
+        let (_, timelines) = self
+            .names
+            .entry(id)
+            .or_insert_with(|| (name.to_string(), 0));
+        *timelines += 1;
+        self.ids.insert(name.to_string(), id);
+    }
+
+    pub(super) fn release(&mut self, id: i64) {
+        if let Some((name, timelines)) = self.names.get_mut(&id) {
+            *timelines -= 1;
+            if *timelines == 0 {
+                self.ids.remove(name.as_str());
+                self.names.remove(&id);
+            }
+        }
+    }
+
+    /// The ID of the tag with this `name`.  If it isn't registered, this starts looking it up
+    /// (without waiting for the answer, which is used from the next call on)
+    pub(super) fn id(&self, name: &str) -> Option<i64> {
+        self.ids.get(name).copied().or_else(|| {
+            let (id_of, _) = self.fallback.as_ref()?;
+            look_up(&self.looked_up_ids, name.to_string(), |name, found| {
+                log::info!("Looking up the unregistered hashtag #{}", name);
+                id_of(name, found)
+            })
+        })
+    }
+
+    /// The name of the tag with this `id`; if it isn't registered, as for `id`
+    pub(super) fn name(&self, id: i64) -> Option<String> {
+        match self.names.get(&id) {
+            Some((name, _)) => Some(name.clone()),
+            None => {
+                let (_, name_of) = self.fallback.as_ref()?;
+                look_up(&self.looked_up_names, id, |id, found| {
+                    log::info!("Looking up the unregistered hashtag with ID {}", id);
+                    name_of(id, found)
+                })
+            }
+        }
+    }
+
+    /// Look up tags that aren't registered (which should be rare) with `id_of` and `name_of`,
+    /// which call back with what they found
+    pub(super) fn fall_back_to(&mut self, id_of: IdOf, name_of: NameOf) {
+        self.fallback = Some((id_of, name_of));
+    }
+}
+
+/// What's been found for `key` by a lookup that hasn't expired, or else `None` after starting
+/// a lookup with `start`.  Only one lookup for a key is under way at a time, and what it
+/// finds (even that the tag doesn't exist) is kept for `LOOKUP_TTL`, so a burst of events
+/// for an unknown tag doesn't become a burst of queries.
+fn look_up<K, V>(looked_up: &LookedUp<K, V>, key: K, start: impl FnOnce(K, Found<V>)) -> Option<V>
+where
+    K: Hash + Eq + Clone + Send + 'static,
+    V: Clone + Send + 'static,
+{
+    let mut results = lock(looked_up);
+    results.retain(|_, (at, _)| at.elapsed() < LOOKUP_TTL);
+    if let Some((_, value)) = results.get(&key) {
+        return value.clone();
+    }
+    results.insert(key.clone(), (Instant::now(), None));
+    drop(results);
+
+    let (looked_up, found_key) = (looked_up.clone(), key.clone());
+    start(
+        key,
+        Box::new(move |value| {
+            lock(&looked_up).insert(found_key, (Instant::now(), value));
+        }),
+    );
+    None
+}
+
+fn lock<K, V>(looked_up: &LookedUp<K, V>) -> MutexGuard<HashMap<K, (Instant, Option<V>)>> {
+    looked_up.lock().unwrap_or_else(PoisonError::into_inner)
+}
@@ -2,35 +2,83 @@
 //! polled by the correct `ClientAgent`.  Also manages sububscriptions and
 //! unsubscriptions to/from Redis.
 mod err;
+mod limits;
 pub use err::Error;
+pub use limits::{Permit, Refusal};
 
+use super::super::queue::{self, QueueErr};
 use super::msg::{RedisParseErr, RedisParseOutput};
-use super::{Event, RedisCmd, RedisConn};
+use super::{Event, Hashtags, RedisCmd, RedisConn, RedisConnErr, MIN_INPUT_LEN};
 use crate::config;
-use crate::request::{Subscription, Timeline};
+use crate::logging;
+use crate::metrics::METRICS;
+use crate::request::{Subscription, Timeline, TimelineErr};
+use crate::response::event::CheckedEvent;
+use crate::Id;
+use limits::Limits;
 
 pub(self) use super::EventErr;
 
 use futures::{Async, Poll, Stream};
 use hashbrown::{HashMap, HashSet};
-use lru::LruCache;
+use std::collections::VecDeque;
 use std::convert::{TryFrom, TryInto};
+use std::net::IpAddr;
 use std::str;
 use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
-use std::time::{Duration, Instant};
-use tokio::sync::mpsc::Sender;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
 
 type Result<T> = std::result::Result<T, Error>;
-type EventChannel = Sender<Arc<Event>>;
+/// Carries events to a client, along with their IDs (pings, which are never replayed, have
+/// ID 0)
+type EventChannel = queue::Sender<(Timeline, u64, Arc<Event>)>;
 
 /// The item that streams from Redis and is polled by the `ClientAgent`
 pub struct Manager {
     pub redis_conn: RedisConn,
     timelines: HashMap<Timeline, HashMap<u32, EventChannel>>,
+    /// The channels that are full, with how many events haven't fit since they filled up (so
+    /// that each is logged once when it fills up, and once when it catches up)
+    overflowing: HashMap<(Timeline, u32), u64>,
+    /// The channel of every WebSocket connection, including those without any subscriptions,
+    /// so that each of them is told when the server shuts down
+    ws_channels: Vec<EventChannel>,
+    /// Whether the server is shutting down, after which new channels are closed at once
+    shutting_down: bool,
+    /// The channels subscribed with each access token, to disconnect if it's revoked
+    channels_by_token: HashMap<i64, Vec<(Timeline, u32)>>,
     ping_time: Instant,
     channel_id: u32,
     pub unread_idx: (usize, usize),
-    tag_id_cache: LruCache<String, i64>,
+    /// The hashtags of the subscribed timelines
+    hashtags: Hashtags,
+    reconnect: Option<Reconnect>,
+    /// When we last received input from Redis, and when we sent a heartbeat PING that Redis
+    /// hasn't yet answered (if any)
+    last_input: Instant,
+    ping_sent: Option<Instant>,
+    next_event_id: u64,
+    /// Recent events for each timeline (with their IDs and when we received them), to replay
+    /// for clients that resume from an earlier event
+    replay_buffer: HashMap<Timeline, VecDeque<(u64, Instant, Arc<Event>)>>,
+    replay_len: usize,
+    replay_max_age: Duration,
+    /// Whether we're discarding input until the next message starts (after the input buffer
+    /// filled up partway through a message)
+    skipping_input: bool,
+    /// Called with the ID of each access token that Mastodon revokes
+    on_token_revoked: Option<Box<dyn Fn(i64) + Send>>,
+    /// Called with the ID of each user whose keyword filters have changed
+    on_filters_changed: Option<Box<dyn Fn(Id) + Send>>,
+    /// How many connections each user and client IP has open, and how often each IP has
+    /// connected lately
+    limits: Limits,
+}
+
+/// When to next try to reconnect to Redis (while the connection is down)
+struct Reconnect {
+    next_attempt: Instant,
+    delay: Duration,
 }
 
 impl Stream for Manager {
@@ -59,7 +107,17 @@ impl Stream for Manager {
                         self.unread_idx.0 =
                             self.unread_idx.1 - msg.leftover_input.len() - invalid.len();
 
-                        let tl = Timeline::from_redis_text(tl, &mut self.tag_id_cache)?;
+                        if tl.starts_with("access_token:") {
+                            let token_id = tl["access_token:".len()..].to_string();
+                            self.token_revoked(&token_id);
+                            return Ok(Async::Ready(None));
+                        }
+                        let hashtags = &self.hashtags;
+                        let tl = match Timeline::from_redis_text(tl, |name| hashtags.id(name)) {
+                            // (No client can be subscribed to a tag that isn't registered)
+                            Err(TimelineErr::BadTag) => return Ok(Async::Ready(None)),
+                            tl => tl?,
+                        };
                         let event: Arc<Event> = Arc::new(msg.event_txt.try_into()?);
                         Ok(Async::Ready(Some((tl, event))))
                     } else {
@@ -74,6 +132,10 @@ impl Stream for Manager {
                     self.copy_partial_msg();
                     Ok(Async::NotReady)
                 }
+                Err(RedisParseErr::NoPerm(reply)) => {
+                    self.unread_idx.0 += reply.len() + "\r\n".len(); // the rest is still valid
+                    Err(RedisConnErr::NoPerm(reply))?
+                }
                 Err(e) => Err(Error::RedisParseErr(e, valid.to_string()))?,
             }
         } else {
@@ -84,102 +146,528 @@ impl Stream for Manager {
 }
 
 impl Manager {
+    const MIN_RECONNECT_DELAY: Duration = Duration::from_millis(500);
+    const MAX_RECONNECT_DELAY: Duration = Duration::from_secs(30);
+    /// How long Redis may be silent before we PING it, and how long it then has to answer
+    /// before we treat the connection as lost
+    const HEARTBEAT_INTERVAL: Duration = Duration::from_secs(15);
+    const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(10);
+
     // untested
     pub fn send_msgs(&mut self) -> Poll<(), Error> {
         if self.ping_time.elapsed() > Duration::from_secs(30) {
             self.send_pings()?
         }
+        if let Some(reconnect) = &self.reconnect {
+            if Instant::now() < reconnect.next_attempt || !self.try_reconnect() {
+                return Ok(Async::NotReady);
+            }
+        }
+        if !self.heartbeat() {
+            return Ok(Async::NotReady);
+        }
 
-        while let Ok(Async::Ready(Some(msg_len))) = self.redis_conn.poll_redis(self.unread_idx.1) {
+        loop {
+            if self.unread_idx.1 >= self.redis_conn.max_input_len {
+                self.shed_input();
+            }
+            let msg_len = match self.redis_conn.poll_redis(self.unread_idx.1) {
+                Ok(Async::Ready(Some(msg_len))) => msg_len,
+                Ok(_) => break,
+                Err(e) => {
+                    log::error!("Lost the connection to Redis: {}", e);
+                    self.connection_lost();
+                    return Ok(Async::NotReady);
+                }
+            };
             self.unread_idx.1 += msg_len;
+            self.last_input = Instant::now();
+            if self.skipping_input {
+                self.skip_to_next_msg();
+            }
+            let unread_len = i64::try_from(self.unread_idx.1 - self.unread_idx.0);
+            METRICS
+                .redis_input_buffer
+                .set(&[], unread_len.unwrap_or(i64::max_value()));
 
-            while let Ok(Async::Ready(msg)) = self.poll() {
+            loop {
+                let msg = match self.poll() {
+                    Ok(Async::Ready(msg)) => msg,
+                    Ok(Async::NotReady) => break,
+                    Err(e @ Error::RedisConnErr(RedisConnErr::NoPerm(_))) => {
+                        log::error!("{}", e);
+                        continue;
+                    }
+                    Err(_) => break,
+                };
                 if let Some((tl, event)) = msg {
-                    for channel in self.timelines.entry(tl).or_default().values_mut() {
-                        if let Ok(Async::NotReady) = channel.poll_ready() {
-                            log::warn!("{:?} channel full\ncan't send:{:?}", tl, event);
-                            self.rewind_to_prev_msg();
-                            return Ok(Async::NotReady);
-                        }
+                    METRICS.redis_events.inc(&[tl.kind()]);
+                    METRICS.observe_event_age("redis", tl.kind(), event.queued_at());
+                    if let Event::TypeSafe(CheckedEvent::FiltersChanged) = *event {
+                        self.filters_changed(tl);
+                    }
+                    let id = self.next_event_id;
+                    self.next_event_id += 1;
+                    self.keep_for_replay(tl, id, &event);
 
-                        let _ = channel.try_send(event.clone()); // err just means channel will be closed
+                    // A full channel only affects its own client; the rest still get the event
+                    for (channel_id, channel) in self.timelines.get(&tl).into_iter().flatten() {
+                        match channel.send((tl, id, event.clone())) {
+                            Ok(()) => {
+                                METRICS.observe_event_age("channel", tl.kind(), event.queued_at());
+                                if let Some(dropped) = self.overflowing.remove(&(tl, *channel_id)) {
+                                    log::info!(
+                                        "{:?} channel {} caught up after {} events didn't fit",
+                                        tl,
+                                        channel_id,
+                                        dropped
+                                    );
+                                }
+                            }
+                            Err(QueueErr::Overflowed) => {
+                                METRICS.channel_full.inc(&[tl.kind()]);
+                                let dropped =
+                                    self.overflowing.entry((tl, *channel_id)).or_insert(0);
+                                if *dropped == 0 {
+                                    log::warn!("{:?} channel {} full", tl, channel_id);
+                                }
+                                *dropped += 1;
+                            }
+                            // (Removed by the next `send_pings`)
+                            Err(QueueErr::Closed) | Err(QueueErr::Revoked) => (),
+                        }
                     }
                 }
             }
         }
+        self.shrink_input();
         Ok(Async::Ready(()))
     }
 
-    fn rewind_to_prev_msg(&mut self) {
-        self.unread_idx.0 = loop {
-            let input = &self.redis_conn.input[..self.unread_idx.0];
-            let input = str::from_utf8(input).unwrap_or_else(|e| {
-                str::from_utf8(input.split_at(e.valid_up_to()).0).expect("guaranteed by `split_at`")
-            });
+    /// PING Redis if it's been silent for a while, so that a connection that has silently
+    /// gone dead (and so would never report an error) is noticed; returns `false` (after
+    /// starting to reconnect) if Redis didn't answer in time
+    fn heartbeat(&mut self) -> bool {
+        match self.ping_sent {
+            Some(sent) if self.last_input >= sent => self.ping_sent = None,
+            Some(sent) if sent.elapsed() > Self::HEARTBEAT_TIMEOUT => {
+                log::error!(
+                    "Lost the connection to Redis: no reply to a PING within {:?}",
+                    Self::HEARTBEAT_TIMEOUT
+                );
+                self.connection_lost();
+                return false;
+            }
+            Some(_awaiting_reply) => (),
+            None if self.last_input.elapsed() > Self::HEARTBEAT_INTERVAL => {
+                if let Err(e) = self.redis_conn.send_ping() {
+                    log::error!("Lost the connection to Redis: {}", e);
+                    self.connection_lost();
+                    return false;
+                }
+                self.ping_sent = Some(Instant::now());
+            }
+            None => (),
+        }
+        true
+    }
 
-            let index = if let Some(i) = input.rfind("\r\n*") {
-                i + "\r\n".len()
-            } else {
-                0
-            };
-            self.unread_idx.0 = index;
+    fn connection_lost(&mut self) {
+        self.unread_idx = (0, 0); // discard any partial message from the old connection
+        self.skipping_input = false;
+        self.ping_sent = None;
+        self.reconnect = Some(Reconnect {
+            next_attempt: Instant::now(),
+            delay: Self::MIN_RECONNECT_DELAY,
+        });
+    }
+
+    /// Try to reconnect to Redis and restore the subscriptions for all current timelines;
+    /// returns whether we succeeded.  On failure, doubles the delay before the next attempt.
+    fn try_reconnect(&mut self) -> bool {
+        match self.redis_conn.reconnect() {
+            Ok(Async::Ready(())) => (),
+            Ok(Async::NotReady) => return false, // still waiting for Sentinel
+            Err(e) => {
+                let reconnect = self.reconnect.get_or_insert(Reconnect {
+                    next_attempt: Instant::now(),
+                    delay: Self::MIN_RECONNECT_DELAY,
+                });
+                log::error!(
+                    "Could not reconnect to Redis (retrying in {:?}): {}",
+                    reconnect.delay,
+                    e
+                );
+                reconnect.next_attempt = Instant::now() + reconnect.delay;
+                reconnect.delay = (reconnect.delay * 2).min(Self::MAX_RECONNECT_DELAY);
+                return false;
+            }
+        }
+        self.reconnect = None;
+        self.last_input = Instant::now();
+        self.redis_conn
+            .subscribe_to_revocations()
+            .unwrap_or_else(|e| log::error!("Could not resubscribe to revocations: {}", e));
 
-            if let Ok(Async::Ready(Some(_))) = self.poll() {
-                break index;
+        let timelines: Vec<Timeline> = self.timelines.keys().copied().collect();
+        if !timelines.is_empty() {
+            self.redis_conn
+                .send_cmd(RedisCmd::Subscribe, &timelines, &self.hashtags)
+                .unwrap_or_else(|e| log::error!("Could not resubscribe to Redis: {}", e));
+        }
+        log::warn!("Reconnected to Redis and resubscribed to {:?}", timelines);
+        true
+    }
+
+    fn keep_for_replay(&mut self, tl: Timeline, id: u64, event: &Arc<Event>) {
+        if self.replay_len == 0 {
+            return;
+        }
+        let events = self.replay_buffer.entry(tl).or_default();
+        if events.len() >= self.replay_len {
+            events.pop_front();
+        }
+        events.push_back((id, Instant::now(), event.clone()));
+    }
+
+    /// Send `channel` the recent events for `tl` that came after the event with ID `since`
+    fn replay(&self, tl: Timeline, since: u64, channel: &EventChannel) {
+        let events = self.replay_buffer.get(&tl).into_iter().flatten();
+        let mut replayed = 0;
+        for (id, received, event) in events {
+            if *id > since && received.elapsed() < self.replay_max_age {
+                if channel.send((tl, *id, event.clone())).is_err() {
+                    break;
+                }
+                replayed += 1;
+            }
+        }
+        log::info!(
+            "Replayed {} events after event {} for {:?}",
+            replayed,
+            since,
+            tl
+        );
+    }
+
+    /// Forget a revoked access token (with `on_token_revoked`) and disconnect the clients
+    /// that subscribed with it
+    fn token_revoked(&mut self, token_id: &str) {
+        let token_id = match token_id.parse() {
+            Ok(token_id) => token_id,
+            Err(_) => return log::warn!("Ignoring revocation of invalid token ID {}", token_id),
+        };
+        if let Some(on_token_revoked) = &self.on_token_revoked {
+            on_token_revoked(token_id);
+        }
+        let revoked = self.channels_by_token.remove(&token_id).unwrap_or_default();
+        for (tl, channel_id) in &revoked {
+            // (The channels themselves are removed by the next `send_pings`)
+            if let Some(channel) = self
+                .timelines
+                .get(tl)
+                .and_then(|chans| chans.get(channel_id))
+            {
+                channel.revoke();
             }
         }
+        if !revoked.is_empty() {
+            log::info!(
+                "Disconnected {} channels with a revoked token",
+                revoked.len()
+            );
+        }
+    }
+
+    fn filters_changed(&self, tl: Timeline) {
+        if let (Some(user_id), Some(on_filters_changed)) = (tl.user(), &self.on_filters_changed) {
+            on_filters_changed(user_id);
+        }
     }
 
     fn copy_partial_msg(&mut self) {
-        if self.unread_idx.0 == 0 {
-            // msg already first; no copying needed
-        } else if self.unread_idx.0 >= (self.unread_idx.1 - self.unread_idx.0) {
-            let (read, unread) =
-                self.redis_conn.input[..self.unread_idx.1].split_at_mut(self.unread_idx.0);
-            for (i, b) in unread.iter().enumerate() {
-                read[i] = *b;
+        let (start, end) = self.unread_idx;
+        if start > 0 {
+            self.redis_conn.input.copy_within(start..end, 0);
+        }
+        self.unread_idx = (0, end - start);
+    }
+
+    /// Make room in the (full) input buffer by discarding the oldest unread message
+    fn shed_input(&mut self) {
+        let discarded = self.skip_to_next_msg();
+        log::error!(
+            "The Redis input buffer is full ({} KiB); discarded {} bytes of input",
+            self.redis_conn.input.len() / 1024,
+            discarded
+        );
+    }
+
+    /// Discard unread input up to the start of the next message; if it hasn't arrived yet,
+    /// keep discarding input as it arrives.  Returns the number of bytes discarded.
+    fn skip_to_next_msg(&mut self) -> usize {
+        let (start, end) = self.unread_idx;
+        let input = &self.redis_conn.input[start..end];
+        // Every message is an array of strings and integers, so "\r\n*" can only occur where
+        // one message ends and the next begins
+        let discarded = match input.windows(3).position(|w| w == b"\r\n*") {
+            Some(i) => {
+                self.unread_idx.0 = start + i + "\r\n".len();
+                self.skipping_input = false;
+                i + "\r\n".len()
             }
-        } else {
-            // Less efficient, but should never occur in production
-            log::warn!("Moving partial input requires heap allocation");
-            self.redis_conn.input = self.redis_conn.input[self.unread_idx.0..].into();
+            None => {
+                // Keep the last two bytes, in case they're the "\r\n" before the next message
+                let keep = input.len().min("\r\n".len());
+                self.redis_conn.input.copy_within(end - keep..end, 0);
+                self.unread_idx = (0, keep);
+                self.skipping_input = true;
+                end - start - keep
+            }
+        };
+        let discarded_len = i64::try_from(discarded).unwrap_or(i64::max_value());
+        METRICS.redis_input_discarded.add(&[], discarded_len);
+        discarded
+    }
+
+    /// Give back the memory from a burst of input, once we've processed it
+    fn shrink_input(&mut self) {
+        let input = &mut self.redis_conn.input;
+        if input.len() > MIN_INPUT_LEN && self.unread_idx.1 <= MIN_INPUT_LEN / 2 {
+            input.truncate(MIN_INPUT_LEN);
+            input.shrink_to_fit();
+            log::info!("Shrinking input buffer to {} KiB.", MIN_INPUT_LEN / 1024);
         }
-        self.unread_idx = (0, self.unread_idx.1 - self.unread_idx.0);
-        &self.unread_idx;
     }
+
     /// Create a new `Manager`, with its own Redis connections (but no active subscriptions).
     pub fn try_from(redis_cfg: &config::Redis) -> Result<Self> {
+        let mut redis_conn = RedisConn::new(redis_cfg)?;
+        redis_conn.subscribe_to_revocations()?;
         Ok(Self {
-            redis_conn: RedisConn::new(redis_cfg)?,
+            redis_conn,
             timelines: HashMap::new(),
+            overflowing: HashMap::new(),
+            ws_channels: Vec::new(),
+            shutting_down: false,
+            channels_by_token: HashMap::new(),
             ping_time: Instant::now(),
             channel_id: 0,
             unread_idx: (0, 0),
-            tag_id_cache: LruCache::new(1000),
+            hashtags: Hashtags::default(),
+            reconnect: None,
+            last_input: Instant::now(),
+            ping_sent: None,
+            next_event_id: Self::first_event_id(),
+            replay_buffer: HashMap::new(),
+            replay_len: 0,
+            replay_max_age: Duration::from_secs(0),
+            skipping_input: false,
+            on_token_revoked: None,
+            on_filters_changed: None,
+            limits: Limits::default(),
         })
     }
 
+    /// Look up hashtags that aren't registered with `id_of` (which finds a tag's ID from its
+    /// name) and `name_of` (which finds its name from its ID).  This should be rare: a tag is
+    /// registered for as long as any timeline for it is subscribed to.  Each is called with a
+    /// callback for what it found, so that it can look the tag up without holding up the
+    /// `Manager`.
+    pub fn look_up_missing_hashtags(
+        &mut self,
+        id_of: impl Fn(String, Box<dyn FnOnce(Option<i64>) + Send>) + Send + 'static,
+        name_of: impl Fn(i64, Box<dyn FnOnce(Option<String>) + Send>) + Send + 'static,
+    ) {
+        self.hashtags
+            .fall_back_to(Box::new(id_of), Box::new(name_of));
+    }
+
+    /// Call `f` with the ID of each access token that Mastodon revokes
+    pub fn on_token_revoked(&mut self, f: impl Fn(i64) + Send + 'static) {
+        self.on_token_revoked = Some(Box::new(f));
+    }
+
+    /// Call `f` with the ID of each user whose keyword filters change (once per change, however
+    /// many connections the user has open)
+    pub fn on_filters_changed(&mut self, f: impl Fn(Id) + Send + 'static) {
+        self.on_filters_changed = Some(Box::new(f));
+    }
+
+    /// Event IDs start from the current time (in microseconds), so that they keep increasing
+    /// across restarts
+    fn first_event_id() -> u64 {
+        SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(1, |now| {
+                now.as_secs() * 1_000_000 + u64::from(now.subsec_micros())
+            })
+    }
+
+    /// Keep up to `len` recent events for each timeline (for up to `max_age`), to replay for
+    /// clients that resume from an earlier event
+    pub fn keep_events_for_replay(&mut self, len: usize, max_age: Duration) {
+        self.replay_len = len;
+        self.replay_max_age = max_age;
+    }
+
+    /// Limit the connections each user and each client IP may have open, and the new
+    /// connections each IP may open per minute (0 for no limit)
+    pub fn limit_connections(&mut self, per_user: usize, per_ip: usize, rate_per_ip: usize) {
+        self.limits.set(per_user, per_ip, rate_per_ip);
+    }
+
+    /// Admit a new `transport` connection from `ip` (if known) for `user_id` (if
+    /// authenticated), unless it's over a limit.  Dropping the `Permit` releases it.
+    pub fn admit(
+        &self,
+        transport: &'static str,
+        ip: Option<IpAddr>,
+        user_id: Option<Id>,
+    ) -> std::result::Result<Permit, Refusal> {
+        let mut permit = self.admit_ip(transport, ip)?;
+        self.admit_user(transport, &mut permit, user_id)?;
+        Ok(permit)
+    }
+
+    /// Admit a new `transport` connection from `ip` (if known), unless it's over a per-IP
+    /// limit; this can be checked before the client is authenticated
+    pub fn admit_ip(
+        &self,
+        transport: &'static str,
+        ip: Option<IpAddr>,
+    ) -> std::result::Result<Permit, Refusal> {
+        self.limits
+            .admit_ip(ip)
+            .map_err(|refusal| refused(transport, refusal, None))
+    }
+
+    /// Admit the connection `permit` was given for `user_id` (if authenticated), unless
+    /// it's over the per-user limit
+    pub fn admit_user(
+        &self,
+        transport: &'static str,
+        permit: &mut Permit,
+        user_id: Option<Id>,
+    ) -> std::result::Result<(), Refusal> {
+        permit
+            .admit_user(user_id)
+            .map_err(|refusal| refused(transport, refusal, user_id))
+    }
+
     pub fn into_arc(self) -> Arc<Mutex<Self>> {
         Arc::new(Mutex::new(self))
     }
 
-    pub fn subscribe(&mut self, subscription: &Subscription, channel: EventChannel) {
-        let (tag, tl) = (subscription.hashtag_name.clone(), subscription.timeline);
-        if let (Some(hashtag), Some(id)) = (tag, tl.tag()) {
-            self.tag_id_cache.put(hashtag.clone(), id);
-            self.redis_conn.tag_name_cache.put(id, hashtag);
-        };
+    /// Send events for the `Subscription`'s timeline to `channel`; returns the channel's ID,
+    /// which is needed to later `unsubscribe` it
+    pub fn subscribe(&mut self, subscription: &Subscription, channel: EventChannel) -> u32 {
+        let tl = subscription.timeline;
+        if self.shutting_down {
+            // (Such as a request that arrived on a connection that was kept alive)
+            channel.close();
+            self.channel_id += 1;
+            return self.channel_id - 1;
+        }
+        if let Some(since) = subscription.since {
+            self.replay(tl, since, &channel);
+        }
 
+        let channel_id = self.channel_id;
+        if let Some(token_id) = subscription.token_id {
+            let channels = self.channels_by_token.entry(token_id).or_default();
+            channels.push((tl, channel_id));
+        }
         let channels = self.timelines.entry(tl).or_default();
-        channels.insert(self.channel_id, channel);
+        channels.insert(channel_id, channel);
         self.channel_id += 1;
 
         if channels.len() == 1 {
+            if let (Some(id), Some(name)) = (tl.tag(), &subscription.hashtag_name) {
+                self.hashtags.register(id, name);
+            }
             self.redis_conn
-                .send_cmd(RedisCmd::Subscribe, &[tl])
+                .send_cmd(RedisCmd::Subscribe, &[tl], &self.hashtags)
                 .unwrap_or_else(|e| log::error!("Could not subscribe to the Redis channel: {}", e));
             log::info!("Subscribed to {:?}", tl);
         };
+        channel_id
+    }
+
+    /// Stop sending events for `tl` to the channel with `channel_id`
+    pub fn unsubscribe(&mut self, tl: Timeline, channel_id: u32) {
+        let channels = match self.timelines.get_mut(&tl) {
+            Some(channels) => channels,
+            None => return,
+        };
+        channels.remove(&channel_id);
+
+        if channels.is_empty() {
+            self.timelines.remove(&tl);
+            self.redis_conn
+                .send_cmd(RedisCmd::Unsubscribe, &[tl], &self.hashtags)
+                .unwrap_or_else(|e| log::error!("Could not unsubscribe from Redis: {}", e));
+            log::info!("Unsubscribed from {:?}", tl);
+            self.release_hashtags(&[tl]);
+        }
+    }
+
+    /// Keep a WebSocket connection's `channel` (which it will subscribe with), so that it's
+    /// closed on shutdown even if the connection has no subscriptions
+    pub fn track_ws(&mut self, channel: EventChannel) {
+        if self.shutting_down {
+            channel.close();
+        } else {
+            self.ws_channels.push(channel);
+        }
+    }
+
+    /// Close every client's channel, so that each client is told that the server is shutting
+    /// down once it has received the events already sent to it.  Channels for later
+    /// subscriptions are closed as soon as they're made.
+    pub fn close_channels(&mut self) {
+        self.shutting_down = true;
+        let channels: Vec<_> = self.timelines.values().flat_map(HashMap::values).collect();
+        for channel in channels.iter().copied().chain(&self.ws_channels) {
+            channel.close();
+        }
+        log::info!(
+            "Closed {} client channels and {} WebSocket connections",
+            channels.len(),
+            self.ws_channels.len()
+        );
+    }
+
+    /// Drop every channel and unsubscribe from all timelines, which resets their
+    /// `subscribed:` keys in Redis
+    pub fn unsubscribe_all(&mut self) -> Result<()> {
+        let timelines: Vec<Timeline> = self.timelines.drain().map(|(tl, _)| tl).collect();
+        self.channels_by_token.clear();
+        self.overflowing.clear();
+        self.close_subscriptions(&timelines)
+    }
+
+    /// Unsubscribe from timelines that no longer have any channels, then release their
+    /// hashtags (which the Redis command needs, so they're released last)
+    fn close_subscriptions(&mut self, timelines: &[Timeline]) -> Result<()> {
+        // (While reconnecting, there's no Redis subscription to close)
+        let unsubscribed = if !timelines.is_empty() && self.reconnect.is_none() {
+            let sent = self
+                .redis_conn
+                .send_cmd(RedisCmd::Unsubscribe, timelines, &self.hashtags);
+            log::info!("Unsubscribed from {:?}", timelines);
+            sent
+        } else {
+            Ok(())
+        };
+        self.release_hashtags(timelines);
+        Ok(unsubscribed?)
+    }
+
+    fn release_hashtags(&mut self, timelines: &[Timeline]) {
+        for id in timelines.iter().filter_map(Timeline::tag) {
+            self.hashtags.release(id);
+        }
     }
 
     fn send_pings(&mut self) -> Result<()> {
@@ -191,7 +679,8 @@ impl Manager {
         self.ping_time = Instant::now();
         let mut subscriptions_to_close = HashSet::new();
         self.timelines.retain(|tl, channels| {
-            channels.retain(|_, chan| chan.try_send(Arc::new(Event::Ping)).is_ok());
+            let ping = || (*tl, 0, Arc::new(Event::Ping));
+            channels.retain(|_, chan| chan.send(ping()) != Err(QueueErr::Closed));
 
             if channels.is_empty() {
                 subscriptions_to_close.insert(*tl);
@@ -200,14 +689,33 @@ impl Manager {
                 true
             }
         });
-        if !subscriptions_to_close.is_empty() {
-            let timelines: Vec<_> = subscriptions_to_close.into_iter().collect();
-            &self
-                .redis_conn
-                .send_cmd(RedisCmd::Unsubscribe, &timelines[..])?;
-            log::info!("Unsubscribed from {:?}", timelines);
-        }
-        Ok(())
+        let timelines = &self.timelines;
+        let is_open = |(tl, id): &(Timeline, u32)| {
+            timelines
+                .get(tl)
+                .map_or(false, |chans| chans.contains_key(id))
+        };
+        self.channels_by_token.retain(|_, channels| {
+            channels.retain(is_open);
+            !channels.is_empty()
+        });
+        self.overflowing.retain(|channel, _| is_open(channel));
+        self.ws_channels.retain(|channel| !channel.is_closed());
+        let max_age = self.replay_max_age;
+        self.replay_buffer.retain(|_, events| {
+            while events
+                .front()
+                .map_or(false, |(_, t, _)| t.elapsed() >= max_age)
+            {
+                events.pop_front();
+            }
+            !events.is_empty()
+        });
+        self.limits.prune();
+
+        // (While reconnecting, there's no Redis subscription to close)
+        let timelines: Vec<_> = subscriptions_to_close.into_iter().collect();
+        self.close_subscriptions(&timelines)
     }
 
     pub fn recover(poisoned: PoisonError<MutexGuard<Self>>) -> MutexGuard<Self> {
@@ -247,5 +755,19 @@ impl Manager {
     }
 }
 
+/// Count and log a refused `transport` connection (for `user_id`, if known)
+fn refused(transport: &'static str, refusal: Refusal, user_id: Option<Id>) -> Refusal {
+    METRICS
+        .connections_refused
+        .inc(&[transport, refusal.label()]);
+    let fields = serde_json::json!({
+        "transport": transport,
+        "reason": refusal.label(),
+        "user_id": user_id.map(|Id(id)| id),
+    });
+    logging::record(log::Level::Info, "refused", fields);
+    refusal
+}
+
 #[cfg(test)]
 mod test;
@@ -1,9 +1,8 @@
 use super::super::{RedisConnErr, RedisParseErr};
-use super::{Event, EventErr};
+use super::EventErr;
 use crate::request::TimelineErr;
 
 use std::fmt;
-use std::sync::Arc;
 
 #[derive(Debug)]
 pub enum Error {
@@ -12,7 +11,6 @@ pub enum Error {
     EventErr(EventErr),
     RedisParseErr(RedisParseErr, String),
     RedisConnErr(RedisConnErr),
-    ChannelSendErr(tokio::sync::mpsc::error::TrySendError<Arc<Event>>),
 }
 
 impl std::error::Error for Error {}
@@ -29,18 +27,11 @@ impl fmt::Display for Error {
             RedisParseErr(inner, input) => write!(f, "error parsing {}\n{}", input, inner),
             RedisConnErr(inner) => write!(f, "{}", inner),
             TimelineErr(inner) => write!(f, "{}", inner),
-            ChannelSendErr(inner) => write!(f, "{}", inner),
         }?;
         Ok(())
     }
 }
 
-impl From<tokio::sync::mpsc::error::TrySendError<Arc<Event>>> for Error {
-    fn from(error: tokio::sync::mpsc::error::TrySendError<Arc<Event>>) -> Self {
-        Self::ChannelSendErr(error)
-    }
-}
-
 impl From<EventErr> for Error {
     fn from(error: EventErr) -> Self {
         Self::EventErr(error)
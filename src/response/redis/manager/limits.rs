@@ -0,0 +1,162 @@
+//! Limits on how many connections each user and each client IP may have open, and on how
+//! often each IP may open new ones
+use crate::Id;
+
+use hashbrown::HashMap;
+use std::fmt;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError};
+use std::time::{Duration, Instant};
+
+/// The window over which new connections from an IP are counted
+const RATE_WINDOW: Duration = Duration::from_secs(60);
+
+/// Shared with every `Permit`, so that connections can be released without locking the
+/// `Manager`
+#[derive(Clone, Default)]
+pub(super) struct Limits(Arc<Mutex<Counts>>);
+
+#[derive(Default)]
+struct Counts {
+    /// The limits (0 for no limit)
+    max_per_user: usize,
+    max_per_ip: usize,
+    max_rate_per_ip: usize,
+    per_user: HashMap<Id, usize>,
+    per_ip: HashMap<IpAddr, usize>,
+    /// When each IP's current rate window started, and how many connections it opened since
+    recent: HashMap<IpAddr, (Instant, usize)>,
+}
+
+/// Admission of one connection; dropping it (when the client disconnects) releases the
+/// connection from the per-user and per-IP counts
+pub struct Permit {
+    limits: Limits,
+    user_id: Option<Id>,
+    ip: Option<IpAddr>,
+}
+
+/// Why a connection was refused
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Refusal {
+    TooManyForUser,
+    TooManyForIp,
+    TooFrequent,
+}
+
+impl Limits {
+    pub(super) fn set(&self, max_per_user: usize, max_per_ip: usize, max_rate_per_ip: usize) {
+        let mut counts = self.lock();
+        counts.max_per_user = max_per_user;
+        counts.max_per_ip = max_per_ip;
+        counts.max_rate_per_ip = max_rate_per_ip;
+    }
+
+    /// Admit a connection from `ip` (if known), unless that would go over a per-IP limit; the
+    /// user is admitted with `Permit::admit_user` once they're authenticated
+    pub(super) fn admit_ip(&self, ip: Option<IpAddr>) -> Result<Permit, Refusal> {
+        let mut guard = self.lock();
+        let counts = &mut *guard;
+        if let Some(ip) = ip {
+            if counts.max_rate_per_ip > 0 {
+                let now = Instant::now();
+                let window = counts.recent.entry(ip).or_insert((now, 0));
+                if now.duration_since(window.0) >= RATE_WINDOW {
+                    *window = (now, 0);
+                }
+                if window.1 >= counts.max_rate_per_ip {
+                    return Err(Refusal::TooFrequent);
+                }
+                // (Counted even if refused below, so that retrying doesn't get around the rate)
+                window.1 += 1;
+            }
+            let open = counts.per_ip.get(&ip).copied().unwrap_or(0);
+            if counts.max_per_ip > 0 && open >= counts.max_per_ip {
+                return Err(Refusal::TooManyForIp);
+            }
+            *counts.per_ip.entry(ip).or_default() += 1;
+        }
+        Ok(Permit {
+            limits: self.clone(),
+            user_id: None,
+            ip,
+        })
+    }
+
+    /// Forget the rate windows that have ended
+    pub(super) fn prune(&self) {
+        self.lock()
+            .recent
+            .retain(|_, (start, _)| start.elapsed() < RATE_WINDOW);
+    }
+
+    fn lock(&self) -> MutexGuard<Counts> {
+        self.0.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+}
+
+impl Permit {
+    /// Admit the connection for `user_id` (if authenticated), unless that would go over the
+    /// per-user limit
+    pub(super) fn admit_user(&mut self, user_id: Option<Id>) -> Result<(), Refusal> {
+        if let (Some(user_id), None) = (user_id, self.user_id) {
+            let mut counts = self.limits.lock();
+            let open = counts.per_user.get(&user_id).copied().unwrap_or(0);
+            if counts.max_per_user > 0 && open >= counts.max_per_user {
+                return Err(Refusal::TooManyForUser);
+            }
+            *counts.per_user.entry(user_id).or_default() += 1;
+            self.user_id = Some(user_id);
+        }
+        Ok(())
+    }
+}
+
+impl Drop for Permit {
+    fn drop(&mut self) {
+        let mut counts = self.limits.lock();
+        if let Some(user_id) = self.user_id {
+            release(&mut counts.per_user, user_id);
+        }
+        if let Some(ip) = self.ip {
+            release(&mut counts.per_ip, ip);
+        }
+    }
+}
+
+fn release<K: Eq + std::hash::Hash>(open: &mut HashMap<K, usize>, key: K) {
+    if let Some(n) = open.get_mut(&key) {
+        *n -= 1;
+        if *n == 0 {
+            open.remove(&key);
+        }
+    }
+}
+
+impl Refusal {
+    /// A short label, for metrics and logs
+    pub fn label(self) -> &'static str {
+        match self {
+            Refusal::TooManyForUser => "user_connections",
+            Refusal::TooManyForIp => "ip_connections",
+            Refusal::TooFrequent => "ip_rate",
+        }
+    }
+
+    /// The reason given to the client
+    pub fn reason(self) -> &'static str {
+        match self {
+            Refusal::TooManyForUser => "Error: Too many connections for this user",
+            Refusal::TooManyForIp => "Error: Too many connections from this address",
+            Refusal::TooFrequent => "Error: Too many new connections from this address",
+        }
+    }
+}
+
+impl std::error::Error for Refusal {}
+
+impl fmt::Display for Refusal {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.reason())
+    }
+}
@@ -1,5 +1,6 @@
 use super::*;
 use crate::config;
+use crate::request::{Content, Reach, Stream::Hashtag};
 use crate::response::event::checked_event::{
     account::{Account, Field},
     status::attachment::{Attachment, AttachmentType::*},
@@ -202,44 +203,413 @@ fn manager_poll_handles_partial_events() -> TestResult {
 }
 
 #[test]
-fn manager_poll_handles_full_channel() -> TestResult {
+fn manager_discards_partial_event_after_reconnecting() -> TestResult {
     let mut manager = Manager::try_from(&config::Redis::default())?;
-    for i in 1..=6 {
-        manager.redis_conn.add(&input(i));
-    }
-    let (mut i, channel_full) = (0, 3);
-    'outer: loop {
-        while let Ok(Async::Ready(Some(n))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
-            manager.unread_idx.1 += n;
-            while let Ok(Async::Ready(msg)) = manager.poll() {
-                if let Some((_tl, event)) = msg {
-                    println!("Parsing Event #{:03}", i + 1);
-                    assert_eq!(event, output(i));
-                    i += 1;
-                }
-                // Simulates a `ChannelFull` error after sending `channel_full` msgs
-                if i == channel_full {
-                    break 'outer;
-                }
+    manager.redis_conn.add(&input(1));
+    manager.redis_conn.add(&input(2)[..50]);
+    let mut i = 0;
+
+    while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
+        manager.unread_idx.1 += len;
+        while let Ok(Async::Ready(msg)) = manager.poll() {
+            if let Some((_tl, event)) = msg {
+                assert_eq!(event, output(i));
+                i += 1;
             }
         }
     }
+    assert_eq!(i, 1);
 
-    let _rewind = (|| {
-        manager.rewind_to_prev_msg();
-        i -= 1;
-    })();
+    manager.connection_lost();
+    assert!(manager.try_reconnect());
+    assert_eq!(manager.unread_idx, (0, 0));
 
+    // The new connection starts from a fresh message, not the rest of the partial one
+    manager.redis_conn.add(&input(3));
+    i = 2;
     while let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(manager.unread_idx.1) {
         manager.unread_idx.1 += len;
         while let Ok(Async::Ready(msg)) = manager.poll() {
             if let Some((_tl, event)) = msg {
-                println!("Parsing Event #{:03}", i + 1);
                 assert_eq!(event, output(i));
                 i += 1;
             }
         }
     }
+    Ok(assert_eq!(i, 3))
+}
 
-    Ok(assert_eq!(i, 6))
+#[test]
+fn manager_discards_msgs_too_big_for_the_input_buffer() -> TestResult {
+    let env = vec![("REDIS_INPUT_BUFFER_MAX".to_string(), "16".to_string())];
+    let (_, redis_cfg, _) = config::from_env(env.into_iter().collect())?;
+    let mut manager = Manager::try_from(&redis_cfg)?;
+    let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        ..Subscription::default()
+    };
+    manager.subscribe(&subscription, event_tx);
+
+    let too_big = "x".repeat(MIN_INPUT_LEN);
+    manager.redis_conn.add(
+        format!(
+            "*3\r\n$7\r\nmessage\r\n$15\r\ntimeline:public\r\n${}\r\n{}\r\n",
+            too_big.len(),
+            too_big
+        )
+        .as_bytes(),
+    );
+    manager.redis_conn.add(&input(1));
+    manager.send_msgs()?;
+    assert_eq!(manager.redis_conn.input.len(), MIN_INPUT_LEN);
+
+    drop(manager); // closes the channel
+    let events: Vec<_> = event_rx
+        .wait()
+        .map(|r| r.map(|(_tl, _id, event)| event))
+        .collect();
+    Ok(assert_eq!(events, vec![Ok(output(0))]))
+}
+
+#[test]
+fn manager_replays_events_after_the_last_one_received() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    manager.keep_events_for_replay(10, Duration::from_secs(60));
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        ..Subscription::default()
+    };
+    let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    manager.subscribe(&subscription, event_tx);
+    for i in 1..=3 {
+        manager.redis_conn.add(&input(i));
+    }
+    manager.send_msgs()?;
+
+    let mut received = event_rx.wait();
+    let first_id = match received.next() {
+        Some(Ok((_tl, id, event))) if event == output(0) => id,
+        other => panic!("Expected the first event, but got {:?}", other),
+    };
+
+    // A client that resumes after the first event gets only the two after it
+    let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    let resumed = Subscription {
+        since: Some(first_id),
+        ..subscription
+    };
+    manager.subscribe(&resumed, event_tx);
+    drop(manager); // closes the channels
+    let replayed: Vec<_> = event_rx
+        .wait()
+        .map(|r| r.map(|(_tl, id, event)| (id, event)))
+        .collect();
+    Ok(assert_eq!(
+        replayed,
+        vec![Ok((first_id + 1, output(1))), Ok((first_id + 2, output(2)))]
+    ))
+}
+
+#[test]
+fn manager_closes_channels_after_queued_events_on_shutdown() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        ..Subscription::default()
+    };
+    let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    manager.subscribe(&subscription, event_tx);
+    manager.redis_conn.add(&input(1));
+    manager.send_msgs()?;
+
+    manager.close_channels();
+    manager.unsubscribe_all()?;
+    assert_eq!(manager.timelines.len(), 0);
+
+    // The client gets the event it was already sent, then notice of the shutdown
+    let received: Vec<_> = event_rx
+        .wait()
+        .map(|r| r.map(|(_tl, _id, event)| event))
+        .collect();
+    Ok(assert_eq!(
+        received,
+        vec![Ok(output(0)), Err(QueueErr::Closed)]
+    ))
+}
+
+#[test]
+fn manager_refuses_connections_over_the_limits_until_some_disconnect() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    manager.limit_connections(2, 3, 0);
+    let (ip, other_ip) = ("10.0.0.1".parse()?, "10.0.0.2".parse()?);
+    let (user, other_user) = (Some(Id(1)), Some(Id(2)));
+
+    let first = manager.admit("sse", Some(ip), user)?;
+    let _second = manager.admit("ws", Some(other_ip), user)?;
+    assert_eq!(
+        manager.admit("sse", None, user).err(),
+        Some(Refusal::TooManyForUser)
+    );
+    let _third = manager.admit("sse", Some(ip), other_user)?;
+    let _fourth = manager.admit("sse", Some(ip), None)?;
+    assert_eq!(
+        manager.admit("ws", Some(ip), None).err(),
+        Some(Refusal::TooManyForIp)
+    );
+
+    // Disconnecting frees a place for both the user and the IP
+    drop(first);
+    manager.admit("sse", Some(ip), user)?;
+    Ok(())
+}
+
+#[test]
+fn manager_refuses_connections_opened_too_often() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    manager.limit_connections(0, 0, 2);
+    let ip = Some("10.0.0.1".parse()?);
+
+    drop(manager.admit("sse", ip, None)?);
+    drop(manager.admit("sse", ip, None)?);
+    assert_eq!(
+        manager.admit("sse", ip, None).err(),
+        Some(Refusal::TooFrequent)
+    );
+    Ok(assert!(manager.admit("sse", None, None).is_ok()))
+}
+
+#[test]
+fn manager_knows_hashtags_until_their_last_timeline_is_unsubscribed() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let (federated, local) = (
+        Timeline(Hashtag(7), Reach::Federated, Content::All),
+        Timeline(Hashtag(7), Reach::Local, Content::All),
+    );
+    let mut channel_ids = Vec::new();
+    let mut receivers = Vec::new();
+    for &timeline in &[federated, local] {
+        let subscription = Subscription {
+            timeline,
+            hashtag_name: Some("rust".to_string()),
+            ..Subscription::default()
+        };
+        let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+        channel_ids.push(manager.subscribe(&subscription, event_tx));
+        receivers.push(event_rx);
+    }
+
+    let input = String::from_utf8(input(1))?;
+    let input = input.replacen("$15\ntimeline:public", "$21\ntimeline:hashtag:rust", 1);
+    manager.redis_conn.add(input.as_bytes());
+    manager.send_msgs()?;
+    assert_eq!(manager.hashtags.id("rust"), Some(7));
+
+    manager.unsubscribe(federated, channel_ids[0]);
+    assert_eq!(manager.hashtags.name(7), Some("rust".to_string()));
+    manager.unsubscribe(local, channel_ids[1]);
+    assert_eq!(manager.hashtags.id("rust"), None);
+
+    drop(manager);
+    let received: Vec<_> = receivers
+        .remove(0)
+        .wait()
+        .map(|r| r.map(|(tl, _id, event)| (tl, event)))
+        .collect();
+    Ok(assert_eq!(received, vec![Ok((federated, output(0)))]))
+}
+
+#[test]
+fn manager_reloads_filters_once_per_change_for_all_of_a_users_connections() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let reloaded = Arc::new(Mutex::new(Vec::new()));
+    let reloads = reloaded.clone();
+    manager.on_filters_changed(move |user_id| reloads.lock().expect("test").push(user_id));
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("1", |_| None)?,
+        ..Subscription::default()
+    };
+    let mut receivers = Vec::new();
+    for _ in 0..2 {
+        let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+        manager.subscribe(&subscription, event_tx);
+        receivers.push(event_rx);
+    }
+
+    let event = r#"{"event":"filters_changed"}"#;
+    manager.redis_conn.add(
+        format!(
+            "*3\r\n$7\r\nmessage\r\n$10\r\ntimeline:1\r\n${}\r\n{}\r\n",
+            event.len(),
+            event
+        )
+        .as_bytes(),
+    );
+    manager.send_msgs()?;
+    Ok(assert_eq!(*reloaded.lock().expect("test"), vec![Id(1)]))
+}
+
+#[test]
+fn manager_reconnects_when_redis_does_not_answer_a_ping() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    manager.last_input = Instant::now() - Manager::HEARTBEAT_INTERVAL * 2;
+    manager.send_msgs()?;
+    let ping_sent = manager.ping_sent.expect("a PING after the silence");
+    assert!(manager.reconnect.is_none());
+
+    // No reply in time
+    manager.ping_sent = Some(ping_sent - Manager::HEARTBEAT_TIMEOUT * 2);
+    manager.send_msgs()?;
+    assert!(manager.reconnect.is_some());
+    Ok(assert_eq!(manager.ping_sent, None))
+}
+
+#[test]
+fn manager_keeps_the_connection_when_redis_answers_a_ping() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    manager.last_input = Instant::now() - Manager::HEARTBEAT_INTERVAL * 2;
+    manager.send_msgs()?;
+    let ping_sent = manager.ping_sent.expect("a PING after the silence");
+
+    manager.redis_conn.add(b"*2\r\n$4\r\npong\r\n$0\r\n\r\n");
+    manager.send_msgs()?;
+    manager.ping_sent = Some(ping_sent - Manager::HEARTBEAT_TIMEOUT * 2); // (but answered)
+    manager.send_msgs()?;
+    assert!(manager.reconnect.is_none());
+    Ok(assert_eq!(manager.ping_sent, None))
+}
+
+#[test]
+fn manager_reports_denied_subscriptions_and_keeps_reading() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        ..Subscription::default()
+    };
+    manager.subscribe(&subscription, event_tx);
+
+    let denied = "-NOPERM this user has no permissions to access one of the channels\r\n";
+    manager.redis_conn.add(denied.as_bytes());
+    if let Ok(Async::Ready(Some(len))) = manager.redis_conn.poll_redis(0) {
+        manager.unread_idx.1 += len;
+    }
+    assert!(matches!(
+        manager.poll(),
+        Err(Error::RedisConnErr(RedisConnErr::NoPerm(_)))
+    ));
+
+    manager.redis_conn.add(&input(1));
+    manager.send_msgs()?;
+    drop(manager);
+    let events: Vec<_> = event_rx
+        .wait()
+        .map(|r| r.map(|(_tl, _id, event)| event))
+        .collect();
+    Ok(assert_eq!(events, vec![Ok(output(0))]))
+}
+
+#[test]
+fn manager_disconnects_channels_subscribed_with_a_revoked_token() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let forgotten = Arc::new(Mutex::new(Vec::new()));
+    let forgotten_by_callback = forgotten.clone();
+    manager.on_token_revoked(move |token_id| {
+        forgotten_by_callback.lock().expect("test").push(token_id)
+    });
+    let subscription = |token_id| Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None).expect("test"),
+        token_id: Some(token_id),
+        ..Subscription::default()
+    };
+    let (revoked_tx, revoked_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    let (other_tx, other_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    manager.subscribe(&subscription(7), revoked_tx);
+    manager.subscribe(&subscription(8), other_tx);
+
+    let revocation = "*4\r\n$8\r\npmessage\r\n$24\r\ntimeline:access_token:*\r\n$24\r\ntimeline:access_token:7\r\n$16\r\n{\"event\":\"kill\"}\r\n";
+    manager.redis_conn.add(revocation.as_bytes());
+    manager.redis_conn.add(&input(1));
+    manager.send_msgs()?;
+    assert_eq!(*forgotten.lock().expect("test"), vec![7]);
+    assert!(manager.channels_by_token.get(&7).is_none());
+
+    drop(manager);
+    let received = |rx: queue::Receiver<(Timeline, u64, Arc<Event>)>| -> Vec<_> {
+        rx.wait()
+            .map(|r| r.map(|(_tl, _id, event)| event))
+            .collect()
+    };
+    assert_eq!(received(revoked_rx), vec![Err(QueueErr::Revoked)]);
+    Ok(assert_eq!(received(other_rx), vec![Ok(output(0))]))
+}
+
+#[test]
+fn manager_counts_the_events_a_full_channel_misses_until_it_catches_up() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let (event_tx, mut event_rx) = queue::channel(1, config::OverflowPolicy::DropNewest);
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        ..Subscription::default()
+    };
+    let channel_id = manager.subscribe(&subscription, event_tx);
+    let channel = (subscription.timeline, channel_id);
+
+    for _ in 0..3 {
+        manager.redis_conn.add(&input(1));
+    }
+    manager.send_msgs()?;
+    assert_eq!(manager.overflowing.get(&channel), Some(&2));
+
+    assert!((&mut event_rx).wait().next().is_some()); // the client makes room
+    manager.redis_conn.add(&input(1));
+    manager.send_msgs()?;
+    Ok(assert!(manager.overflowing.is_empty()))
+}
+
+#[test]
+fn manager_closes_every_ws_channel_and_later_subscriptions_on_shutdown() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let (unsubscribed_tx, unsubscribed_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    manager.track_ws(unsubscribed_tx);
+
+    manager.close_channels();
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        ..Subscription::default()
+    };
+    let (late_tx, late_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    manager.subscribe(&subscription, late_tx);
+    assert_eq!(manager.timelines.len(), 0);
+
+    let received = |rx: queue::Receiver<(Timeline, u64, Arc<Event>)>| -> Vec<_> {
+        rx.wait()
+            .map(|r| r.map(|(_tl, _id, event)| event))
+            .collect()
+    };
+    assert_eq!(received(unsubscribed_rx), vec![Err(QueueErr::Closed)]);
+    Ok(assert_eq!(received(late_rx), vec![Err(QueueErr::Closed)]))
+}
+
+#[test]
+fn manager_looks_up_unregistered_hashtags_without_waiting_for_the_answer() -> TestResult {
+    let mut manager = Manager::try_from(&config::Redis::default())?;
+    let pending = Arc::new(Mutex::new(Vec::new()));
+    let lookups = pending.clone();
+    manager.look_up_missing_hashtags(
+        move |name, found| lookups.lock().expect("test").push((name, found)),
+        |_id, found| found(None),
+    );
+
+    assert_eq!(manager.hashtags.id("rust"), None); // starts looking it up
+    assert_eq!(manager.hashtags.id("rust"), None); // still looking
+    let (name, found) = pending.lock().expect("test").pop().expect("test");
+    assert_eq!(name, "rust");
+    assert!(pending.lock().expect("test").is_empty());
+    found(Some(7));
+    assert_eq!(manager.hashtags.id("rust"), Some(7));
+
+    // A tag that doesn't exist is remembered too
+    assert_eq!(manager.hashtags.name(8), None);
+    Ok(assert_eq!(manager.hashtags.name(8), None))
 }
@@ -88,6 +88,12 @@ fn utf8_to_redis_data<'a>(s: &'a str) -> Result<(RedisData, &'a str), RedisParse
         ":" => parse_redis_int(s),
         "$" => parse_redis_bulk_string(s),
         "*" => parse_redis_array(s),
+        // Redis replies to a command the user's ACL doesn't allow (e.g., SUBSCRIBE to a channel
+        // it can't access) with `-NOPERM <reason>\r\n`
+        "-" if s.starts_with("NOPERM") => {
+            let len = s.find("\r\n").ok_or(Incomplete)?;
+            Err(NoPerm(format!("-{}", &s[..len])))
+        }
         e => Err(InvalidLineStart(e.to_string())),
     }
 }
@@ -153,7 +159,10 @@ impl<'a> TryFrom<RedisStructuredText<'a>> for RedisParseOutput<'a> {
                 // subscription statuses look like:
                 // $14\r\ntimeline:local\r\n
                 // :47\r\n
-                "subscribe" | "unsubscribe" => Ok(NonMsg(input.leftover_input)),
+                // (as do replies to the `Manager`'s heartbeat PINGs: $4\r\npong\r\n$0\r\n\r\n)
+                "subscribe" | "unsubscribe" | "psubscribe" | "punsubscribe" | "pong" => {
+                    Ok(NonMsg(input.leftover_input))
+                }
                 // Messages look like;
                 // $10\r\ntimeline:4\r\n
                 // $1386\r\n{\"event\":\"update\",\"payload\"...\"queued_at\":1569623342825}\r\n
@@ -162,6 +171,18 @@ impl<'a> TryFrom<RedisStructuredText<'a>> for RedisParseOutput<'a> {
                     event_txt: redis_strings.pop().ok_or(MissingField)?.try_into()?,
                     leftover_input: input.leftover_input,
                 })),
+                // Messages for a pattern subscription start with the pattern they matched:
+                // $24\r\ntimeline:access_token:*\r\n
+                // $24\r\ntimeline:access_token:7\r\n
+                // $16\r\n{\"event\":\"kill\"}\r\n
+                "pmessage" => {
+                    let _pattern: &str = redis_strings.pop().ok_or(MissingField)?.try_into()?;
+                    Ok(Msg(RedisMsg {
+                        timeline_txt: redis_strings.pop().ok_or(MissingField)?.try_into()?,
+                        event_txt: redis_strings.pop().ok_or(MissingField)?.try_into()?,
+                        leftover_input: input.leftover_input,
+                    }))
+                }
                 _cmd => Err(Incomplete),
             }
         } else {
@@ -8,6 +8,8 @@ pub enum RedisParseErr {
     InvalidLineEnd(usize, String),
     IncorrectRedisType,
     MissingField,
+    /// A `-NOPERM` error reply (which takes up the whole line)
+    NoPerm(String),
 }
 
 impl fmt::Display for RedisParseErr {
@@ -39,6 +41,7 @@ impl fmt::Display for RedisParseErr {
             MissingField => "Redis input was missing a field Flodgatt expected (e.g., a `message` \
                 without a payload line)"
                 .to_string(),
+            NoPerm(reply) => format!("Redis refused a command: {}", reply),
         };
         write!(f, "{}", msg)
     }
@@ -55,6 +55,21 @@ fn parse_redis_msg() -> Result<(), RedisParseErr> {
     Ok(())
 }
 
+#[test]
+fn parse_redis_pattern_msg() -> Result<(), RedisParseErr> {
+    let input = "*4\r\n$8\r\npmessage\r\n$24\r\ntimeline:access_token:*\r\n$24\r\ntimeline:access_token:7\r\n$16\r\n{\"event\":\"kill\"}\r\n";
+
+    let r_msg = match RedisParseOutput::try_from(input) {
+        Ok(Msg(msg)) => msg,
+        other => panic!("Failed to parse a pattern msg: {:?}", other),
+    };
+
+    assert!(r_msg.leftover_input.is_empty());
+    assert_eq!(r_msg.timeline_txt, "timeline:access_token:7");
+    assert_eq!(r_msg.event_txt, r#"{"event":"kill"}"#);
+    Ok(())
+}
+
 #[test]
 fn parse_long_redis_msg() -> Result<(), Box<dyn std::error::Error>> {
     let mut test_num = 1;
@@ -82,3 +97,25 @@ fn parse_long_redis_msg() -> Result<(), Box<dyn std::error::Error>> {
 
     Ok(())
 }
+
+#[test]
+fn parse_redis_pong() -> Result<(), RedisParseErr> {
+    let input = "*2\r\n$4\r\npong\r\n$0\r\n\r\n";
+    match RedisParseOutput::try_from(input)? {
+        NonMsg(leftover) => assert!(leftover.is_empty()),
+        Msg(msg) => panic!("unexpectedly got a msg: {:?}", msg),
+    }
+    Ok(())
+}
+
+#[test]
+fn parse_redis_noperm_reply() {
+    let input = "-NOPERM this user has no permissions to access one of the channels\r\n*3\r\n";
+    match RedisParseOutput::try_from(input) {
+        Err(RedisParseErr::NoPerm(reply)) => assert_eq!(
+            reply,
+            "-NOPERM this user has no permissions to access one of the channels"
+        ),
+        other => panic!("Expected a NoPerm error, but got {:?}", other),
+    }
+}
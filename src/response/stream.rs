@@ -1,7 +1,31 @@
 pub use sse::Sse;
 pub use ws::Ws;
 
-pub(self) use super::{Event, Payload};
+pub(self) use super::{Event, Payload, Permit, RedisManager, Refusal};
+
+use super::queue::{Receiver, Sender};
+use crate::request::Timeline;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+
+type EventRx = Receiver<(Timeline, u64, Arc<Event>)>;
+type EventTx = Sender<(Timeline, u64, Arc<Event>)>;
+
+/// Connections (of either kind) that haven't finished yet
+static OPEN_CONNECTIONS: AtomicUsize = AtomicUsize::new(0);
+
+/// The number of client connections still open, so that shutdown can wait for them
+pub fn open_connections() -> usize {
+    OPEN_CONNECTIONS.load(Ordering::Acquire)
+}
+
+fn connection_opened() {
+    OPEN_CONNECTIONS.fetch_add(1, Ordering::AcqRel);
+}
+
+fn connection_closed() {
+    OPEN_CONNECTIONS.fetch_sub(1, Ordering::AcqRel);
+}
 
 mod sse;
 mod ws;
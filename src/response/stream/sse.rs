@@ -1,30 +1,62 @@
-use super::{Event, Payload};
+use super::{connection_closed, connection_opened, Event, EventRx, Payload, Permit};
+use crate::logging::ConnLog;
+use crate::metrics::METRICS;
 use crate::request::Subscription;
+use crate::response::queue::QueueErr;
 
+use futures::future::Either;
 use futures::stream::Stream;
-use std::sync::Arc;
+use log::Level;
+use serde_json::json;
 use std::time::Duration;
-use tokio::sync::mpsc::Receiver;
 use warp::reply::Reply;
 use warp::sse::Sse as WarpSse;
 
-type EventRx = Receiver<Arc<Event>>;
-
-pub struct Sse(Subscription);
+pub struct Sse {
+    subscription: Subscription,
+    log: ConnLog,
+    /// Held until the connection closes, which releases it from the connection limits
+    _permit: Permit,
+}
 
 impl Sse {
-    pub fn new(subscription: Subscription) -> Self {
-        Self(subscription)
+    pub fn new(subscription: Subscription, permit: Permit) -> Self {
+        METRICS.connections.inc(&["sse"]);
+        connection_opened();
+        METRICS
+            .subscriptions
+            .inc(&["sse", subscription.timeline.kind()]);
+        let tl = subscription.timeline.kind();
+        let log = ConnLog::accept("sse", subscription.user_id, tl);
+        let fields = json!({ "timeline": tl, "since": subscription.since });
+        log.record(Level::Info, "subscribe", fields);
+        Self {
+            subscription,
+            log,
+            _permit: permit,
+        }
     }
 
-    pub fn send_events(self, sse: WarpSse, event_rx: EventRx) -> impl Reply {
-        let event_stream = event_rx.filter_map(move |event| {
-            match (event.update_payload(), event.dyn_update_payload()) {
-                (Some(update), _) if self.update_not_filtered(update) => event.to_warp_reply(),
-                (_, Some(update)) if self.update_not_filtered(update) => event.to_warp_reply(),
-                (_, _) => event.to_warp_reply(), // send all non-updates
-            }
-        });
+    pub fn send_events(mut self, sse: WarpSse, event_rx: EventRx) -> impl Reply {
+        let event_stream = event_rx
+            .filter_map(move |(_tl, id, event)| {
+                if !self.should_send(&event) {
+                    return None;
+                }
+                let (reply, len) = event.to_warp_reply(id)?;
+                let tl = self.subscription.timeline;
+                METRICS.events_delivered.inc(&["sse", tl.kind()]);
+                METRICS.observe_event_age("socket", tl.kind(), event.queued_at());
+                self.log.sent(len);
+                Some(Either::A(reply))
+            })
+            // A closed queue means the server is shutting down (or the client's token was
+            // revoked); say so before ending the stream
+            .or_else(|e| match e {
+                QueueErr::Closed => Ok(Either::B(warp::sse::comment("server shutting down"))),
+                QueueErr::Revoked => Ok(Either::B(warp::sse::comment("access token revoked"))),
+                QueueErr::Overflowed => Err(e),
+            });
 
         sse.reply(
             warp::sse::keep_alive()
@@ -34,22 +66,60 @@ impl Sse {
         )
     }
 
+    fn should_send(&self, event: &Event) -> bool {
+        match (event.update_payload(), event.dyn_update_payload()) {
+            (Some(update), _) => self.update_not_filtered(update),
+            (_, Some(update)) => self.update_not_filtered(update),
+            (None, None) => true, // send all non-updates
+        }
+    }
+
     fn update_not_filtered(&self, update: &impl Payload) -> bool {
-        let blocks = &self.0.blocks;
-        let allowed_langs = &self.0.allowed_langs;
+        let blocks = self.subscription.blocks.read();
+        let allowed_langs = &self.subscription.allowed_langs;
+        let filtered = |reason| {
+            METRICS.events_filtered.inc(&["sse", reason]);
+            let tl = self.subscription.timeline.kind();
+            let fields = json!({ "timeline": tl, "reason": reason });
+            self.log.record(Level::Debug, "filtered", fields);
+            false
+        };
 
-        match self.0.timeline {
+        match self.subscription.timeline {
             tl if tl.is_public()
                 && !update.language_unset()
                 && !allowed_langs.is_empty()
                 && !allowed_langs.contains(&update.language()) =>
             {
-                false
+                filtered("language")
+            }
+            _ if !blocks.blocked_users.is_disjoint(&update.involved_users()) => {
+                filtered("blocked_user")
+            }
+            _ if blocks.blocking_users.contains(update.author()) => filtered("blocking_user"),
+            _ if blocks.blocked_domains.contains(update.sent_from()) => filtered("blocked_domain"),
+            tl if self.subscription.filters.read().hide(
+                tl,
+                update.spoiler_text(),
+                update.content(),
+            ) =>
+            {
+                filtered("keyword_filter")
             }
-            _ if !blocks.blocked_users.is_disjoint(&update.involved_users()) => false,
-            _ if blocks.blocking_users.contains(update.author()) => false,
-            _ if blocks.blocked_domains.contains(update.sent_from()) => false,
             _ => true,
         }
     }
 }
+
+impl Drop for Sse {
+    fn drop(&mut self) {
+        METRICS.connections.dec(&["sse"]);
+        connection_closed();
+        METRICS
+            .subscriptions
+            .dec(&["sse", self.subscription.timeline.kind()]);
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,39 @@
+use super::*;
+use crate::config;
+use crate::request::{Filter, FilterContext, Filters, Timeline};
+use crate::response::RedisManager;
+use std::convert::TryFrom;
+use std::fs;
+
+type TestResult = std::result::Result<(), Box<dyn std::error::Error>>;
+
+/// An SSE connection to the public timeline for a user with one keyword filter
+fn sse_filtering(phrase: &str) -> Result<Sse, Box<dyn std::error::Error>> {
+    let contexts = vec![FilterContext::Public].into_iter().collect();
+    let filters = Filters(vec![Filter::new(phrase, contexts, false)]);
+    let subscription = Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        filters: filters.into(),
+        ..Subscription::default()
+    };
+    let permit = RedisManager::try_from(&config::Redis::default())?.admit_ip("sse", None)?;
+    Ok(Sse::new(subscription, permit))
+}
+
+fn update() -> Result<Event, Box<dyn std::error::Error>> {
+    // A status whose content includes "Trending tags"
+    let txt = fs::read_to_string("test_data/msg.event_txt_001.txt")?;
+    Ok(Event::try_from(txt)?)
+}
+
+#[test]
+fn sse_does_not_send_a_filtered_update() -> TestResult {
+    let sse = sse_filtering("trending")?;
+    Ok(assert!(!sse.should_send(&update()?)))
+}
+
+#[test]
+fn sse_sends_an_update_no_filter_matches() -> TestResult {
+    let sse = sse_filtering("python")?;
+    Ok(assert!(sse.should_send(&update()?)))
+}
@@ -1,19 +1,163 @@
-use super::{Event, Payload};
-use crate::request::Subscription;
+use super::{connection_closed, connection_opened, Event, EventRx, EventTx, Payload, Permit};
+use super::{RedisManager, Refusal};
+use crate::logging::ConnLog;
+use crate::metrics::METRICS;
+use crate::request::{Handler, PgPool, Subscription, Timeline, WsMsg, WsStreamMsg};
+use crate::response::queue::QueueErr;
 
 use futures::future::Future;
+use futures::sink::Sink;
 use futures::stream::Stream;
-use std::sync::Arc;
-use tokio::sync::mpsc::Receiver;
+use futures::sync::mpsc::{self as futures_mpsc, UnboundedReceiver, UnboundedSender};
+use hashbrown::{HashMap, HashSet};
+use log::Level;
+use serde_json::json;
+use std::sync::{Arc, Mutex};
 use warp::ws::{Message, WebSocket};
+use warp::Rejection;
 
-type EventRx = Receiver<Arc<Event>>;
+/// The outcome of a `subscribe` message, with the `stream` it asked for
+type Subscribed = (Vec<String>, Result<Subscription, Rejection>);
+type SubscribedRx = UnboundedReceiver<Subscribed>;
+type SubscribedTx = UnboundedSender<Subscribed>;
 
-pub struct Ws(Subscription);
+/// A WebSocket connection, which can carry any number of subscriptions
+pub struct Ws {
+    channels: HashMap<Timeline, Channel>,
+    access_token: Option<String>,
+    manager: Arc<Mutex<RedisManager>>,
+    handler: Handler,
+    event_tx: EventTx,
+    /// The `stream`s of the `subscribe` messages still waiting for Postgres to authorize them
+    pending: HashSet<Vec<String>>,
+    /// Carries the results of `subscribe` messages, once Postgres has authorized them (`None`
+    /// once the connection is closing, so that the connection can end)
+    subscribed_tx: Option<SubscribedTx>,
+    subscribed_rx: Option<SubscribedRx>,
+    log: ConnLog,
+    /// Held until the connection closes, which releases it from the connection limits
+    _permit: Permit,
+}
+
+/// One of the subscriptions multiplexed over a `Ws` connection
+struct Channel {
+    subscription: Subscription,
+    id: u32,
+    stream: Vec<String>,
+}
+
+/// Input to a `Ws` connection: an event from Redis, a message from the client, the outcome
+/// of a `subscribe` message, or notice that the client fell too far behind, that its access
+/// token was revoked, or that the server is shutting down
+enum Incoming {
+    Event((Timeline, u64, Arc<Event>)),
+    Client(Message),
+    Subscribed(Subscribed),
+    Overflowed,
+    Revoked,
+    ShuttingDown,
+}
 
 impl Ws {
-    pub fn new(subscription: Subscription) -> Self {
-        Self(subscription)
+    /// "Policy Violation"; sent when a client's event queue overflows with the `disconnect`
+    /// overflow policy, or when its access token is revoked
+    const CLOSE_POLICY_VIOLATION: u16 = 1008;
+    /// "Going Away"; sent to every client when the server shuts down
+    const CLOSE_GOING_AWAY: u16 = 1001;
+    /// "Try Again Later"; sent to a client that's over a connection limit
+    const CLOSE_TRY_AGAIN_LATER: u16 = 1013;
+    /// The most subscriptions a connection may have, counting those still being authorized
+    const MAX_SUBSCRIPTIONS: usize = 100;
+    const TOO_MANY_SUBSCRIPTIONS: &'static str = "Error: Too many subscriptions";
+
+    /// Accept a connection, with its first `Subscription`.  A connection opened without a
+    /// `stream` has a `Subscription` to `Timeline::empty()`, which only authorizes it: it
+    /// starts with no subscriptions, and makes them with `subscribe` messages.
+    pub fn new(
+        manager: Arc<Mutex<RedisManager>>,
+        handler: Handler,
+        event_tx: EventTx,
+        subscription: Subscription,
+        permit: Permit,
+    ) -> Self {
+        METRICS.connections.inc(&["ws"]);
+        connection_opened();
+        let (subscribed_tx, subscribed_rx) = futures_mpsc::unbounded();
+        let log = ConnLog::accept("ws", subscription.user_id, subscription.timeline.kind());
+        manager
+            .lock()
+            .unwrap_or_else(RedisManager::recover)
+            .track_ws(event_tx.clone());
+        let mut ws = Self {
+            channels: HashMap::new(),
+            access_token: subscription.access_token.clone(),
+            manager,
+            handler,
+            event_tx,
+            pending: HashSet::new(),
+            subscribed_tx: Some(subscribed_tx),
+            subscribed_rx: Some(subscribed_rx),
+            log,
+            _permit: permit,
+        };
+        if subscription.timeline != Timeline::empty() {
+            ws.subscribe(subscription);
+        }
+        ws
+    }
+
+    /// Add a `Subscription` to this connection (a no-op if it's already subscribed)
+    pub fn subscribe(&mut self, subscription: Subscription) {
+        let tl = subscription.timeline;
+        if self.channels.contains_key(&tl) {
+            return;
+        }
+        let stream = match tl.to_stream_field(subscription.hashtag_name.as_ref()) {
+            Ok(stream) => stream,
+            Err(e) => {
+                let error = json!({ "timeline": tl.kind(), "error": e.to_string() });
+                return self.log.record(Level::Error, "subscribe_failed", error);
+            }
+        };
+
+        let subscription_since = subscription.since;
+        let id = self
+            .manager
+            .lock()
+            .unwrap_or_else(RedisManager::recover)
+            .subscribe(&subscription, self.event_tx.clone());
+        let channel = Channel {
+            subscription,
+            id,
+            stream,
+        };
+        self.channels.insert(tl, channel);
+        METRICS.subscriptions.inc(&["ws", tl.kind()]);
+        let fields = json!({ "timeline": tl.kind(), "since": subscription_since });
+        self.log.record(Level::Info, "subscribe", fields);
+    }
+
+    fn unsubscribe(&mut self, stream: &[String]) {
+        if self.pending.remove(stream) {
+            let fields = json!({ "stream": stream });
+            return self.log.record(Level::Info, "unsubscribe_pending", fields);
+        }
+        let tl = match self.channels.iter().find(|(_, chan)| chan.stream == stream) {
+            Some((tl, _)) => *tl,
+            None => {
+                let fields = json!({ "stream": stream });
+                return self.log.record(Level::Info, "unknown_stream", fields);
+            }
+        };
+        if let Some(channel) = self.channels.remove(&tl) {
+            METRICS.subscriptions.dec(&["ws", tl.kind()]);
+            let fields = json!({ "timeline": tl.kind() });
+            self.log.record(Level::Info, "unsubscribe", fields);
+            self.manager
+                .lock()
+                .unwrap_or_else(RedisManager::recover)
+                .unsubscribe(tl, channel.id);
+        }
     }
 
     pub fn send_to(
@@ -21,25 +165,57 @@ impl Ws {
         ws: WebSocket,
         event_rx: EventRx,
     ) -> impl Future<Item = (), Error = ()> {
-        let (transmit_to_ws, _receive_from_ws) = ws.split();
+        let (transmit_to_ws, receive_from_ws) = ws.split();
+        let subscribed_rx = self.subscribed_rx.take().expect("only taken here");
         event_rx
-            .filter_map(move |event| {
-                if matches!(*event, Event::Ping) {
-                    Some(Message::text(&event.to_json_string()))
-                } else {
-                    match (event.update_payload(), event.dyn_update_payload()) {
-                        (Some(update), _) if !self.filtered(update) => {
-                            Some(Message::text(&event.to_json_string()))
-                        }
-                        (None, None) => Some(Message::text(&event.to_json_string())), // send all non-updates
-                        (_, Some(dyn_update)) if !self.filtered(dyn_update) => {
-                            Some(Message::text(&event.to_json_string()))
-                        }
-                        _ => None,
+            .map(Incoming::Event)
+            .or_else(|e| {
+                Ok::<_, warp::Error>(match e {
+                    QueueErr::Overflowed => Incoming::Overflowed,
+                    QueueErr::Revoked => Incoming::Revoked,
+                    QueueErr::Closed => Incoming::ShuttingDown,
+                })
+            })
+            .select(receive_from_ws.map(Incoming::Client))
+            .select(
+                subscribed_rx
+                    .map(Incoming::Subscribed)
+                    .map_err(|()| -> warp::Error { unreachable!() }),
+            )
+            .filter_map(move |incoming| {
+                let msg = match incoming {
+                    Incoming::Event((tl, id, event)) => self.event_msg(tl, id, &event),
+                    Incoming::Client(msg) => self.handle_client_msg(&msg),
+                    Incoming::Subscribed(subscription) => self.subscribed(subscription),
+                    Incoming::Overflowed => {
+                        self.log.record(Level::Info, "overflowed", json!({}));
+                        self.subscribed_tx = None;
+                        Some(Message::close_with(
+                            Self::CLOSE_POLICY_VIOLATION,
+                            "client too slow",
+                        ))
+                    }
+                    Incoming::Revoked => {
+                        self.log.record(Level::Info, "revoked", json!({}));
+                        self.subscribed_tx = None;
+                        Some(Message::close_with(
+                            Self::CLOSE_POLICY_VIOLATION,
+                            "access token revoked",
+                        ))
+                    }
+                    Incoming::ShuttingDown => {
+                        self.subscribed_tx = None;
+                        Some(Message::close_with(
+                            Self::CLOSE_GOING_AWAY,
+                            "server shutting down",
+                        ))
                     }
+                };
+                if let Some(msg) = &msg {
+                    self.log.sent(msg.as_bytes().len());
                 }
+                msg
             })
-            .map_err(|_| -> warp::Error { unreachable!() })
             .forward(transmit_to_ws)
             .map(|_r| ())
             // ignore errors that indicate normal disconnects.  TODO - once we upgrade our
@@ -50,27 +226,161 @@ impl Ws {
                 e => log::warn!("WebSocket send error: {}", e),
             })
     }
-    fn filtered<T: std::fmt::Debug + Payload>(&mut self, update: &T) -> bool {
-        let (blocks, allowed_langs) = (&self.0.blocks, &self.0.allowed_langs);
-        let skip = |msg| {
-            // Some(log::info!("{:?} msg skipped - {}\n{:?}", self.0.timeline, msg, update)).is_some()
-            Some(log::info!("{:?} msg skipped - {}", self.0.timeline, msg)).is_some()
+
+    /// Close a connection that was refused for going over a connection limit
+    pub fn refuse(ws: WebSocket, refusal: Refusal) -> impl Future<Item = (), Error = ()> {
+        ws.send(Message::close_with(
+            Self::CLOSE_TRY_AGAIN_LATER,
+            refusal.reason(),
+        ))
+        .map(|_ws| ())
+        .map_err(|e| log::warn!("WebSocket send error: {}", e))
+    }
+
+    fn event_msg(&self, tl: Timeline, id: u64, event: &Event) -> Option<Message> {
+        let channel = self.channels.get(&tl)?; // None if the client since unsubscribed
+        let msg = || Some(Message::text(&event.to_json_string(&channel.stream, id)));
+        let deliver = || {
+            METRICS.events_delivered.inc(&["ws", tl.kind()]);
+            METRICS.observe_event_age("socket", tl.kind(), event.queued_at());
+            msg()
         };
 
-        match self.0.timeline {
+        if matches!(*event, Event::Ping) {
+            msg()
+        } else {
+            match (event.update_payload(), event.dyn_update_payload()) {
+                (Some(update), _) if !self.filtered(&channel.subscription, update) => deliver(),
+                (None, None) => deliver(), // send all non-updates
+                (_, Some(dyn_update)) if !self.filtered(&channel.subscription, dyn_update) => {
+                    deliver()
+                }
+                _ => None,
+            }
+        }
+    }
+
+    fn handle_client_msg(&mut self, msg: &Message) -> Option<Message> {
+        let txt = msg.to_str().ok()?; // pings, pongs, and close frames need no reply
+        match serde_json::from_str(txt) {
+            Ok(WsMsg::Subscribe(stream)) => self.subscribe_to(stream),
+            Ok(WsMsg::Unsubscribe(stream)) => {
+                self.unsubscribe(&stream.stream_field());
+                None
+            }
+            Err(e) => {
+                // (Not the message itself, or the error's text, which can quote it: they're
+                // whatever the client sent)
+                let error = format!("{:?}", e.classify()).to_lowercase();
+                let fields = json!({ "len": txt.len(), "error": error, "column": e.column() });
+                self.log.record(Level::Info, "ignored_msg", fields);
+                None
+            }
+        }
+    }
+
+    /// Authorize a `subscribe` message (on Postgres's threads); the outcome arrives as an
+    /// `Incoming::Subscribed`.  Replies with an error if the message can't be authorized.
+    fn subscribe_to(&mut self, msg: WsStreamMsg) -> Option<Message> {
+        let stream = msg.stream_field();
+        if !msg.is_known_stream() {
+            return self.refuse_subscription(Timeline::NONEXISTENT_ENDPOINT);
+        }
+        let subscribed = self.channels.values().any(|chan| chan.stream == stream);
+        if subscribed || self.pending.contains(&stream) {
+            return None;
+        }
+        if self.channels.len() + self.pending.len() >= Self::MAX_SUBSCRIPTIONS {
+            return self.refuse_subscription(Self::TOO_MANY_SUBSCRIPTIONS);
+        }
+        let subscribed_tx = match &self.subscribed_tx {
+            Some(subscribed_tx) => subscribed_tx.clone(),
+            None => return None, // the connection is closing
+        };
+
+        self.pending.insert(stream.clone());
+        let authorize = self
+            .handler
+            .ws_msg_subscription(msg, self.access_token.clone())
+            .then(move |subscription| {
+                // An err just means the client has disconnected
+                subscribed_tx
+                    .unbounded_send((stream, subscription))
+                    .map_err(|_| ())
+            });
+        tokio::spawn(authorize);
+        None
+    }
+
+    fn subscribed(&mut self, (stream, subscription): Subscribed) -> Option<Message> {
+        if !self.pending.remove(&stream) {
+            return None; // the client unsubscribed while it was being authorized
+        }
+        match subscription {
+            Ok(subscription) => {
+                self.subscribe(subscription);
+                None
+            }
+            Err(rejection) => {
+                let msg = rejection.cause().map(|cause| cause.to_string());
+                let msg = msg.as_deref().unwrap_or(PgPool::SERVER_ERR);
+                let fields = json!({ "reason": msg });
+                self.log.record(Level::Info, "auth_failure", fields);
+                Some(Message::text(json!({ "error": msg }).to_string()))
+            }
+        }
+    }
+
+    /// Tell the client why its `subscribe` message was refused
+    fn refuse_subscription(&self, reason: &str) -> Option<Message> {
+        let fields = json!({ "reason": reason });
+        self.log.record(Level::Info, "subscribe_refused", fields);
+        Some(Message::text(json!({ "error": reason }).to_string()))
+    }
+
+    fn filtered<T: Payload>(&self, subscription: &Subscription, update: &T) -> bool {
+        let (blocks, allowed_langs) = (subscription.blocks.read(), &subscription.allowed_langs);
+        let skip = |reason| {
+            METRICS.events_filtered.inc(&["ws", reason]);
+            let fields = json!({ "timeline": subscription.timeline.kind(), "reason": reason });
+            self.log.record(Level::Debug, "filtered", fields);
+            true
+        };
+
+        match subscription.timeline {
             tl if tl.is_public()
                 && !update.language_unset()
                 && !allowed_langs.is_empty()
                 && !allowed_langs.contains(&update.language()) =>
             {
-                skip("disallowed language")
+                skip("language")
             }
             _ if !blocks.blocked_users.is_disjoint(&update.involved_users()) => {
-                skip("involves blocked user")
+                skip("blocked_user")
+            }
+            _ if blocks.blocking_users.contains(update.author()) => skip("blocking_user"),
+            _ if blocks.blocked_domains.contains(update.sent_from()) => skip("blocked_domain"),
+            tl if subscription
+                .filters
+                .read()
+                .hide(tl, update.spoiler_text(), update.content()) =>
+            {
+                skip("keyword_filter")
             }
-            _ if blocks.blocking_users.contains(update.author()) => skip("from blocking user"),
-            _ if blocks.blocked_domains.contains(update.sent_from()) => skip("from blocked domain"),
             _ => false,
         }
     }
 }
+
+impl Drop for Ws {
+    fn drop(&mut self) {
+        METRICS.connections.dec(&["ws"]);
+        connection_closed();
+        for tl in self.channels.keys() {
+            METRICS.subscriptions.dec(&["ws", tl.kind()]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test;
@@ -0,0 +1,110 @@
+use super::*;
+use crate::config;
+use crate::response::queue;
+use futures::future;
+use tokio::runtime::current_thread::Runtime;
+
+type TestResult = std::result::Result<(), Box<dyn std::error::Error>>;
+
+/// A connection opened without a `stream` (and its event queue, which must stay open)
+fn ws_without_stream() -> std::result::Result<(Ws, EventRx), Box<dyn std::error::Error>> {
+    let manager = RedisManager::try_from(&config::Redis::default())?.into_arc();
+    let permit = manager
+        .lock()
+        .unwrap_or_else(RedisManager::recover)
+        .admit_ip("ws", None)?;
+    let (event_tx, event_rx) = queue::channel(10, config::OverflowPolicy::DropOldest);
+    let subscription = Subscription {
+        access_token: Some("TOKEN".to_string()),
+        ..Subscription::default()
+    };
+    let ws = Ws::new(
+        manager,
+        Handler::unconnected(),
+        event_tx,
+        subscription,
+        permit,
+    );
+    Ok((ws, event_rx))
+}
+
+fn subscribe_msg(stream: &str) -> Message {
+    Message::text(json!({ "type": "subscribe", "stream": stream, "tag": "rust" }).to_string())
+}
+
+fn error_text(reply: Option<Message>) -> Option<String> {
+    let reply: serde_json::Value = serde_json::from_str(reply?.to_str().ok()?).ok()?;
+    Some(reply["error"].as_str()?.to_string())
+}
+
+#[test]
+fn ws_without_a_stream_starts_with_no_subscriptions() -> TestResult {
+    let (ws, _event_rx) = ws_without_stream()?;
+    assert!(ws.channels.is_empty());
+    assert_eq!(ws.access_token, Some("TOKEN".to_string()));
+    Ok(())
+}
+
+#[test]
+fn ws_subscribe_message_is_authorized_once() -> TestResult {
+    let (mut ws, _event_rx) = ws_without_stream()?;
+    let replies = Runtime::new()?.block_on(future::lazy(|| {
+        let first = ws.handle_client_msg(&subscribe_msg("hashtag"));
+        let repeated = ws.handle_client_msg(&subscribe_msg("hashtag"));
+        Ok::<_, ()>((first, repeated))
+    }));
+
+    assert_eq!(replies, Ok((None, None)));
+    let stream = vec!["hashtag".to_string(), "rust".to_string()];
+    assert_eq!(ws.pending.iter().collect::<Vec<_>>(), vec![&stream]);
+    Ok(())
+}
+
+#[test]
+fn ws_unsubscribe_message_ends_a_subscription() -> TestResult {
+    let (mut ws, _event_rx) = ws_without_stream()?;
+    ws.subscribe(Subscription {
+        timeline: Timeline::from_redis_text("public", |_| None)?,
+        ..Subscription::default()
+    });
+    ws.pending.insert(vec!["direct".to_string()]);
+    assert_eq!(ws.channels.len(), 1);
+
+    let unsubscribe = |stream: &str| {
+        Message::text(json!({ "type": "unsubscribe", "stream": stream }).to_string())
+    };
+    assert_eq!(ws.handle_client_msg(&unsubscribe("public")), None);
+    assert_eq!(ws.handle_client_msg(&unsubscribe("direct")), None);
+    assert!(ws.channels.is_empty());
+    assert!(ws.pending.is_empty());
+    Ok(())
+}
+
+#[test]
+fn ws_subscribe_message_for_an_unknown_stream_gets_an_error() -> TestResult {
+    let (mut ws, _event_rx) = ws_without_stream()?;
+    let reply = ws.handle_client_msg(&subscribe_msg("public:everything"));
+
+    assert_eq!(
+        error_text(reply),
+        Some(Timeline::NONEXISTENT_ENDPOINT.to_string())
+    );
+    assert!(ws.pending.is_empty());
+    Ok(())
+}
+
+#[test]
+fn ws_refuses_subscriptions_over_the_limit() -> TestResult {
+    let (mut ws, _event_rx) = ws_without_stream()?;
+    for i in 0..Ws::MAX_SUBSCRIPTIONS {
+        ws.pending.insert(vec!["list".to_string(), i.to_string()]);
+    }
+    let reply = ws.handle_client_msg(&subscribe_msg("public"));
+
+    assert_eq!(
+        error_text(reply),
+        Some(Ws::TOO_MANY_SUBSCRIPTIONS.to_string())
+    );
+    assert_eq!(ws.pending.len(), Ws::MAX_SUBSCRIPTIONS);
+    Ok(())
+}